@@ -0,0 +1,545 @@
+use std::{
+    io::BufReader,
+    path::PathBuf,
+    str::FromStr,
+    sync::{Arc, Mutex, RwLock},
+    time::{Duration, SystemTime, UNIX_EPOCH},
+};
+
+use lazy_static::lazy_static;
+use mongodb::options::{
+    AuthMechanism, ClientOptions, ReadPreference, ReadPreferenceOptions, SelectionCriteria,
+    ServerAddress, Tls, TlsOptions,
+};
+use serde::{Deserialize, Serialize};
+
+use crate::{
+    engine::loader::SourceCache,
+    error::Error,
+    proxy::ProxyOptions,
+    tunnel::{SshTunnelOptions, Tunnel},
+};
+
+lazy_static! {
+    pub static ref CLIENTS: RwLock<Vec<SyncClientEntry>> = RwLock::new(Vec::new());
+}
+
+/// Connection settings kept next to the URI of a saved connection. Anything
+/// left unset falls back to what the connection string says.
+#[derive(Debug, Clone, Default, Serialize, Deserialize)]
+#[serde(rename_all = "camelCase", default)]
+pub struct ConnectionProfile {
+    pub default_database: Option<String>,
+    /// One of `primary`, `primaryPreferred`, `secondary`, `secondaryPreferred`
+    /// or `nearest`.
+    pub read_preference: Option<String>,
+    pub app_name: Option<String>,
+    pub connect_timeout_ms: Option<u64>,
+    pub server_selection_timeout_ms: Option<u64>,
+    /// Turns TLS on or off regardless of the connection string. Setting any
+    /// other `tls_` option turns it on as well.
+    pub tls: Option<bool>,
+    /// PEM file with the certificate authorities to trust.
+    pub tls_ca_file: Option<String>,
+    /// PEM file with the client certificate, e.g. for `MONGODB-X509`.
+    pub tls_cert_file: Option<String>,
+    /// Private key for `tls_cert_file`, when it is not in the same file.
+    pub tls_key_file: Option<String>,
+    /// The driver is built with rustls, which can't skip the hostname check
+    /// alone, so this is only accepted together with
+    /// `tls_allow_invalid_certificates`, which skips it as well.
+    pub tls_allow_invalid_hostnames: Option<bool>,
+    pub tls_allow_invalid_certificates: Option<bool>,
+    /// e.g. `SCRAM-SHA-256` or `MONGODB-X509`.
+    pub auth_mechanism: Option<String>,
+    pub direct_connection: Option<bool>,
+    /// Color tag shown next to the connection in the UI.
+    pub color: Option<String>,
+    /// Rejects writes from scripts run against this connection.
+    pub read_only: bool,
+    /// Reaches the server through an SSH bastion host.
+    pub ssh_tunnel: Option<SshTunnelOptions>,
+    /// Reaches the server through a SOCKS5 or HTTP proxy.
+    pub proxy: Option<ProxyOptions>,
+}
+
+impl ConnectionProfile {
+    /// Overrides `options` with the settings of this profile. The client
+    /// certificate is left to [`connect`], which may have to merge it with
+    /// its key first.
+    pub fn apply(&self, options: &mut ClientOptions) -> Result<(), Error> {
+        if let Some(database) = &self.default_database {
+            options.default_database = Some(database.clone());
+        }
+        if let Some(mode) = &self.read_preference {
+            options.selection_criteria =
+                Some(SelectionCriteria::ReadPreference(read_preference(mode)?));
+        }
+        if let Some(app_name) = &self.app_name {
+            options.app_name = Some(app_name.clone());
+        }
+        if let Some(ms) = self.connect_timeout_ms {
+            options.connect_timeout = Some(Duration::from_millis(ms));
+        }
+        if let Some(ms) = self.server_selection_timeout_ms {
+            options.server_selection_timeout = Some(Duration::from_millis(ms));
+        }
+        if self.tls == Some(false) {
+            options.tls = Some(Tls::Disabled);
+        } else {
+            if self.tls == Some(true) {
+                tls_options(options);
+            }
+            if let Some(ca_file) = &self.tls_ca_file {
+                tls_options(options).ca_file_path = Some(PathBuf::from(ca_file));
+            }
+            if let Some(allow) = self.tls_allow_invalid_certificates {
+                tls_options(options).allow_invalid_certificates = Some(allow);
+            }
+        }
+        if let Some(mechanism) = &self.auth_mechanism {
+            let mechanism = AuthMechanism::from_str(mechanism)
+                .map_err(|err| Error::InvalidArgument(err.kind.to_string()))?;
+            options
+                .credential
+                .get_or_insert_with(Default::default)
+                .mechanism = Some(mechanism);
+        }
+        if let Some(direct) = self.direct_connection {
+            options.direct_connection = Some(direct);
+        }
+        Ok(())
+    }
+
+    /// Checks the profile, and that `uri` can be used with it, without
+    /// connecting anywhere, including that the TLS files can be read and hold
+    /// what they should.
+    pub fn validate(&self, uri: &str) -> Result<(), Error> {
+        let has_tls_options = self.tls_ca_file.is_some()
+            || self.tls_cert_file.is_some()
+            || self.tls_key_file.is_some()
+            || self.tls_allow_invalid_hostnames.is_some()
+            || self.tls_allow_invalid_certificates.is_some();
+        if self.tls == Some(false) && has_tls_options {
+            return Err(Error::InvalidArgument(
+                "TLS options are set but TLS is turned off".to_string(),
+            ));
+        }
+        if self.tls_key_file.is_some() && self.tls_cert_file.is_none() {
+            return Err(Error::InvalidArgument(
+                "a TLS key file needs a certificate file".to_string(),
+            ));
+        }
+        if let Some(ssh) = &self.ssh_tunnel {
+            ssh.validate()?;
+        }
+        if let Some(proxy) = &self.proxy {
+            proxy.validate()?;
+            if self.ssh_tunnel.is_some() {
+                return Err(Error::InvalidArgument(
+                    "an SSH tunnel and a proxy can't be used together".to_string(),
+                ));
+            }
+        }
+        // Checked here so SRV records aren't looked up before failing.
+        if self.ssh_tunnel.is_some() || self.proxy.is_some() {
+            if uri.starts_with("mongodb+srv://") {
+                return Err(Error::InvalidArgument(
+                    "an SSH tunnel or proxy can't be used with a mongodb+srv:// URI".to_string(),
+                ));
+            }
+            if uri_host(uri).contains(',') {
+                return Err(Error::InvalidArgument(
+                    "an SSH tunnel or proxy can only reach a single host".to_string(),
+                ));
+            }
+        }
+        if self.tls_allow_invalid_hostnames == Some(true)
+            && self.tls_allow_invalid_certificates != Some(true)
+        {
+            return Err(Error::InvalidArgument(
+                "invalid hostnames can only be allowed together with invalid certificates"
+                    .to_string(),
+            ));
+        }
+
+        if let Some(path) = &self.tls_ca_file {
+            if !read_pem(path)?.iter().any(is_certificate) {
+                return Err(Error::InvalidArgument(format!(
+                    "{} holds no PEM certificate",
+                    path
+                )));
+            }
+        }
+        if let Some(path) = &self.tls_cert_file {
+            let items = read_pem(path)?;
+            if !items.iter().any(is_certificate) {
+                return Err(Error::InvalidArgument(format!(
+                    "{} holds no PEM certificate",
+                    path
+                )));
+            }
+            let key_items = match &self.tls_key_file {
+                Some(key_file) => read_pem(key_file)?,
+                None => items,
+            };
+            if !key_items.iter().any(is_private_key) {
+                return Err(Error::InvalidArgument(format!(
+                    "{} holds no PEM private key",
+                    self.tls_key_file.as_ref().unwrap_or(path)
+                )));
+            }
+        }
+        self.apply(&mut ClientOptions::default())
+    }
+
+    /// Whether the profile holds secrets that are stored in the vault.
+    pub fn has_secrets(&self) -> bool {
+        self.ssh_tunnel
+            .as_ref()
+            .is_some_and(SshTunnelOptions::has_secrets)
+            || self.proxy.as_ref().is_some_and(ProxyOptions::has_secrets)
+    }
+}
+
+fn read_pem(path: &str) -> Result<Vec<rustls_pemfile::Item>, Error> {
+    let file = std::fs::File::open(path)
+        .map_err(|err| Error::InvalidArgument(format!("failed to open {}: {}", path, err)))?;
+    rustls_pemfile::read_all(&mut BufReader::new(file))
+        .map_err(|err| Error::InvalidArgument(format!("failed to read {}: {}", path, err)))
+}
+
+fn is_certificate(item: &rustls_pemfile::Item) -> bool {
+    matches!(item, rustls_pemfile::Item::X509Certificate(_))
+}
+
+fn is_private_key(item: &rustls_pemfile::Item) -> bool {
+    matches!(
+        item,
+        rustls_pemfile::Item::RSAKey(_)
+            | rustls_pemfile::Item::PKCS8Key(_)
+            | rustls_pemfile::Item::ECKey(_)
+    )
+}
+
+fn read_preference(mode: &str) -> Result<ReadPreference, Error> {
+    let options = ReadPreferenceOptions::default();
+    Ok(match mode {
+        "primary" => ReadPreference::Primary,
+        "primaryPreferred" => ReadPreference::PrimaryPreferred { options },
+        "secondary" => ReadPreference::Secondary { options },
+        "secondaryPreferred" => ReadPreference::SecondaryPreferred { options },
+        "nearest" => ReadPreference::Nearest { options },
+        _ => {
+            return Err(Error::InvalidArgument(format!(
+                "unknown read preference '{}'",
+                mode
+            )))
+        }
+    })
+}
+
+/// Returns the TLS options of `options`, turning TLS on if it was not.
+fn tls_options(options: &mut ClientOptions) -> &mut TlsOptions {
+    if !matches!(options.tls, Some(Tls::Enabled(_))) {
+        options.tls = Some(Tls::Enabled(TlsOptions::default()));
+    }
+    match options.tls.as_mut() {
+        Some(Tls::Enabled(tls)) => tls,
+        _ => unreachable!(),
+    }
+}
+
+/// Builds a client for `uri` with `profile` applied on top of it. With an SSH
+/// tunnel or a proxy the client talks to the local end of a tunnel, which has
+/// to stay open as long as the client is used.
+pub fn connect(
+    uri: &str,
+    profile: &ConnectionProfile,
+) -> Result<(mongodb::sync::Client, Option<Tunnel>), Error> {
+    profile.validate(uri)?;
+    let mut options = ClientOptions::parse(uri)?;
+    profile.apply(&mut options)?;
+    let tunnel = open_tunnel(profile, &mut options)?;
+    Ok((build_client(profile, options)?, tunnel))
+}
+
+/// Opens the SSH tunnel or proxy tunnel of `profile`, if it has one, and
+/// points `options` at its local end.
+pub fn open_tunnel(
+    profile: &ConnectionProfile,
+    options: &mut ClientOptions,
+) -> Result<Option<Tunnel>, Error> {
+    if profile.ssh_tunnel.is_none() && profile.proxy.is_none() {
+        return Ok(None);
+    }
+    // Only a single server can sit behind the forwarded port, so the driver
+    // must not try to discover the other members of a replica set. The driver
+    // checks TLS certificates against the address it connects to, which is
+    // 127.0.0.1 and can't be told apart from the server name, so TLS only
+    // works when certificates aren't checked.
+    let (tls, unchecked) = match &options.tls {
+        Some(Tls::Enabled(tls)) => (true, tls.allow_invalid_certificates == Some(true)),
+        _ => (profile.tls_cert_file.is_some(), false),
+    };
+    if tls && !unchecked {
+        return Err(Error::InvalidArgument(
+            "TLS through an SSH tunnel or proxy can't verify the server's certificate, \
+            allow invalid certificates or connect without the tunnel"
+                .to_string(),
+        ));
+    }
+    let (host, port) = match options.hosts.as_slice() {
+        [ServerAddress::Tcp { host, port }] => (host.clone(), port.unwrap_or(27017)),
+        _ => {
+            return Err(Error::InvalidArgument(
+                "an SSH tunnel or proxy can only reach a single host".to_string(),
+            ))
+        }
+    };
+    let tunnel = match (&profile.ssh_tunnel, &profile.proxy) {
+        (Some(ssh), _) => Tunnel::ssh(ssh, &host, port)?,
+        (None, Some(proxy)) => Tunnel::proxy(proxy, &host, port)?,
+        (None, None) => unreachable!(),
+    };
+    options.hosts = vec![ServerAddress::Tcp {
+        host: "127.0.0.1".to_string(),
+        port: Some(tunnel.local_port()),
+    }];
+    options.direct_connection = Some(true);
+    Ok(Some(tunnel))
+}
+
+/// Builds a client from `options`, adding the client certificate of
+/// `profile`.
+pub fn build_client(
+    profile: &ConnectionProfile,
+    mut options: ClientOptions,
+) -> Result<mongodb::sync::Client, Error> {
+    // The driver wants the certificate and its key in one file, and reads it
+    // while the client is created, so a merged copy only has to live until then.
+    let mut merged = None;
+    if let Some(cert_file) = &profile.tls_cert_file {
+        let path = match &profile.tls_key_file {
+            Some(key_file) if key_file != cert_file => {
+                let path = merge_cert_key(cert_file, key_file)?;
+                merged = Some(path.clone());
+                path
+            }
+            _ => PathBuf::from(cert_file),
+        };
+        tls_options(&mut options).cert_key_file_path = Some(path);
+    }
+
+    let client = mongodb::sync::Client::with_options(options);
+    if let Some(path) = merged {
+        let _ = std::fs::remove_file(path);
+    }
+    Ok(client?)
+}
+
+/// The client certificate chain and key of `profile`, for TLS made outside
+/// the driver.
+pub fn client_identity(
+    profile: &ConnectionProfile,
+) -> Result<Option<(Vec<rustls::Certificate>, rustls::PrivateKey)>, Error> {
+    let Some(cert_file) = &profile.tls_cert_file else {
+        return Ok(None);
+    };
+    let certs = read_pem(cert_file)?
+        .into_iter()
+        .filter_map(|item| match item {
+            rustls_pemfile::Item::X509Certificate(cert) => Some(rustls::Certificate(cert)),
+            _ => None,
+        })
+        .collect();
+    let key_file = profile.tls_key_file.as_ref().unwrap_or(cert_file);
+    let key = read_pem(key_file)?
+        .into_iter()
+        .find_map(|item| match item {
+            rustls_pemfile::Item::RSAKey(key)
+            | rustls_pemfile::Item::PKCS8Key(key)
+            | rustls_pemfile::Item::ECKey(key) => Some(rustls::PrivateKey(key)),
+            _ => None,
+        })
+        .ok_or_else(|| Error::InvalidArgument(format!("{} holds no PEM private key", key_file)))?;
+    Ok(Some((certs, key)))
+}
+
+fn merge_cert_key(cert_file: &str, key_file: &str) -> Result<PathBuf, Error> {
+    let read = |path: &str| {
+        std::fs::read(path)
+            .map_err(|err| Error::InvalidArgument(format!("failed to read {}: {}", path, err)))
+    };
+    let pem = [read(cert_file)?, b"\n".to_vec(), read(key_file)?].concat();
+    let path = std::env::temp_dir().join(format!("mongoui-{}.pem", uuid::Uuid::new_v4()));
+
+    let mut file = std::fs::OpenOptions::new();
+    file.write(true).create_new(true);
+    #[cfg(unix)]
+    std::os::unix::fs::OpenOptionsExt::mode(&mut file, 0o600);
+    file.open(&path)
+        .and_then(|mut file| std::io::Write::write_all(&mut file, &pem))
+        .map_err(|err| Error::Storage(format!("failed to write {}: {}", path.display(), err)))?;
+    Ok(path)
+}
+
+pub struct SyncClientEntry {
+    pub client: mongodb::sync::Client,
+    pub id: String,
+    uri: String,
+    pub profile: ConnectionProfile,
+    /// The saved connection this was opened from, if any.
+    pub saved_db_id: Option<i32>,
+    /// Label of the window that opened the connection.
+    window: String,
+    opened_at: i64,
+    selected_db: Option<String>,
+    /// Closed when the entry is dropped, after the client.
+    tunnel: Option<Tunnel>,
+    /// What the scripts run on this connection imported, for the next run.
+    pub sources: Arc<Mutex<SourceCache>>,
+}
+
+impl SyncClientEntry {
+    pub fn new(uri: String, profile: ConnectionProfile, window: &str) -> Result<Self, Error> {
+        let (client, tunnel) = connect(&uri, &profile)?;
+        Ok(Self {
+            id: uuid::Uuid::new_v4().to_string(),
+            client,
+            uri,
+            profile,
+            saved_db_id: None,
+            tunnel,
+            window: window.to_string(),
+            opened_at: SystemTime::now()
+                .duration_since(UNIX_EPOCH)
+                .map_or(0, |d| d.as_secs() as i64),
+            selected_db: None,
+            sources: Arc::default(),
+        })
+    }
+
+    pub fn uri(&self) -> &str {
+        &self.uri
+    }
+
+    /// Swaps in a client built by [`connect`] from this entry's URI and
+    /// profile. The old client and tunnel are handed back so that they can be
+    /// shut down once the registry lock is released.
+    pub fn replace_client(
+        &mut self,
+        client: mongodb::sync::Client,
+        tunnel: Option<Tunnel>,
+    ) -> (mongodb::sync::Client, Option<Tunnel>) {
+        (
+            std::mem::replace(&mut self.client, client),
+            std::mem::replace(&mut self.tunnel, tunnel),
+        )
+    }
+
+    pub fn info(&self) -> ConnectionInfo {
+        ConnectionInfo {
+            id: self.id.clone(),
+            host: uri_host(&self.uri).to_string(),
+            saved_db_id: self.saved_db_id,
+            window: self.window.clone(),
+            opened_at: self.opened_at,
+            selected_db: self.selected_db.clone(),
+        }
+    }
+}
+
+#[derive(Serialize)]
+#[serde(rename_all = "camelCase")]
+pub struct ConnectionInfo {
+    id: String,
+    pub host: String,
+    pub saved_db_id: Option<i32>,
+    window: String,
+    opened_at: i64,
+    selected_db: Option<String>,
+}
+
+/// Returns the host list of a connection string, without scheme, credentials,
+/// default database or options.
+pub fn uri_host(uri: &str) -> &str {
+    let rest = uri.split_once("://").map_or(uri, |(_, rest)| rest);
+    let rest = rest.split(['/', '?']).next().unwrap_or(rest);
+    rest.rsplit_once('@').map_or(rest, |(_, host)| host)
+}
+
+pub fn connection_info(client_id: &str) -> Result<ConnectionInfo, Error> {
+    CLIENTS
+        .read()?
+        .iter()
+        .find(|c| c.id == client_id)
+        .map(SyncClientEntry::info)
+        .ok_or(Error::InvalidArgument("client not found".to_string()))
+}
+
+/// A handle to the client of a connection, for long running work that
+/// shouldn't hold the registry lock.
+pub fn get_client(client_id: &str) -> Result<mongodb::sync::Client, Error> {
+    CLIENTS
+        .read()?
+        .iter()
+        .find(|c| c.id == client_id)
+        .map(|c| c.client.clone())
+        .ok_or(Error::InvalidArgument("client not found".to_string()))
+}
+
+/// A handle to the client of a connection, for long running writes that
+/// shouldn't hold the registry lock. Fails for read-only connections.
+pub fn get_writable_client(client_id: &str) -> Result<mongodb::sync::Client, Error> {
+    let clients = CLIENTS.read()?;
+    let entry = clients
+        .iter()
+        .find(|c| c.id == client_id)
+        .ok_or(Error::InvalidArgument("client not found".to_string()))?;
+    if entry.profile.read_only {
+        return Err(Error::InvalidArgument(
+            "connection is read-only".to_string(),
+        ));
+    }
+    Ok(entry.client.clone())
+}
+
+pub fn select_db(client_id: &str, db_name: &str) -> Result<(), Error> {
+    let mut clients = CLIENTS.write()?;
+    let entry = clients
+        .iter_mut()
+        .find(|c| c.id == client_id)
+        .ok_or(Error::InvalidArgument("client not found".to_string()))?;
+    entry.selected_db = Some(db_name.to_string());
+    Ok(())
+}
+
+/// Removes the client from the registry and closes its connection pool.
+pub fn disconnect(client_id: &str) -> Result<(), Error> {
+    let entry = {
+        let mut clients = CLIENTS.write()?;
+        let idx = clients
+            .iter()
+            .position(|c| c.id == client_id)
+            .ok_or(Error::InvalidArgument("client not found".to_string()))?;
+        clients.remove(idx)
+    };
+    entry.client.shutdown_immediate();
+    Ok(())
+}
+
+/// Disconnects every client opened by the given window.
+pub fn reap_window(window: &str) {
+    let entries = match CLIENTS.write() {
+        Ok(mut clients) => {
+            let (reaped, kept) = clients.drain(..).partition(|c| c.window == window);
+            *clients = kept;
+            reaped
+        }
+        Err(_) => Vec::<SyncClientEntry>::new(),
+    };
+    for entry in entries {
+        entry.client.shutdown_immediate();
+    }
+}
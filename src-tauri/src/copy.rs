@@ -0,0 +1,525 @@
+use std::{
+    collections::HashMap,
+    sync::{
+        atomic::{AtomicBool, Ordering},
+        Arc, Mutex,
+    },
+};
+
+use lazy_static::lazy_static;
+use mongodb::{
+    bson::{doc, Bson, Document, RawDocumentBuf},
+    options::FindOptions,
+    sync::Client,
+};
+use serde::{Deserialize, Serialize};
+use serde_json::Value;
+
+use crate::{
+    dump::{self, CollectionMeta},
+    error::Error,
+    export::query_document,
+    import,
+};
+
+lazy_static! {
+    /// Cancellation flags of the copies in progress, by id.
+    static ref COPIES: Mutex<HashMap<String, Arc<AtomicBool>>> = Mutex::new(HashMap::new());
+}
+
+/// One side of a copy.
+pub struct Endpoint<'a> {
+    pub client: &'a Client,
+    pub client_id: &'a str,
+    pub database: &'a str,
+}
+
+impl Endpoint<'_> {
+    /// Whether both sides reach the same server. Host names can't tell, a
+    /// tunnel makes another server look local and one server has many names,
+    /// so unless both sides share a connection the servers are asked.
+    fn same_server(&self, other: &Endpoint) -> Result<bool, Error> {
+        if self.client_id == other.client_id {
+            return Ok(true);
+        }
+        let identity = ServerIdentity::of(self.client)?;
+        Ok(identity.is_some() && identity == ServerIdentity::of(other.client)?)
+    }
+}
+
+/// What the server behind a connection says about itself.
+#[derive(Debug, PartialEq)]
+struct ServerIdentity {
+    /// The replica set, from `hello`.
+    set_name: Option<String>,
+    /// `host` from `serverStatus`, or `me` from `hello` when the user may not
+    /// run `serverStatus`.
+    host: String,
+    /// The process id from `serverStatus`.
+    pid: Option<i64>,
+}
+
+impl ServerIdentity {
+    fn of(client: &Client) -> Result<Option<Self>, Error> {
+        let admin = client.database("admin");
+        let hello = admin.run_command(doc! { "hello": 1 }, None)?;
+        let status = admin.run_command(doc! { "serverStatus": 1 }, None).ok();
+        Ok(Self::from_replies(&hello, status.as_ref()))
+    }
+
+    /// `None` when neither reply names the server, e.g. a standalone server
+    /// the user may not run `serverStatus` on.
+    fn from_replies(hello: &Document, status: Option<&Document>) -> Option<Self> {
+        let set_name = hello.get_str("setName").ok().map(String::from);
+        let pid = |status: &Document| match status.get("pid") {
+            Some(Bson::Int64(pid)) => Some(*pid),
+            Some(Bson::Int32(pid)) => Some(*pid as i64),
+            _ => None,
+        };
+        match status.and_then(|status| Some((status.get_str("host").ok()?, pid(status)))) {
+            Some((host, pid)) => Some(Self {
+                set_name,
+                host: host.to_string(),
+                pid,
+            }),
+            None => Some(Self {
+                host: hello.get_str("me").ok()?.to_string(),
+                set_name,
+                pid: None,
+            }),
+        }
+    }
+}
+
+#[derive(Deserialize)]
+#[serde(rename_all = "camelCase", default)]
+pub struct CopyOptions {
+    /// Copies only the documents matching this Extended JSON filter.
+    pub filter: Option<Value>,
+    /// Drops the target collection first.
+    pub drop_target: bool,
+    pub copy_indexes: bool,
+    /// Documents sent to the target at a time.
+    pub batch_size: usize,
+}
+
+impl Default for CopyOptions {
+    fn default() -> Self {
+        Self {
+            filter: None,
+            drop_target: false,
+            copy_indexes: true,
+            batch_size: 1000,
+        }
+    }
+}
+
+#[derive(Deserialize)]
+#[serde(rename_all = "camelCase", default)]
+pub struct CopyDatabaseOptions {
+    /// Collections to copy, every one if empty.
+    pub collections: Vec<String>,
+    pub drop_target: bool,
+    pub copy_indexes: bool,
+    pub batch_size: usize,
+}
+
+impl Default for CopyDatabaseOptions {
+    fn default() -> Self {
+        Self {
+            collections: Vec::new(),
+            drop_target: false,
+            copy_indexes: true,
+            batch_size: 1000,
+        }
+    }
+}
+
+/// Sent after every batch and every collection, and returned once the copy
+/// is done or cancelled.
+#[derive(Serialize, Default, Clone)]
+#[serde(rename_all = "camelCase")]
+pub struct CopyProgress {
+    /// The id the copy was started with, which [`cancel`] takes.
+    pub id: String,
+    /// The source collection in progress, as `db.collection`.
+    pub namespace: Option<String>,
+    /// Collections done.
+    pub collections: u64,
+    pub copied: u64,
+    /// Documents the target rejected, such as duplicate keys.
+    pub failed: u64,
+    /// Documents to copy, as estimated before starting.
+    pub total: u64,
+    pub cancelled: bool,
+}
+
+/// Registers a copy with [`COPIES`] for as long as it runs.
+struct CopyHandle {
+    id: String,
+    cancelled: Arc<AtomicBool>,
+}
+
+impl CopyHandle {
+    fn new(id: &str) -> Result<Self, Error> {
+        let mut copies = COPIES.lock()?;
+        if copies.contains_key(id) {
+            return Err(Error::Conflict(format!("copy {} is already running", id)));
+        }
+        let handle = CopyHandle {
+            id: id.to_string(),
+            cancelled: Arc::new(AtomicBool::new(false)),
+        };
+        copies.insert(handle.id.clone(), handle.cancelled.clone());
+        Ok(handle)
+    }
+
+    fn is_cancelled(&self) -> bool {
+        self.cancelled.load(Ordering::Relaxed)
+    }
+}
+
+impl Drop for CopyHandle {
+    fn drop(&mut self) {
+        if let Ok(mut copies) = COPIES.lock() {
+            copies.remove(&self.id);
+        }
+    }
+}
+
+/// Stops a running copy after the batch in flight.
+pub fn cancel(id: &str) -> Result<(), Error> {
+    match COPIES.lock()?.get(id) {
+        Some(cancelled) => {
+            cancelled.store(true, Ordering::Relaxed);
+            Ok(())
+        }
+        None => Err(Error::NotFound(format!("copy {} is not running", id))),
+    }
+}
+
+/// Copies the documents of a collection, along with its options and indexes,
+/// possibly to another connection. `id` can be cancelled as soon as the copy
+/// starts.
+pub fn copy_collection(
+    id: &str,
+    source: Endpoint,
+    source_collection: &str,
+    target: Endpoint,
+    target_collection: &str,
+    options: &CopyOptions,
+    on_progress: &mut dyn FnMut(&CopyProgress),
+) -> Result<CopyProgress, Error> {
+    if source.database == target.database
+        && source_collection == target_collection
+        && source.same_server(&target)?
+    {
+        return Err(Error::InvalidArgument(
+            "cannot copy a collection onto itself".to_string(),
+        ));
+    }
+    let filter = query_document(options.filter.clone(), "filter")?;
+    let meta = dump::list_collections(
+        &source.client.database(source.database),
+        Some(doc! { "name": source_collection }),
+    )?
+    .pop()
+    .ok_or_else(|| {
+        Error::NotFound(format!(
+            "collection {}.{} not found",
+            source.database, source_collection
+        ))
+    })?;
+
+    let mut copier = Copier::new(
+        id,
+        &source,
+        &target,
+        options.drop_target,
+        options.copy_indexes,
+        options.batch_size,
+        on_progress,
+    )?;
+    copier.progress.total = copier.count(&meta, filter.clone())?;
+    copier.copy(&meta, target_collection, filter)?;
+    Ok(copier.finish())
+}
+
+/// Copies every collection of a database, or the chosen ones, under the
+/// same names.
+pub fn copy_database(
+    id: &str,
+    source: Endpoint,
+    target: Endpoint,
+    options: &CopyDatabaseOptions,
+    on_progress: &mut dyn FnMut(&CopyProgress),
+) -> Result<CopyProgress, Error> {
+    if source.database == target.database && source.same_server(&target)? {
+        return Err(Error::InvalidArgument(
+            "cannot copy a database onto itself".to_string(),
+        ));
+    }
+    let collections: Vec<CollectionMeta> =
+        dump::list_collections(&source.client.database(source.database), None)?
+            .into_iter()
+            .filter(|meta| !meta.name.starts_with("system."))
+            .filter(|meta| {
+                options.collections.is_empty() || options.collections.contains(&meta.name)
+            })
+            .collect();
+
+    let mut copier = Copier::new(
+        id,
+        &source,
+        &target,
+        options.drop_target,
+        options.copy_indexes,
+        options.batch_size,
+        on_progress,
+    )?;
+    for meta in &collections {
+        copier.progress.total += copier.count(meta, None)?;
+    }
+    for meta in &collections {
+        copier.copy(meta, &meta.name, None)?;
+    }
+    Ok(copier.finish())
+}
+
+struct Copier<'a> {
+    source: &'a Endpoint<'a>,
+    target: &'a Endpoint<'a>,
+    drop_target: bool,
+    copy_indexes: bool,
+    batch_size: usize,
+    handle: CopyHandle,
+    progress: CopyProgress,
+    on_progress: &'a mut dyn FnMut(&CopyProgress),
+}
+
+impl<'a> Copier<'a> {
+    fn new(
+        id: &str,
+        source: &'a Endpoint<'a>,
+        target: &'a Endpoint<'a>,
+        drop_target: bool,
+        copy_indexes: bool,
+        batch_size: usize,
+        on_progress: &'a mut dyn FnMut(&CopyProgress),
+    ) -> Result<Self, Error> {
+        let handle = CopyHandle::new(id)?;
+        Ok(Self {
+            source,
+            target,
+            drop_target,
+            copy_indexes,
+            batch_size: batch_size.max(1),
+            progress: CopyProgress {
+                id: handle.id.clone(),
+                ..Default::default()
+            },
+            handle,
+            on_progress,
+        })
+    }
+
+    fn count(&self, meta: &CollectionMeta, filter: Option<Document>) -> Result<u64, Error> {
+        if meta.is_view() {
+            return Ok(0);
+        }
+        let collection = self
+            .source
+            .client
+            .database(self.source.database)
+            .collection::<Document>(&meta.name);
+        Ok(match filter {
+            Some(filter) => collection.count_documents(filter, None)?,
+            None => collection.estimated_document_count(None)?,
+        })
+    }
+
+    fn copy(
+        &mut self,
+        meta: &CollectionMeta,
+        target_collection: &str,
+        filter: Option<Document>,
+    ) -> Result<(), Error> {
+        // Cancelled while counting.
+        if self.handle.is_cancelled() {
+            return Ok(());
+        }
+        self.progress.namespace = Some(format!("{}.{}", self.source.database, meta.name));
+        (self.on_progress)(&self.progress);
+        let target_database = self.target.client.database(self.target.database);
+        let target = target_database.collection::<RawDocumentBuf>(target_collection);
+        if self.drop_target {
+            target.drop(None)?;
+        }
+        dump::create_collection(&target_database, target_collection, &meta.options)?;
+
+        if !meta.is_view() {
+            let options = FindOptions::builder()
+                .batch_size(self.batch_size as u32)
+                .build();
+            let mut cursor = self
+                .source
+                .client
+                .database(self.source.database)
+                .collection::<Document>(&meta.name)
+                .find(filter, options)?;
+            let mut batch = Vec::with_capacity(self.batch_size);
+            while cursor.advance()? {
+                batch.push(cursor.current().to_raw_document_buf());
+                if batch.len() >= self.batch_size {
+                    self.insert(&target, std::mem::take(&mut batch))?;
+                    if self.handle.is_cancelled() {
+                        return Ok(());
+                    }
+                }
+            }
+            self.insert(&target, batch)?;
+            if self.copy_indexes {
+                dump::create_indexes(&target_database, target_collection, &meta.indexes)?;
+            }
+        }
+        self.progress.collections += 1;
+        (self.on_progress)(&self.progress);
+        Ok(())
+    }
+
+    fn insert(
+        &mut self,
+        target: &mongodb::sync::Collection<RawDocumentBuf>,
+        batch: Vec<RawDocumentBuf>,
+    ) -> Result<(), Error> {
+        if batch.is_empty() {
+            return Ok(());
+        }
+        let inserted = import::insert_documents(target, batch, false)?;
+        self.progress.failed += inserted.rejected;
+        self.progress.copied += inserted.inserted;
+        (self.on_progress)(&self.progress);
+        Ok(())
+    }
+
+    fn finish(mut self) -> CopyProgress {
+        self.progress.namespace = None;
+        self.progress.cancelled = self.handle.is_cancelled();
+        (self.on_progress)(&self.progress);
+        self.progress
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// A client that is never reached, for checks that happen before any
+    /// command is sent.
+    fn unreachable_client() -> Client {
+        Client::with_uri_str("mongodb://127.0.0.1:1/?serverSelectionTimeoutMS=100").unwrap()
+    }
+
+    #[test]
+    fn refuses_to_copy_onto_itself() {
+        let client = unreachable_client();
+        let endpoint = |database| Endpoint {
+            client: &client,
+            client_id: "a",
+            database,
+        };
+        let err = copy_collection(
+            "self-collection",
+            endpoint("app"),
+            "users",
+            endpoint("app"),
+            "users",
+            &CopyOptions::default(),
+            &mut |_| {},
+        )
+        .err()
+        .unwrap();
+        assert!(err.to_string().contains("onto itself"), "{}", err);
+
+        let err = copy_database(
+            "self-database",
+            endpoint("app"),
+            endpoint("app"),
+            &CopyDatabaseOptions::default(),
+            &mut |_| {},
+        )
+        .err()
+        .unwrap();
+        assert!(err.to_string().contains("onto itself"), "{}", err);
+    }
+
+    #[test]
+    fn servers_are_told_apart_by_what_they_report() {
+        let hello = doc! { "setName": "rs0", "me": "db1:27017" };
+        let status = doc! { "host": "db1.internal:27017", "pid": 42i64 };
+        let identity = ServerIdentity::from_replies(&hello, Some(&status)).unwrap();
+        assert_eq!(identity.host, "db1.internal:27017");
+        assert_eq!(identity.pid, Some(42));
+
+        let restarted = doc! { "host": "db1.internal:27017", "pid": 43i64 };
+        assert_ne!(
+            Some(identity),
+            ServerIdentity::from_replies(&hello, Some(&restarted))
+        );
+
+        let without_status = ServerIdentity::from_replies(&hello, None).unwrap();
+        assert_eq!(without_status.host, "db1:27017");
+        assert_eq!(
+            ServerIdentity::from_replies(&doc! { "isWritablePrimary": true }, None),
+            None
+        );
+    }
+
+    #[test]
+    fn copies_are_registered_while_they_run() {
+        let handle = CopyHandle::new("registered").unwrap();
+        assert!(matches!(
+            CopyHandle::new("registered"),
+            Err(Error::Conflict(_))
+        ));
+
+        cancel("registered").unwrap();
+        assert!(handle.is_cancelled());
+
+        drop(handle);
+        assert!(matches!(cancel("registered"), Err(Error::NotFound(_))));
+        CopyHandle::new("registered").unwrap();
+    }
+
+    #[test]
+    fn a_cancelled_copy_stops_before_the_next_collection() {
+        let client = unreachable_client();
+        let endpoint = |database| Endpoint {
+            client: &client,
+            client_id: "a",
+            database,
+        };
+        let (source, target) = (endpoint("app"), endpoint("backup"));
+        let mut reported = Vec::new();
+        let mut on_progress = |progress: &CopyProgress| reported.push(progress.cancelled);
+        let mut copier = Copier::new(
+            "cancelled",
+            &source,
+            &target,
+            false,
+            true,
+            10,
+            &mut on_progress,
+        )
+        .unwrap();
+        cancel("cancelled").unwrap();
+
+        copier
+            .copy(&CollectionMeta::plain("app", "users"), "users", None)
+            .unwrap();
+        let progress = copier.finish();
+        assert!(progress.cancelled);
+        assert_eq!(progress.collections, 0);
+        assert_eq!(reported, [true]);
+    }
+}
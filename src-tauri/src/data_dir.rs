@@ -0,0 +1,91 @@
+use std::{
+    env,
+    path::{Path, PathBuf},
+};
+
+use crate::error::Error;
+
+/// Environment variable overriding the data directory.
+const DATA_DIR_ENV: &str = "MONGOUI_DATA_DIR";
+/// Command line flag overriding the data directory, `--data-dir <path>` or
+/// `--data-dir=<path>`.
+const DATA_DIR_FLAG: &str = "--data-dir";
+
+fn flag_value() -> Option<PathBuf> {
+    let mut args = env::args().skip(1);
+    while let Some(arg) = args.next() {
+        if arg == DATA_DIR_FLAG {
+            return args.next().map(PathBuf::from);
+        }
+        if let Some(value) = arg
+            .strip_prefix(DATA_DIR_FLAG)
+            .and_then(|v| v.strip_prefix('='))
+        {
+            return Some(PathBuf::from(value));
+        }
+    }
+    None
+}
+
+fn non_empty_env(key: &str) -> Option<PathBuf> {
+    env::var_os(key)
+        .filter(|value| !value.is_empty())
+        .map(PathBuf::from)
+}
+
+/// The directory picked for the local store.
+pub struct DataDir {
+    pub path: PathBuf,
+    /// Things the user should know about how it was picked.
+    pub warnings: Vec<String>,
+}
+
+/// Picks the directory the local store lives in, in order of preference:
+/// the `--data-dir` flag, `$MONGOUI_DATA_DIR`, `$XDG_CONFIG_HOME/mongoui` or
+/// `~/.config/mongoui`, whichever already holds a store and in that order,
+/// then `$XDG_CONFIG_HOME/mongoui` and finally the platform config dir Tauri
+/// resolved for the app. An existing store wins over a directory that is only
+/// configured, so setting `XDG_CONFIG_HOME` later doesn't hide it.
+///
+/// The directory is created if it doesn't exist.
+pub fn resolve(app_config_dir: Option<PathBuf>) -> Result<DataDir, Error> {
+    let legacy = non_empty_env("HOME").map(|home| home.join(".config").join("mongoui"));
+    let xdg = non_empty_env("XDG_CONFIG_HOME").map(|dir| dir.join("mongoui"));
+    let has_store = |dir: &PathBuf| dir.join("saved.db").exists();
+
+    let path = flag_value()
+        .or_else(|| non_empty_env(DATA_DIR_ENV))
+        .or_else(|| xdg.clone().filter(has_store))
+        .or_else(|| legacy.clone().filter(has_store))
+        .or(xdg)
+        .or(app_config_dir)
+        .or(legacy.clone())
+        .ok_or_else(|| {
+            Error::Storage(format!(
+                "could not determine a data directory, set {} or pass {}",
+                DATA_DIR_ENV, DATA_DIR_FLAG
+            ))
+        })?;
+
+    let mut warnings = Vec::new();
+    if let Some(legacy) = legacy.filter(|legacy| *legacy != path && has_store(legacy)) {
+        warnings.push(format!(
+            "the saved connections in {} are not used, {} is used instead",
+            legacy.display(),
+            path.display()
+        ));
+    }
+
+    create(&path)?;
+    Ok(DataDir { path, warnings })
+}
+
+fn create(dir: &Path) -> Result<(), Error> {
+    std::fs::create_dir_all(dir).map_err(|err| {
+        Error::Storage(format!(
+            "failed to create data directory {}: {}",
+            dir.display(),
+            err
+        ))
+    })
+}
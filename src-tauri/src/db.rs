@@ -1,71 +1,2258 @@
-use std::sync::Mutex;
+use std::{
+    path::{Path, PathBuf},
+    sync::{Mutex, MutexGuard, OnceLock},
+};
 
-use lazy_static::lazy_static;
+use rusqlite::{Connection, ErrorCode, OptionalExtension, Row};
+use serde::{Deserialize, Serialize};
 
-use rusqlite::{Connection, Error, Result};
-use serde::Serialize;
+use crate::{
+    client::ConnectionProfile, data_dir::DataDir, error::Error, proxy::ProxyOptions,
+    tunnel::SshTunnelOptions, vault,
+};
 
-lazy_static! {
-    static ref CONN: Mutex<Connection> =
-        Mutex::new(Connection::open(format!("{}/.config/mongoui/saved.db", env!("HOME"))).unwrap());
+/// The store's connection, or why it could not be opened.
+static CONN: OnceLock<Result<Mutex<Connection>, String>> = OnceLock::new();
+
+fn conn() -> Result<MutexGuard<'static, Connection>, Error> {
+    match CONN.get() {
+        Some(Ok(con)) => Ok(con.lock()?),
+        Some(Err(err)) => Err(Error::Storage(err.clone())),
+        None => Err(Error::Storage(
+            "the local store is not open yet".to_string(),
+        )),
+    }
+}
+
+/// Schema migrations, applied in order. The database's `user_version` is the
+/// number of migrations already applied, so never edit or reorder these, only
+/// append new ones.
+const MIGRATIONS: &[&str] = &[
+    // 1: the original schema, already present on databases created before
+    // migrations existed.
+    "CREATE TABLE IF NOT EXISTS saved_dbs (
+        id         INTEGER PRIMARY KEY,
+        name       TEXT NOT NULL UNIQUE,
+        uri        TEXT NOT NULL,
+        created_at INTEGER NOT NULL
+    );",
+    // 2: encrypted credentials.
+    "CREATE TABLE IF NOT EXISTS vault (
+        id       INTEGER PRIMARY KEY CHECK (id = 1),
+        salt     BLOB NOT NULL,
+        verifier BLOB NOT NULL
+    );
+    CREATE TABLE IF NOT EXISTS saved_db_credentials (
+        saved_db_id INTEGER PRIMARY KEY REFERENCES saved_dbs(id) ON DELETE CASCADE,
+        credentials BLOB NOT NULL
+    );",
+    // 3: connection profiles.
+    "ALTER TABLE saved_dbs ADD COLUMN default_database TEXT;
+    ALTER TABLE saved_dbs ADD COLUMN read_preference TEXT;
+    ALTER TABLE saved_dbs ADD COLUMN app_name TEXT;
+    ALTER TABLE saved_dbs ADD COLUMN connect_timeout_ms INTEGER;
+    ALTER TABLE saved_dbs ADD COLUMN server_selection_timeout_ms INTEGER;
+    ALTER TABLE saved_dbs ADD COLUMN tls_ca_file TEXT;
+    ALTER TABLE saved_dbs ADD COLUMN tls_cert_file TEXT;
+    ALTER TABLE saved_dbs ADD COLUMN tls_key_file TEXT;
+    ALTER TABLE saved_dbs ADD COLUMN auth_mechanism TEXT;
+    ALTER TABLE saved_dbs ADD COLUMN direct_connection INTEGER;
+    ALTER TABLE saved_dbs ADD COLUMN color TEXT;
+    ALTER TABLE saved_dbs ADD COLUMN read_only INTEGER NOT NULL DEFAULT 0;",
+    // 4: folders, tags, favorites and ordering.
+    "CREATE TABLE IF NOT EXISTS folders (
+        id         INTEGER PRIMARY KEY,
+        name       TEXT NOT NULL UNIQUE,
+        sort_order INTEGER NOT NULL DEFAULT 0
+    );
+    ALTER TABLE saved_dbs ADD COLUMN folder_id INTEGER REFERENCES folders(id);
+    ALTER TABLE saved_dbs ADD COLUMN favorite INTEGER NOT NULL DEFAULT 0;
+    ALTER TABLE saved_dbs ADD COLUMN sort_order INTEGER NOT NULL DEFAULT 0;
+    ALTER TABLE saved_dbs ADD COLUMN last_used_at INTEGER;
+    UPDATE saved_dbs SET sort_order = id;
+    CREATE TABLE IF NOT EXISTS saved_db_tags (
+        saved_db_id INTEGER NOT NULL REFERENCES saved_dbs(id) ON DELETE CASCADE,
+        tag         TEXT NOT NULL,
+        PRIMARY KEY (saved_db_id, tag)
+    );",
+    // 5: explicit TLS settings.
+    "ALTER TABLE saved_dbs ADD COLUMN tls INTEGER;
+    ALTER TABLE saved_dbs ADD COLUMN tls_allow_invalid_hostnames INTEGER;
+    ALTER TABLE saved_dbs ADD COLUMN tls_allow_invalid_certificates INTEGER;",
+    // 6: SSH tunnels, the password and passphrase are encrypted by the vault.
+    "ALTER TABLE saved_dbs ADD COLUMN ssh_host TEXT;
+    ALTER TABLE saved_dbs ADD COLUMN ssh_port INTEGER;
+    ALTER TABLE saved_dbs ADD COLUMN ssh_username TEXT;
+    ALTER TABLE saved_dbs ADD COLUMN ssh_private_key_file TEXT;
+    ALTER TABLE saved_dbs ADD COLUMN ssh_known_hosts_file TEXT;
+    ALTER TABLE saved_dbs ADD COLUMN ssh_password BLOB;
+    ALTER TABLE saved_dbs ADD COLUMN ssh_passphrase BLOB;",
+    // 7: proxies, the password is encrypted by the vault.
+    "ALTER TABLE saved_dbs ADD COLUMN proxy_kind TEXT;
+    ALTER TABLE saved_dbs ADD COLUMN proxy_host TEXT;
+    ALTER TABLE saved_dbs ADD COLUMN proxy_port INTEGER;
+    ALTER TABLE saved_dbs ADD COLUMN proxy_username TEXT;
+    ALTER TABLE saved_dbs ADD COLUMN proxy_password BLOB;",
+    // 8: script history and app settings.
+    "CREATE TABLE IF NOT EXISTS history (
+        id           INTEGER PRIMARY KEY,
+        saved_db_id  INTEGER REFERENCES saved_dbs(id) ON DELETE SET NULL,
+        host         TEXT NOT NULL,
+        database     TEXT NOT NULL,
+        script       TEXT NOT NULL,
+        duration_ms  INTEGER NOT NULL,
+        result_count INTEGER,
+        error        TEXT,
+        executed_at  INTEGER NOT NULL
+    );
+    CREATE INDEX IF NOT EXISTS history_executed_at ON history(executed_at);
+    CREATE TABLE IF NOT EXISTS settings (
+        key   TEXT PRIMARY KEY,
+        value TEXT NOT NULL
+    );",
+    // 9: saved scripts.
+    "CREATE TABLE IF NOT EXISTS saved_scripts (
+        id          INTEGER PRIMARY KEY,
+        name        TEXT NOT NULL UNIQUE,
+        description TEXT,
+        folder      TEXT,
+        body        TEXT NOT NULL,
+        saved_db_id INTEGER REFERENCES saved_dbs(id) ON DELETE SET NULL,
+        database    TEXT,
+        created_at  INTEGER NOT NULL,
+        updated_at  INTEGER NOT NULL
+    );
+    CREATE TABLE IF NOT EXISTS saved_script_tags (
+        script_id INTEGER NOT NULL REFERENCES saved_scripts(id) ON DELETE CASCADE,
+        tag       TEXT NOT NULL,
+        PRIMARY KEY (script_id, tag)
+    );",
+    // 10: open editor tabs.
+    "CREATE TABLE IF NOT EXISTS workspace (
+        id              INTEGER PRIMARY KEY,
+        position        INTEGER NOT NULL,
+        saved_db_id     INTEGER REFERENCES saved_dbs(id) ON DELETE CASCADE,
+        saved_script_id INTEGER REFERENCES saved_scripts(id) ON DELETE SET NULL,
+        title           TEXT,
+        script          TEXT NOT NULL,
+        database        TEXT,
+        cursor_line     INTEGER,
+        cursor_column   INTEGER,
+        result_count    INTEGER,
+        duration_ms     INTEGER,
+        error           TEXT,
+        executed_at     INTEGER,
+        active          INTEGER NOT NULL DEFAULT 0
+    );",
+];
+
+/// Brings the schema up to date. Before touching an existing database a copy
+/// of it is written next to `path` as `<path>.bak`. The copy is kept until the
+/// next open finds the schema current, so that a release that breaks after
+/// the upgrade can still be rolled back, and removed then since older schemas
+/// keep credentials in plain text. Each migration runs in its own transaction
+/// together with the `user_version` bump.
+fn migrate(con: &mut Connection, migrations: &[&str], path: Option<&str>) -> Result<(), Error> {
+    let version: usize = con.query_row("PRAGMA user_version", (), |row| row.get(0))?;
+    if version >= migrations.len() {
+        if let Some(path) = path {
+            let _ = std::fs::remove_file(format!("{}.bak", path));
+        }
+        return Ok(());
+    }
+
+    let has_tables: bool = con.query_row(
+        "SELECT EXISTS (SELECT 1 FROM sqlite_master WHERE type = 'table')",
+        (),
+        |row| row.get(0),
+    )?;
+    if let (Some(path), true) = (path, has_tables) {
+        let backup = format!("{}.bak", path);
+        let _ = std::fs::remove_file(&backup);
+        con.execute("VACUUM INTO ?1", (&backup,))?;
+    }
+
+    for (idx, migration) in migrations.iter().enumerate().skip(version) {
+        let tx = con.transaction()?;
+        tx.execute_batch(migration)?;
+        tx.pragma_update(None, "user_version", idx + 1)?;
+        tx.commit()?;
+    }
+    Ok(())
 }
 
 #[derive(Debug, Serialize)]
+#[serde(rename_all = "camelCase")]
 pub struct SavedDb {
     pub id: i32,
     pub name: String,
+    /// The connection string without credentials.
     pub uri: String,
     pub created_at: i64,
+    pub has_credentials: bool,
+    #[serde(flatten)]
+    pub profile: ConnectionProfile,
+    /// `None` for connections at the top level.
+    pub folder_id: Option<i32>,
+    pub tags: Vec<String>,
+    pub favorite: bool,
+    /// Position within the folder.
+    pub sort_order: i64,
+    pub last_used_at: Option<i64>,
+}
+
+#[derive(Debug, Serialize)]
+#[serde(rename_all = "camelCase")]
+pub struct Folder {
+    pub id: i32,
+    pub name: String,
+    pub sort_order: i64,
+}
+
+/// Narrows down `search_saved_dbs`. Every field that is set has to match.
+#[derive(Debug, Default, Deserialize)]
+#[serde(rename_all = "camelCase", default)]
+pub struct SavedDbFilter {
+    /// Matched case-insensitively against the name, URI and tags.
+    pub query: Option<String>,
+    pub folder_id: Option<i32>,
+    /// Connections have to carry all of these tags.
+    pub tags: Vec<String>,
+    pub favorite: Option<bool>,
+}
+
+fn open(path: &Path) -> Result<Connection, Error> {
+    let mut con = Connection::open(path)?;
+    // Overwrites deleted content, so that replaced credentials don't linger
+    // in free pages.
+    con.pragma_update(None, "secure_delete", true)?;
+    // Off by default in SQLite, the ON DELETE clauses of the schema rely on it.
+    con.pragma_update(None, "foreign_keys", true)?;
+    migrate(&mut con, MIGRATIONS, path.to_str())?;
+    Ok(con)
+}
+
+/// How opening the store went, for the frontend to report at startup.
+#[derive(Debug, Clone, Default, Serialize)]
+#[serde(rename_all = "camelCase")]
+pub struct StoreStatus {
+    pub data_dir: Option<PathBuf>,
+    pub error: Option<String>,
+    pub warnings: Vec<String>,
+}
+
+static STATUS: OnceLock<StoreStatus> = OnceLock::new();
+
+pub fn store_status() -> StoreStatus {
+    STATUS.get().cloned().unwrap_or_else(|| StoreStatus {
+        error: Some("the local store is not open yet".to_string()),
+        ..Default::default()
+    })
+}
+
+/// Opens `saved.db` in `data_dir` and brings its schema up to date. A failure
+/// is remembered and returned by [`store_status`] and every later store call
+/// instead of panicking.
+pub fn init(data_dir: Result<DataDir, Error>) -> Result<(), Error> {
+    let mut status = StoreStatus::default();
+    let result = data_dir.and_then(|data_dir| {
+        status.warnings = data_dir.warnings;
+        let con = open(&data_dir.path.join("saved.db"));
+        status.data_dir = Some(data_dir.path);
+        con
+    });
+    let (con, err) = match result {
+        Ok(con) => (Ok(Mutex::new(con)), None),
+        Err(err) => (Err(err.to_string()), Some(err)),
+    };
+    status.error = err.as_ref().map(ToString::to_string);
+    let _ = CONN.set(con);
+    let _ = STATUS.set(status);
+    match err {
+        Some(err) => Err(err),
+        None => Ok(()),
+    }
 }
 
-pub fn ensure_tables() {
-    let con = CONN.lock().unwrap();
+const PROFILE_COLUMNS: &str = "default_database, read_preference, app_name, connect_timeout_ms,
+    server_selection_timeout_ms, tls_ca_file, tls_cert_file, tls_key_file, auth_mechanism,
+    direct_connection, color, read_only, tls, tls_allow_invalid_hostnames,
+    tls_allow_invalid_certificates, ssh_host, ssh_port, ssh_username, ssh_private_key_file,
+    ssh_known_hosts_file, ssh_password, ssh_passphrase, proxy_kind, proxy_host, proxy_port,
+    proxy_username, proxy_password";
+
+const SELECT_SAVED_DB: &str = "SELECT s.id, s.name, s.uri, s.created_at,
+    c.saved_db_id IS NOT NULL AS has_credentials,
+    s.default_database, s.read_preference, s.app_name, s.connect_timeout_ms,
+    s.server_selection_timeout_ms, s.tls_ca_file, s.tls_cert_file, s.tls_key_file,
+    s.auth_mechanism, s.direct_connection, s.color, s.read_only, s.tls,
+    s.tls_allow_invalid_hostnames, s.tls_allow_invalid_certificates, s.ssh_host, s.ssh_port,
+    s.ssh_username, s.ssh_private_key_file, s.ssh_known_hosts_file, s.proxy_kind, s.proxy_host,
+    s.proxy_port, s.proxy_username, s.folder_id,
+    (SELECT group_concat(tag, char(31)) FROM saved_db_tags t WHERE t.saved_db_id = s.id) AS tags,
+    s.favorite, s.sort_order, s.last_used_at
+    FROM saved_dbs s LEFT JOIN saved_db_credentials c ON c.saved_db_id = s.id";
+
+/// Top level connections first, then folder by folder.
+const ORDER_SAVED_DBS: &str = "ORDER BY s.folder_id IS NOT NULL,
+    (SELECT sort_order FROM folders f WHERE f.id = s.folder_id), s.folder_id, s.sort_order, s.name";
+
+/// Reads the profile columns by name. The SSH and proxy secrets are left out,
+/// see [`get_db_profile`].
+fn profile_from_row(row: &Row) -> rusqlite::Result<ConnectionProfile> {
+    let ssh_host: Option<String> = row.get("ssh_host")?;
+    let ssh_tunnel = match ssh_host {
+        Some(host) => Some(SshTunnelOptions {
+            host,
+            port: row.get("ssh_port")?,
+            username: row.get("ssh_username")?,
+            private_key_file: row.get("ssh_private_key_file")?,
+            known_hosts_file: row.get("ssh_known_hosts_file")?,
+            ..Default::default()
+        }),
+        None => None,
+    };
+    let proxy_host: Option<String> = row.get("proxy_host")?;
+    let proxy = match proxy_host {
+        Some(host) => Some(ProxyOptions {
+            kind: row.get("proxy_kind")?,
+            host,
+            port: row.get("proxy_port")?,
+            username: row.get("proxy_username")?,
+            password: None,
+        }),
+        None => None,
+    };
+    Ok(ConnectionProfile {
+        default_database: row.get("default_database")?,
+        read_preference: row.get("read_preference")?,
+        app_name: row.get("app_name")?,
+        connect_timeout_ms: row.get("connect_timeout_ms")?,
+        server_selection_timeout_ms: row.get("server_selection_timeout_ms")?,
+        tls_ca_file: row.get("tls_ca_file")?,
+        tls_cert_file: row.get("tls_cert_file")?,
+        tls_key_file: row.get("tls_key_file")?,
+        auth_mechanism: row.get("auth_mechanism")?,
+        direct_connection: row.get("direct_connection")?,
+        color: row.get("color")?,
+        read_only: row.get("read_only")?,
+        tls: row.get("tls")?,
+        tls_allow_invalid_hostnames: row.get("tls_allow_invalid_hostnames")?,
+        tls_allow_invalid_certificates: row.get("tls_allow_invalid_certificates")?,
+        ssh_tunnel,
+        proxy,
+    })
+}
+
+/// Writes the profile columns, encrypting the SSH and proxy secrets. Needs the
+/// vault to be unlocked if there are any. A secret the profile leaves out keeps
+/// the stored one, since profiles are handed out without them, unless the
+/// tunnel or proxy itself is removed.
+fn write_profile(con: &Connection, id: i32, profile: &ConnectionProfile) -> Result<(), Error> {
+    let ssh = profile.ssh_tunnel.as_ref();
+    let encrypt = |secret: Option<&String>| secret.map(|s| vault::encrypt(s)).transpose();
+    let ssh_password = encrypt(ssh.and_then(|ssh| ssh.password.as_ref()))?;
+    let ssh_passphrase = encrypt(ssh.and_then(|ssh| ssh.passphrase.as_ref()))?;
+    let proxy = profile.proxy.as_ref();
+    let proxy_password = encrypt(proxy.and_then(|proxy| proxy.password.as_ref()))?;
     con.execute(
-        "CREATE TABLE IF NOT EXISTS saved_dbs (
-            id         INTEGER PRIMARY KEY,
-            name       TEXT NOT NULL UNIQUE,
-            uri        TEXT NOT NULL,
-            created_at INTEGER NOT NULL
-        )",
-        (),
+        "UPDATE saved_dbs SET default_database = ?1, read_preference = ?2, app_name = ?3,
+            connect_timeout_ms = ?4, server_selection_timeout_ms = ?5, tls_ca_file = ?6,
+            tls_cert_file = ?7, tls_key_file = ?8, auth_mechanism = ?9, direct_connection = ?10,
+            color = ?11, read_only = ?12, tls = ?13, tls_allow_invalid_hostnames = ?14,
+            tls_allow_invalid_certificates = ?15, ssh_host = ?16, ssh_port = ?17,
+            ssh_username = ?18, ssh_private_key_file = ?19, ssh_known_hosts_file = ?20,
+            ssh_password = CASE WHEN ?16 IS NULL THEN NULL ELSE coalesce(?21, ssh_password) END,
+            ssh_passphrase = CASE WHEN ?16 IS NULL THEN NULL ELSE coalesce(?22, ssh_passphrase) END,
+            proxy_kind = ?23, proxy_host = ?24,
+            proxy_port = ?25, proxy_username = ?26,
+            proxy_password = CASE WHEN ?24 IS NULL THEN NULL ELSE coalesce(?27, proxy_password) END
+        WHERE id = ?28",
+        rusqlite::params![
+            profile.default_database,
+            profile.read_preference,
+            profile.app_name,
+            profile.connect_timeout_ms,
+            profile.server_selection_timeout_ms,
+            profile.tls_ca_file,
+            profile.tls_cert_file,
+            profile.tls_key_file,
+            profile.auth_mechanism,
+            profile.direct_connection,
+            profile.color,
+            profile.read_only,
+            profile.tls,
+            profile.tls_allow_invalid_hostnames,
+            profile.tls_allow_invalid_certificates,
+            ssh.map(|ssh| &ssh.host),
+            ssh.and_then(|ssh| ssh.port),
+            ssh.map(|ssh| &ssh.username),
+            ssh.and_then(|ssh| ssh.private_key_file.as_ref()),
+            ssh.and_then(|ssh| ssh.known_hosts_file.as_ref()),
+            ssh_password,
+            ssh_passphrase,
+            proxy.map(|proxy| proxy.kind.as_str()),
+            proxy.map(|proxy| &proxy.host),
+            proxy.and_then(|proxy| proxy.port),
+            proxy.and_then(|proxy| proxy.username.as_ref()),
+            proxy_password,
+            id,
+        ],
+    )?;
+    Ok(())
+}
+
+/// Splits the tags selected with `group_concat(tag, char(31))`, sorted.
+fn split_tags(tags: Option<String>) -> Vec<String> {
+    let mut tags = tags
+        .map(|tags| tags.split('\u{1f}').map(str::to_string).collect::<Vec<_>>())
+        .unwrap_or_default();
+    tags.sort();
+    tags
+}
+
+fn saved_db_from_row(row: &Row) -> rusqlite::Result<SavedDb> {
+    // Rows saved before the vault was set up can still hold plain text
+    // credentials, never hand those out.
+    let (uri, plaintext_credentials) = vault::split_credentials(&row.get::<_, String>("uri")?);
+    let encrypted: bool = row.get("has_credentials")?;
+    Ok(SavedDb {
+        id: row.get("id")?,
+        name: row.get("name")?,
+        uri,
+        created_at: row.get("created_at")?,
+        has_credentials: encrypted || plaintext_credentials.is_some(),
+        profile: profile_from_row(row)?,
+        folder_id: row.get("folder_id")?,
+        tags: split_tags(row.get("tags")?),
+        favorite: row.get("favorite")?,
+        sort_order: row.get("sort_order")?,
+        last_used_at: row.get("last_used_at")?,
+    })
+}
+
+/// Turns a violation of the unique `name` constraint into a conflict error.
+fn name_conflict(err: rusqlite::Error, name: &str) -> Error {
+    match &err {
+        rusqlite::Error::SqliteFailure(failure, _)
+            if failure.code == ErrorCode::ConstraintViolation =>
+        {
+            Error::Conflict(format!(
+                "a saved connection named '{}' already exists",
+                name
+            ))
+        }
+        _ => err.into(),
+    }
+}
+
+fn not_found(id: i32) -> Error {
+    Error::NotFound(format!("saved connection {} not found", id))
+}
+
+fn folder_not_found(id: i32) -> Error {
+    Error::NotFound(format!("folder {} not found", id))
+}
+
+fn folder_conflict(err: rusqlite::Error, name: &str) -> Error {
+    match &err {
+        rusqlite::Error::SqliteFailure(failure, _)
+            if failure.code == ErrorCode::ConstraintViolation =>
+        {
+            Error::Conflict(format!("a folder named '{}' already exists", name))
+        }
+        _ => err.into(),
+    }
+}
+
+/// The position after the last connection in `folder_id`.
+fn next_sort_order(con: &Connection, folder_id: Option<i32>) -> rusqlite::Result<i64> {
+    con.query_row(
+        "SELECT COALESCE(MAX(sort_order) + 1, 0) FROM saved_dbs WHERE folder_id IS ?1",
+        (folder_id,),
+        |row| row.get(0),
     )
-    .unwrap();
 }
 
-pub fn create_saved_db(name: String, uri: String) -> Result<(), Error> {
-    let con = CONN.lock().unwrap();
+fn saved_db_id(con: &Connection, name: &str) -> rusqlite::Result<Option<i32>> {
+    con.query_row("SELECT id FROM saved_dbs WHERE name = ?1", (name,), |row| {
+        row.get(0)
+    })
+    .optional()
+}
+
+pub fn is_name_taken(name: &str) -> Result<bool, Error> {
+    let con = conn()?;
+    Ok(saved_db_id(&con, name)?.is_some())
+}
+
+/// Finds a free name of the form "<name> (<label>)", "<name> (<label> 2)" and
+/// so on.
+fn free_name(con: &Connection, name: &str, label: &str) -> rusqlite::Result<String> {
+    let mut candidate = format!("{} ({})", name, label);
+    let mut n = 2;
+    while saved_db_id(con, &candidate)?.is_some() {
+        candidate = format!("{} ({} {})", name, label, n);
+        n += 1;
+    }
+    Ok(candidate)
+}
+
+fn insert_saved_db(
+    con: &Connection,
+    name: &str,
+    uri: &str,
+    profile: &ConnectionProfile,
+) -> Result<i32, Error> {
+    profile.validate(uri)?;
+    let (uri, credentials) = vault::split_credentials(uri);
+    let credentials = credentials.as_deref().map(vault::encrypt).transpose()?;
+
     con.execute(
         "INSERT INTO saved_dbs (name, uri, created_at) VALUES (?1, ?2, strftime('%s','now'))",
         (name, uri),
+    )
+    .map_err(|err| name_conflict(err, name))?;
+    let id = con.last_insert_rowid() as i32;
+    write_profile(con, id, profile)?;
+    con.execute(
+        "UPDATE saved_dbs SET sort_order = ?1 WHERE id = ?2",
+        (next_sort_order(con, None)?, id),
     )?;
-    Ok(())
+    if let Some(credentials) = credentials {
+        con.execute(
+            "INSERT INTO saved_db_credentials (saved_db_id, credentials) VALUES (?1, ?2)",
+            (id, credentials),
+        )?;
+    }
+    Ok(id)
+}
+
+pub fn create_saved_db(
+    name: String,
+    uri: String,
+    profile: &ConnectionProfile,
+) -> Result<SavedDb, Error> {
+    let mut con = conn()?;
+    let tx = con.transaction()?;
+    let id = insert_saved_db(&tx, &name, &uri, profile)?;
+    tx.commit()?;
+    drop(con);
+    get_db(id)
 }
 
 pub fn get_dbs() -> Result<Vec<SavedDb>, Error> {
-    let con = CONN.lock().unwrap();
-    let mut stmt = con.prepare("SELECT id, name, uri, created_at FROM saved_dbs")?;
+    let con = conn()?;
+    let mut stmt = con.prepare(&format!("{} {}", SELECT_SAVED_DB, ORDER_SAVED_DBS))?;
+    let rows = stmt.query_map((), saved_db_from_row)?;
+
+    Ok(rows.collect::<rusqlite::Result<_>>()?)
+}
+
+pub fn get_db(id: i32) -> Result<SavedDb, Error> {
+    let con = conn()?;
+    let mut stmt = con.prepare(&format!("{} WHERE s.id = ?1", SELECT_SAVED_DB))?;
+    let row = stmt
+        .query_row((id,), saved_db_from_row)
+        .optional()?
+        .ok_or_else(|| not_found(id))?;
+    Ok(row)
+}
+
+/// Returns the full connection string of a saved connection, decrypting its
+/// credentials. Fails with `VaultLocked` if it has credentials and the vault is locked.
+pub fn get_db_uri(id: i32) -> Result<String, Error> {
+    let con = conn()?;
+    let (uri, credentials): (String, Option<Vec<u8>>) = con
+        .query_row(
+            "SELECT s.uri, c.credentials
+            FROM saved_dbs s LEFT JOIN saved_db_credentials c ON c.saved_db_id = s.id
+            WHERE s.id = ?1",
+            (id,),
+            |row| Ok((row.get(0)?, row.get(1)?)),
+        )
+        .optional()?
+        .ok_or_else(|| not_found(id))?;
+    drop(con);
+
+    match credentials {
+        Some(credentials) => Ok(vault::join_credentials(
+            &uri,
+            &vault::decrypt(&credentials)?,
+        )),
+        None => Ok(uri),
+    }
+}
+
+/// Returns the profile of a saved connection including its SSH and proxy
+/// secrets. Fails with `VaultLocked` if it has any and the vault is locked.
+pub fn get_db_profile(id: i32) -> Result<ConnectionProfile, Error> {
+    let mut profile = get_db(id)?.profile;
+    if profile.ssh_tunnel.is_none() && profile.proxy.is_none() {
+        return Ok(profile);
+    }
+    let con = conn()?;
+    type Secret = Option<Vec<u8>>;
+    let (ssh_password, ssh_passphrase, proxy_password): (Secret, Secret, Secret) = con.query_row(
+        "SELECT ssh_password, ssh_passphrase, proxy_password FROM saved_dbs WHERE id = ?1",
+        (id,),
+        |row| Ok((row.get(0)?, row.get(1)?, row.get(2)?)),
+    )?;
+    drop(con);
+
+    let decrypt = |secret: Secret| secret.as_deref().map(vault::decrypt).transpose();
+    if let Some(ssh) = profile.ssh_tunnel.as_mut() {
+        ssh.password = decrypt(ssh_password)?;
+        ssh.passphrase = decrypt(ssh_passphrase)?;
+    }
+    if let Some(proxy) = profile.proxy.as_mut() {
+        proxy.password = decrypt(proxy_password)?;
+    }
+    Ok(profile)
+}
+
+fn write_saved_db(
+    con: &Connection,
+    id: i32,
+    name: &str,
+    uri: &str,
+    profile: Option<&ConnectionProfile>,
+    clear_credentials: bool,
+) -> Result<(), Error> {
+    if let Some(profile) = profile {
+        profile.validate(uri)?;
+    }
+    let (uri, credentials) = vault::split_credentials(uri);
+    let credentials = credentials.as_deref().map(vault::encrypt).transpose()?;
+
+    let updated = con
+        .execute(
+            "UPDATE saved_dbs SET name = ?1, uri = ?2 WHERE id = ?3",
+            (name, uri, id),
+        )
+        .map_err(|err| name_conflict(err, name))?;
+    if updated == 0 {
+        return Err(not_found(id));
+    }
+    if let Some(profile) = profile {
+        write_profile(con, id, profile)?;
+    }
+    if let Some(credentials) = credentials {
+        con.execute(
+            "INSERT OR REPLACE INTO saved_db_credentials (saved_db_id, credentials) VALUES (?1, ?2)",
+            (id, credentials),
+        )?;
+    } else if clear_credentials {
+        con.execute(
+            "DELETE FROM saved_db_credentials WHERE saved_db_id = ?1",
+            (id,),
+        )?;
+    }
+    Ok(())
+}
+
+/// Replaces the name, URI and, when given, the profile of a saved connection.
+/// The stored credentials are kept unless `uri` carries new ones, since the
+/// URIs handed out by [`get_dbs`] have them stripped, or `clear_credentials`
+/// is set.
+pub fn update_saved_db(
+    id: i32,
+    name: String,
+    uri: String,
+    profile: Option<&ConnectionProfile>,
+    clear_credentials: bool,
+) -> Result<SavedDb, Error> {
+    let mut con = conn()?;
+    let tx = con.transaction()?;
+    write_saved_db(&tx, id, &name, &uri, profile, clear_credentials)?;
+    tx.commit()?;
+    drop(con);
+    get_db(id)
+}
+
+pub fn rename_saved_db(id: i32, name: String) -> Result<SavedDb, Error> {
+    let con = conn()?;
+    let updated = con
+        .execute("UPDATE saved_dbs SET name = ?1 WHERE id = ?2", (&name, id))
+        .map_err(|err| name_conflict(err, &name))?;
+    if updated == 0 {
+        return Err(not_found(id));
+    }
+    drop(con);
+    get_db(id)
+}
+
+pub fn delete_saved_db(id: i32) -> Result<(), Error> {
+    let con = conn()?;
+    // The credentials, tags and tabs go with it, the history and scripts are
+    // kept but detached, see the ON DELETE clauses of the schema.
+    let deleted = con.execute("DELETE FROM saved_dbs WHERE id = ?1", (id,))?;
+    if deleted == 0 {
+        return Err(not_found(id));
+    }
+    Ok(())
+}
+
+/// Copies a saved connection. Without a name the copy is called
+/// "<name> (copy)", "<name> (copy 2)" and so on.
+pub fn duplicate_saved_db(id: i32, name: Option<String>) -> Result<SavedDb, Error> {
+    let original = get_db(id)?;
+
+    // The encrypted credentials are copied as is, so this works while the
+    // vault is locked.
+    let mut con = conn()?;
+    let tx = con.transaction()?;
+    let name = match name {
+        Some(name) => name,
+        None => free_name(&tx, &original.name, "copy")?,
+    };
+    tx.execute(
+        &format!(
+            "INSERT INTO saved_dbs (name, uri, created_at, folder_id, sort_order, {0})
+            SELECT ?1, uri, strftime('%s','now'), folder_id, sort_order, {0}
+            FROM saved_dbs WHERE id = ?2",
+            PROFILE_COLUMNS
+        ),
+        (&name, id),
+    )
+    .map_err(|err| name_conflict(err, &name))?;
+    let copy_id = tx.last_insert_rowid() as i32;
+    tx.execute(
+        "INSERT INTO saved_db_credentials (saved_db_id, credentials)
+        SELECT ?1, credentials FROM saved_db_credentials WHERE saved_db_id = ?2",
+        (copy_id, id),
+    )?;
+    tx.execute(
+        "INSERT INTO saved_db_tags (saved_db_id, tag)
+        SELECT ?1, tag FROM saved_db_tags WHERE saved_db_id = ?2",
+        (copy_id, id),
+    )?;
+    // Right after the original.
+    tx.execute(
+        "UPDATE saved_dbs SET sort_order = sort_order + 1
+        WHERE folder_id IS ?1 AND sort_order > ?2",
+        (original.folder_id, original.sort_order),
+    )?;
+    tx.execute(
+        "UPDATE saved_dbs SET sort_order = ?1 WHERE id = ?2",
+        (original.sort_order + 1, copy_id),
+    )?;
+    tx.commit()?;
+    drop(con);
+    get_db(copy_id)
+}
+
+pub fn set_favorite(id: i32, favorite: bool) -> Result<SavedDb, Error> {
+    let con = conn()?;
+    let updated = con.execute(
+        "UPDATE saved_dbs SET favorite = ?1 WHERE id = ?2",
+        (favorite, id),
+    )?;
+    if updated == 0 {
+        return Err(not_found(id));
+    }
+    drop(con);
+    get_db(id)
+}
+
+fn write_tags(con: &Connection, id: i32, tags: &[String]) -> Result<(), Error> {
+    let exists = con
+        .query_row("SELECT 1 FROM saved_dbs WHERE id = ?1", (id,), |_| Ok(()))
+        .optional()?
+        .is_some();
+    if !exists {
+        return Err(not_found(id));
+    }
+    con.execute("DELETE FROM saved_db_tags WHERE saved_db_id = ?1", (id,))?;
+    for tag in tags
+        .iter()
+        .map(|tag| tag.trim())
+        .filter(|tag| !tag.is_empty())
+    {
+        con.execute(
+            "INSERT OR IGNORE INTO saved_db_tags (saved_db_id, tag) VALUES (?1, ?2)",
+            (id, tag),
+        )?;
+    }
+    Ok(())
+}
+
+/// Replaces the tags of a saved connection. Tags are trimmed and empty ones
+/// dropped.
+pub fn set_tags(id: i32, tags: Vec<String>) -> Result<SavedDb, Error> {
+    let mut con = conn()?;
+    let tx = con.transaction()?;
+    write_tags(&tx, id, &tags)?;
+    tx.commit()?;
+    drop(con);
+    get_db(id)
+}
+
+/// Records that a saved connection was just connected to.
+pub fn touch_saved_db(id: i32) -> Result<(), Error> {
+    let con = conn()?;
+    con.execute(
+        "UPDATE saved_dbs SET last_used_at = strftime('%s','now') WHERE id = ?1",
+        (id,),
+    )?;
+    Ok(())
+}
+
+/// A `LIKE` pattern matching `text` anywhere, to be used with `ESCAPE '\\'`.
+fn like_pattern(text: &str) -> String {
+    let escaped = text
+        .replace('\\', "\\\\")
+        .replace('%', "\\%")
+        .replace('_', "\\_");
+    format!("%{}%", escaped)
+}
+
+pub fn search_saved_dbs(filter: &SavedDbFilter) -> Result<Vec<SavedDb>, Error> {
+    let mut clauses = Vec::new();
+    let mut params: Vec<rusqlite::types::Value> = Vec::new();
+
+    if let Some(query) = filter
+        .query
+        .as_deref()
+        .map(str::trim)
+        .filter(|q| !q.is_empty())
+    {
+        params.push(like_pattern(query).into());
+        let n = params.len();
+        clauses.push(format!(
+            "(s.name LIKE ?{0} ESCAPE '\\' OR s.uri LIKE ?{0} ESCAPE '\\'
+            OR EXISTS (SELECT 1 FROM saved_db_tags t
+                WHERE t.saved_db_id = s.id AND t.tag LIKE ?{0} ESCAPE '\\'))",
+            n
+        ));
+    }
+    if let Some(folder_id) = filter.folder_id {
+        params.push(folder_id.into());
+        clauses.push(format!("s.folder_id = ?{}", params.len()));
+    }
+    for tag in &filter.tags {
+        params.push(tag.clone().into());
+        clauses.push(format!(
+            "EXISTS (SELECT 1 FROM saved_db_tags t WHERE t.saved_db_id = s.id AND t.tag = ?{})",
+            params.len()
+        ));
+    }
+    if let Some(favorite) = filter.favorite {
+        params.push(favorite.into());
+        clauses.push(format!("s.favorite = ?{}", params.len()));
+    }
+
+    let where_clause = match clauses.is_empty() {
+        true => String::new(),
+        false => format!("WHERE {}", clauses.join(" AND ")),
+    };
+    let con = conn()?;
+    let mut stmt = con.prepare(&format!(
+        "{} {} {}",
+        SELECT_SAVED_DB, where_clause, ORDER_SAVED_DBS
+    ))?;
+    let rows = stmt.query_map(rusqlite::params_from_iter(params), saved_db_from_row)?;
+
+    Ok(rows.collect::<rusqlite::Result<_>>()?)
+}
+
+/// Moves a saved connection into `folder_id` (the top level when `None`) at
+/// `position`, or at the end of the folder without one.
+pub fn move_saved_db(
+    id: i32,
+    folder_id: Option<i32>,
+    position: Option<usize>,
+) -> Result<SavedDb, Error> {
+    let mut con = conn()?;
+    let tx = con.transaction()?;
+    place_saved_db(&tx, id, folder_id, position)?;
+    tx.commit()?;
+    drop(con);
+    get_db(id)
+}
+
+fn place_saved_db(
+    con: &Connection,
+    id: i32,
+    folder_id: Option<i32>,
+    position: Option<usize>,
+) -> Result<(), Error> {
+    let exists = con
+        .query_row("SELECT 1 FROM saved_dbs WHERE id = ?1", (id,), |_| Ok(()))
+        .optional()?
+        .is_some();
+    if !exists {
+        return Err(not_found(id));
+    }
+    if let Some(folder_id) = folder_id {
+        let exists = con
+            .query_row("SELECT 1 FROM folders WHERE id = ?1", (folder_id,), |_| {
+                Ok(())
+            })
+            .optional()?
+            .is_some();
+        if !exists {
+            return Err(folder_not_found(folder_id));
+        }
+    }
+
+    let mut ids = {
+        let mut stmt = con.prepare(
+            "SELECT id FROM saved_dbs WHERE folder_id IS ?1 AND id != ?2
+            ORDER BY sort_order, name",
+        )?;
+        let rows = stmt.query_map((folder_id, id), |row| row.get::<_, i32>(0))?;
+        rows.collect::<rusqlite::Result<Vec<_>>>()?
+    };
+    ids.insert(position.unwrap_or(ids.len()).min(ids.len()), id);
+    for (idx, id) in ids.iter().enumerate() {
+        con.execute(
+            "UPDATE saved_dbs SET folder_id = ?1, sort_order = ?2 WHERE id = ?3",
+            (folder_id, idx as i64, id),
+        )?;
+    }
+    Ok(())
+}
+
+/// Sets the order of the connections in `folder_id` to the order of `ids`,
+/// which must all be in that folder.
+pub fn reorder_saved_dbs(folder_id: Option<i32>, ids: &[i32]) -> Result<(), Error> {
+    let mut con = conn()?;
+    let tx = con.transaction()?;
+    for (idx, id) in ids.iter().enumerate() {
+        let updated = tx.execute(
+            "UPDATE saved_dbs SET sort_order = ?1 WHERE id = ?2 AND folder_id IS ?3",
+            (idx as i64, id, folder_id),
+        )?;
+        if updated == 0 {
+            return Err(not_found(*id));
+        }
+    }
+    tx.commit()?;
+    Ok(())
+}
+
+pub fn get_folders() -> Result<Vec<Folder>, Error> {
+    let con = conn()?;
+    let mut stmt =
+        con.prepare("SELECT id, name, sort_order FROM folders ORDER BY sort_order, name")?;
     let rows = stmt.query_map((), |row| {
-        Ok(SavedDb {
+        Ok(Folder {
             id: row.get(0)?,
             name: row.get(1)?,
-            uri: row.get(2)?,
-            created_at: row.get(3)?,
+            sort_order: row.get(2)?,
         })
     })?;
 
-    Ok(rows.map(|row| row.unwrap()).collect())
+    Ok(rows.collect::<rusqlite::Result<_>>()?)
 }
 
-pub fn get_db(id: i32) -> Result<SavedDb, Error> {
-    let con = CONN.lock().unwrap();
-    let mut stmt = con.prepare("SELECT id, name, uri, created_at FROM saved_dbs where id = ?1")?;
-    let row = stmt.query_row((id,), |row| {
-        Ok(SavedDb {
+pub fn create_folder(name: String) -> Result<Folder, Error> {
+    let con = conn()?;
+    insert_folder(&con, name)
+}
+
+fn insert_folder(con: &Connection, name: String) -> Result<Folder, Error> {
+    con.execute(
+        "INSERT INTO folders (name, sort_order)
+        SELECT ?1, COALESCE(MAX(sort_order) + 1, 0) FROM folders",
+        (&name,),
+    )
+    .map_err(|err| folder_conflict(err, &name))?;
+    let id = con.last_insert_rowid() as i32;
+    let sort_order = con.query_row(
+        "SELECT sort_order FROM folders WHERE id = ?1",
+        (id,),
+        |row| row.get(0),
+    )?;
+    Ok(Folder {
+        id,
+        name,
+        sort_order,
+    })
+}
+
+/// Returns the id of the folder called `name`, creating it if needed.
+fn ensure_folder(con: &Connection, name: &str) -> Result<i32, Error> {
+    let id = con
+        .query_row("SELECT id FROM folders WHERE name = ?1", (name,), |row| {
+            row.get(0)
+        })
+        .optional()?;
+    match id {
+        Some(id) => Ok(id),
+        None => Ok(insert_folder(con, name.to_string())?.id),
+    }
+}
+
+/// A connection read from an import file.
+pub struct ImportedDb {
+    pub name: String,
+    pub uri: String,
+    pub profile: ConnectionProfile,
+    pub folder: Option<String>,
+    pub tags: Vec<String>,
+    pub favorite: bool,
+}
+
+/// What [`import_saved_dbs`] does with a connection whose name is taken.
+#[derive(Deserialize, Default, Clone, Copy, PartialEq)]
+#[serde(rename_all = "camelCase")]
+pub enum OnConflict {
+    /// Keeps the existing connection.
+    #[default]
+    Skip,
+    /// Replaces the existing connection, keeping the stored secrets the
+    /// import leaves out.
+    Overwrite,
+    /// Imports under a new name, "<name> (imported)".
+    Rename,
+}
+
+#[derive(Default)]
+pub struct ImportCounts {
+    pub created: usize,
+    pub updated: usize,
+    pub skipped: usize,
+}
+
+/// Saves imported connections and their folders in a single transaction, so
+/// a failure leaves the store as it was.
+pub fn import_saved_dbs(
+    folders: &[String],
+    connections: Vec<ImportedDb>,
+    on_conflict: OnConflict,
+) -> Result<ImportCounts, Error> {
+    let mut con = conn()?;
+    let tx = con.transaction()?;
+    let counts = import_into(&tx, folders, connections, on_conflict)?;
+    tx.commit()?;
+    Ok(counts)
+}
+
+fn import_into(
+    con: &Connection,
+    folders: &[String],
+    connections: Vec<ImportedDb>,
+    on_conflict: OnConflict,
+) -> Result<ImportCounts, Error> {
+    let mut counts = ImportCounts::default();
+    for folder in folders {
+        ensure_folder(con, folder)?;
+    }
+    for connection in connections {
+        let id = match (saved_db_id(con, &connection.name)?, on_conflict) {
+            (Some(_), OnConflict::Skip) => {
+                counts.skipped += 1;
+                continue;
+            }
+            (Some(id), OnConflict::Overwrite) => {
+                counts.updated += 1;
+                write_saved_db(
+                    con,
+                    id,
+                    &connection.name,
+                    &connection.uri,
+                    Some(&connection.profile),
+                    false,
+                )?;
+                id
+            }
+            (Some(_), OnConflict::Rename) => {
+                counts.created += 1;
+                let name = free_name(con, &connection.name, "imported")?;
+                insert_saved_db(con, &name, &connection.uri, &connection.profile)?
+            }
+            (None, _) => {
+                counts.created += 1;
+                insert_saved_db(con, &connection.name, &connection.uri, &connection.profile)?
+            }
+        };
+
+        let folder_id = connection
+            .folder
+            .as_deref()
+            .map(|folder| ensure_folder(con, folder))
+            .transpose()?;
+        place_saved_db(con, id, folder_id, None)?;
+        write_tags(con, id, &connection.tags)?;
+        con.execute(
+            "UPDATE saved_dbs SET favorite = ?1 WHERE id = ?2",
+            (connection.favorite, id),
+        )?;
+    }
+    Ok(counts)
+}
+
+pub fn rename_folder(id: i32, name: String) -> Result<(), Error> {
+    let con = conn()?;
+    let updated = con
+        .execute("UPDATE folders SET name = ?1 WHERE id = ?2", (&name, id))
+        .map_err(|err| folder_conflict(err, &name))?;
+    if updated == 0 {
+        return Err(folder_not_found(id));
+    }
+    Ok(())
+}
+
+/// Deletes a folder, moving its connections to the end of the top level.
+pub fn delete_folder(id: i32) -> Result<(), Error> {
+    let mut con = conn()?;
+    let tx = con.transaction()?;
+    tx.execute(
+        "UPDATE saved_dbs SET sort_order = sort_order + ?1, folder_id = NULL WHERE folder_id = ?2",
+        (next_sort_order(&tx, None)?, id),
+    )?;
+    let deleted = tx.execute("DELETE FROM folders WHERE id = ?1", (id,))?;
+    if deleted == 0 {
+        return Err(folder_not_found(id));
+    }
+    tx.commit()?;
+    Ok(())
+}
+
+pub fn reorder_folders(ids: &[i32]) -> Result<(), Error> {
+    let mut con = conn()?;
+    let tx = con.transaction()?;
+    for (idx, id) in ids.iter().enumerate() {
+        let updated = tx.execute(
+            "UPDATE folders SET sort_order = ?1 WHERE id = ?2",
+            (idx as i64, id),
+        )?;
+        if updated == 0 {
+            return Err(folder_not_found(*id));
+        }
+    }
+    tx.commit()?;
+    Ok(())
+}
+
+#[derive(Debug, Serialize)]
+#[serde(rename_all = "camelCase")]
+pub struct HistoryEntry {
+    pub id: i64,
+    /// `None` for connections that weren't saved, or have been deleted since.
+    pub saved_db_id: Option<i32>,
+    pub host: String,
+    pub database: String,
+    pub script: String,
+    pub duration_ms: i64,
+    /// `None` when the script failed.
+    pub result_count: Option<i64>,
+    pub error: Option<String>,
+    pub executed_at: i64,
+}
+
+pub struct NewHistoryEntry {
+    pub saved_db_id: Option<i32>,
+    pub host: String,
+    pub database: String,
+    pub script: String,
+    pub duration_ms: i64,
+    pub result_count: Option<i64>,
+    pub error: Option<String>,
+}
+
+#[derive(Debug, Default, Deserialize)]
+#[serde(rename_all = "camelCase", default)]
+pub struct HistoryFilter {
+    pub saved_db_id: Option<i32>,
+    pub host: Option<String>,
+    pub database: Option<String>,
+    /// Matched case-insensitively against the script text.
+    pub query: Option<String>,
+    /// Only failed runs with `true`, only successful ones with `false`.
+    pub failed: Option<bool>,
+    /// Unix timestamps, in seconds.
+    pub since: Option<i64>,
+    pub until: Option<i64>,
+}
+
+#[derive(Debug, Deserialize)]
+#[serde(rename_all = "camelCase", default)]
+pub struct Paging {
+    pub offset: u32,
+    pub limit: u32,
+}
+
+impl Default for Paging {
+    fn default() -> Self {
+        Self {
+            offset: 0,
+            limit: 50,
+        }
+    }
+}
+
+#[derive(Debug, Serialize)]
+#[serde(rename_all = "camelCase")]
+pub struct HistoryPage {
+    pub entries: Vec<HistoryEntry>,
+    /// Number of entries matching the filter, across all pages.
+    pub total: i64,
+}
+
+/// How long script history is kept. Older entries are pruned whenever a new
+/// one is recorded.
+#[derive(Debug, Serialize, Deserialize)]
+#[serde(rename_all = "camelCase", default)]
+pub struct HistoryRetention {
+    pub max_age_days: Option<u32>,
+    pub max_entries: Option<u32>,
+}
+
+impl Default for HistoryRetention {
+    fn default() -> Self {
+        Self {
+            max_age_days: Some(90),
+            max_entries: Some(10_000),
+        }
+    }
+}
+
+const MAX_PAGE_SIZE: u32 = 500;
+const HISTORY_RETENTION: &str = "history_retention";
+const SCRIPTS_DIR: &str = "scripts_dir";
+
+fn get_setting<T: serde::de::DeserializeOwned>(
+    con: &Connection,
+    key: &str,
+) -> Result<Option<T>, Error> {
+    let value: Option<String> = con
+        .query_row("SELECT value FROM settings WHERE key = ?1", (key,), |row| {
+            row.get(0)
+        })
+        .optional()?;
+    value
+        .map(|value| serde_json::from_str(&value))
+        .transpose()
+        .map_err(|err| Error::Storage(format!("invalid setting '{}': {}", key, err)))
+}
+
+fn set_setting<T: Serialize>(con: &Connection, key: &str, value: &T) -> Result<(), Error> {
+    let value = serde_json::to_string(value)
+        .map_err(|err| Error::Storage(format!("invalid setting '{}': {}", key, err)))?;
+    con.execute(
+        "INSERT INTO settings (key, value) VALUES (?1, ?2)
+        ON CONFLICT (key) DO UPDATE SET value = excluded.value",
+        (key, value),
+    )?;
+    Ok(())
+}
+
+fn prune_history(con: &Connection, retention: &HistoryRetention) -> Result<(), Error> {
+    if let Some(days) = retention.max_age_days {
+        con.execute(
+            "DELETE FROM history WHERE executed_at < strftime('%s','now') - ?1 * 86400",
+            (days,),
+        )?;
+    }
+    if let Some(max_entries) = retention.max_entries {
+        con.execute(
+            "DELETE FROM history WHERE id NOT IN
+                (SELECT id FROM history ORDER BY executed_at DESC, id DESC LIMIT ?1)",
+            (max_entries,),
+        )?;
+    }
+    Ok(())
+}
+
+/// Adds a run to the history. A run on a saved connection that was deleted in
+/// the meantime is kept without it.
+pub fn record_history(entry: NewHistoryEntry) -> Result<(), Error> {
+    let con = conn()?;
+    con.execute(
+        "INSERT INTO history (saved_db_id, host, database, script, duration_ms, result_count,
+            error, executed_at)
+        VALUES ((SELECT id FROM saved_dbs WHERE id = ?1), ?2, ?3, ?4, ?5, ?6, ?7,
+            strftime('%s','now'))",
+        rusqlite::params![
+            entry.saved_db_id,
+            entry.host,
+            entry.database,
+            entry.script,
+            entry.duration_ms,
+            entry.result_count,
+            entry.error,
+        ],
+    )?;
+    let retention = get_setting(&con, HISTORY_RETENTION)?.unwrap_or_default();
+    prune_history(&con, &retention)
+}
+
+/// Returns a page of history, most recent first.
+pub fn get_history(filter: &HistoryFilter, paging: &Paging) -> Result<HistoryPage, Error> {
+    let mut clauses = match filter.failed {
+        Some(true) => vec!["error IS NOT NULL".to_string()],
+        Some(false) => vec!["error IS NULL".to_string()],
+        None => Vec::new(),
+    };
+    let mut params: Vec<rusqlite::types::Value> = Vec::new();
+    let mut push = |clause: &str, value: rusqlite::types::Value| {
+        params.push(value);
+        clauses.push(clause.replace('?', &format!("?{}", params.len())));
+    };
+
+    if let Some(id) = filter.saved_db_id {
+        push("saved_db_id = ?", id.into());
+    }
+    if let Some(host) = &filter.host {
+        push("host = ?", host.clone().into());
+    }
+    if let Some(database) = &filter.database {
+        push("database = ?", database.clone().into());
+    }
+    if let Some(query) = filter.query.as_deref().filter(|q| !q.is_empty()) {
+        push("script LIKE ? ESCAPE '\\'", like_pattern(query).into());
+    }
+    if let Some(since) = filter.since {
+        push("executed_at >= ?", since.into());
+    }
+    if let Some(until) = filter.until {
+        push("executed_at < ?", until.into());
+    }
+
+    let where_clause = match clauses.is_empty() {
+        true => String::new(),
+        false => format!("WHERE {}", clauses.join(" AND ")),
+    };
+    let con = conn()?;
+    let total = con.query_row(
+        &format!("SELECT COUNT(*) FROM history {}", where_clause),
+        rusqlite::params_from_iter(&params),
+        |row| row.get(0),
+    )?;
+
+    let limit = paging.limit.min(MAX_PAGE_SIZE);
+    params.push(limit.into());
+    params.push(paging.offset.into());
+    let mut stmt = con.prepare(&format!(
+        "SELECT id, saved_db_id, host, database, script, duration_ms, result_count, error,
+            executed_at
+        FROM history {} ORDER BY executed_at DESC, id DESC LIMIT ?{} OFFSET ?{}",
+        where_clause,
+        params.len() - 1,
+        params.len()
+    ))?;
+    let rows = stmt.query_map(rusqlite::params_from_iter(&params), |row| {
+        Ok(HistoryEntry {
             id: row.get(0)?,
-            name: row.get(1)?,
-            uri: row.get(2)?,
-            created_at: row.get(3)?,
+            saved_db_id: row.get(1)?,
+            host: row.get(2)?,
+            database: row.get(3)?,
+            script: row.get(4)?,
+            duration_ms: row.get(5)?,
+            result_count: row.get(6)?,
+            error: row.get(7)?,
+            executed_at: row.get(8)?,
         })
     })?;
-    Ok(row)
+
+    Ok(HistoryPage {
+        entries: rows.collect::<rusqlite::Result<_>>()?,
+        total,
+    })
+}
+
+/// Deletes the given history entries and returns how many there were.
+pub fn delete_history(ids: &[i64]) -> Result<usize, Error> {
+    let mut con = conn()?;
+    let tx = con.transaction()?;
+    let mut deleted = 0;
+    for id in ids {
+        deleted += tx.execute("DELETE FROM history WHERE id = ?1", (id,))?;
+    }
+    tx.commit()?;
+    Ok(deleted)
+}
+
+pub fn clear_history() -> Result<usize, Error> {
+    let con = conn()?;
+    Ok(con.execute("DELETE FROM history", ())?)
+}
+
+pub fn get_history_retention() -> Result<HistoryRetention, Error> {
+    let con = conn()?;
+    Ok(get_setting(&con, HISTORY_RETENTION)?.unwrap_or_default())
+}
+
+/// Stores the retention settings and prunes the history right away.
+pub fn set_history_retention(retention: &HistoryRetention) -> Result<(), Error> {
+    let con = conn()?;
+    set_setting(&con, HISTORY_RETENTION, retention)?;
+    prune_history(&con, retention)
+}
+
+#[derive(Debug, Serialize)]
+#[serde(rename_all = "camelCase")]
+pub struct SavedScript {
+    pub id: i32,
+    pub name: String,
+    pub description: Option<String>,
+    /// A `/` separated path, `None` for scripts at the top level.
+    pub folder: Option<String>,
+    pub tags: Vec<String>,
+    pub body: String,
+    /// The saved connection the script is meant for, if any.
+    pub saved_db_id: Option<i32>,
+    /// The database the script runs against, whichever one is selected
+    /// otherwise.
+    pub database: Option<String>,
+    pub created_at: i64,
+    pub updated_at: i64,
+}
+
+/// The editable fields of a [`SavedScript`].
+#[derive(Debug, Default, Deserialize)]
+#[serde(rename_all = "camelCase", default)]
+pub struct SavedScriptInput {
+    pub name: String,
+    pub description: Option<String>,
+    pub folder: Option<String>,
+    pub tags: Vec<String>,
+    pub body: String,
+    pub saved_db_id: Option<i32>,
+    pub database: Option<String>,
+}
+
+/// Narrows down `search_saved_scripts`. Every field that is set has to match.
+#[derive(Debug, Default, Deserialize)]
+#[serde(rename_all = "camelCase", default)]
+pub struct SavedScriptFilter {
+    /// Matched case-insensitively against the name, description, body and
+    /// tags.
+    pub query: Option<String>,
+    /// Also matches the scripts in subfolders.
+    pub folder: Option<String>,
+    /// Scripts have to carry all of these tags.
+    pub tags: Vec<String>,
+    pub saved_db_id: Option<i32>,
+}
+
+const SELECT_SAVED_SCRIPT: &str = "SELECT s.id, s.name, s.description, s.folder,
+    (SELECT group_concat(tag, char(31)) FROM saved_script_tags t WHERE t.script_id = s.id),
+    s.body, s.saved_db_id, s.database, s.created_at, s.updated_at
+    FROM saved_scripts s";
+
+fn saved_script_from_row(row: &Row) -> rusqlite::Result<SavedScript> {
+    Ok(SavedScript {
+        id: row.get(0)?,
+        name: row.get(1)?,
+        description: row.get(2)?,
+        folder: row.get(3)?,
+        tags: split_tags(row.get(4)?),
+        body: row.get(5)?,
+        saved_db_id: row.get(6)?,
+        database: row.get(7)?,
+        created_at: row.get(8)?,
+        updated_at: row.get(9)?,
+    })
+}
+
+fn script_not_found(id: i32) -> Error {
+    Error::NotFound(format!("saved script {} not found", id))
+}
+
+fn script_conflict(err: rusqlite::Error, name: &str) -> Error {
+    match &err {
+        rusqlite::Error::SqliteFailure(failure, _)
+            if failure.code == ErrorCode::ConstraintViolation =>
+        {
+            Error::Conflict(format!("a saved script named '{}' already exists", name))
+        }
+        _ => err.into(),
+    }
+}
+
+/// Trims the folder path and drops empty segments, so that "a//b/" and "a/b"
+/// are the same folder.
+fn normalize_folder(folder: Option<&str>) -> Option<String> {
+    let segments = folder?
+        .split('/')
+        .map(str::trim)
+        .filter(|segment| !segment.is_empty())
+        .collect::<Vec<_>>();
+    match segments.is_empty() {
+        true => None,
+        false => Some(segments.join("/")),
+    }
+}
+
+fn validate_script(con: &Connection, script: &SavedScriptInput) -> Result<(), Error> {
+    if script.name.trim().is_empty() {
+        return Err(Error::InvalidArgument(
+            "a saved script needs a name".to_string(),
+        ));
+    }
+    if let Some(id) = script.saved_db_id {
+        let exists = con
+            .query_row("SELECT 1 FROM saved_dbs WHERE id = ?1", (id,), |_| Ok(()))
+            .optional()?
+            .is_some();
+        if !exists {
+            return Err(not_found(id));
+        }
+    }
+    Ok(())
+}
+
+/// Writes every field of `script` but the name to the row `id`.
+fn write_script(con: &Connection, id: i32, script: &SavedScriptInput) -> Result<(), Error> {
+    con.execute(
+        "UPDATE saved_scripts SET description = ?1, folder = ?2, body = ?3, saved_db_id = ?4,
+            database = ?5, updated_at = strftime('%s','now')
+        WHERE id = ?6",
+        rusqlite::params![
+            script
+                .description
+                .as_deref()
+                .filter(|d| !d.trim().is_empty()),
+            normalize_folder(script.folder.as_deref()),
+            script.body,
+            script.saved_db_id,
+            script.database.as_deref().filter(|db| !db.is_empty()),
+            id,
+        ],
+    )?;
+    con.execute("DELETE FROM saved_script_tags WHERE script_id = ?1", (id,))?;
+    for tag in script
+        .tags
+        .iter()
+        .map(|tag| tag.trim())
+        .filter(|tag| !tag.is_empty())
+    {
+        con.execute(
+            "INSERT OR IGNORE INTO saved_script_tags (script_id, tag) VALUES (?1, ?2)",
+            (id, tag),
+        )?;
+    }
+    Ok(())
+}
+
+pub fn create_saved_script(script: &SavedScriptInput) -> Result<SavedScript, Error> {
+    let mut con = conn()?;
+    validate_script(&con, script)?;
+    let name = script.name.trim();
+    let tx = con.transaction()?;
+    tx.execute(
+        "INSERT INTO saved_scripts (name, body, created_at, updated_at)
+        VALUES (?1, '', strftime('%s','now'), strftime('%s','now'))",
+        (name,),
+    )
+    .map_err(|err| script_conflict(err, name))?;
+    let id = tx.last_insert_rowid() as i32;
+    write_script(&tx, id, script)?;
+    tx.commit()?;
+    drop(con);
+    get_saved_script(id)
+}
+
+pub fn get_saved_scripts() -> Result<Vec<SavedScript>, Error> {
+    search_saved_scripts(&SavedScriptFilter::default())
+}
+
+pub fn get_saved_script(id: i32) -> Result<SavedScript, Error> {
+    let con = conn()?;
+    con.query_row(
+        &format!("{} WHERE s.id = ?1", SELECT_SAVED_SCRIPT),
+        (id,),
+        saved_script_from_row,
+    )
+    .optional()?
+    .ok_or_else(|| script_not_found(id))
+}
+
+/// Finds a saved script by its exact name.
+pub fn find_saved_script(name: &str) -> Result<Option<SavedScript>, Error> {
+    let con = conn()?;
+    let script = con
+        .query_row(
+            &format!("{} WHERE s.name = ?1", SELECT_SAVED_SCRIPT),
+            (name,),
+            saved_script_from_row,
+        )
+        .optional()?;
+    Ok(script)
+}
+
+pub fn update_saved_script(id: i32, script: &SavedScriptInput) -> Result<SavedScript, Error> {
+    let mut con = conn()?;
+    validate_script(&con, script)?;
+    let name = script.name.trim();
+    let tx = con.transaction()?;
+    let updated = tx
+        .execute(
+            "UPDATE saved_scripts SET name = ?1 WHERE id = ?2",
+            (name, id),
+        )
+        .map_err(|err| script_conflict(err, name))?;
+    if updated == 0 {
+        return Err(script_not_found(id));
+    }
+    write_script(&tx, id, script)?;
+    tx.commit()?;
+    drop(con);
+    get_saved_script(id)
+}
+
+pub fn delete_saved_script(id: i32) -> Result<(), Error> {
+    let con = conn()?;
+    // Takes the tags along and detaches the tabs showing the script.
+    let deleted = con.execute("DELETE FROM saved_scripts WHERE id = ?1", (id,))?;
+    if deleted == 0 {
+        return Err(script_not_found(id));
+    }
+    Ok(())
+}
+
+/// Returns the matching scripts, ordered by folder and name.
+pub fn search_saved_scripts(filter: &SavedScriptFilter) -> Result<Vec<SavedScript>, Error> {
+    let mut clauses = Vec::new();
+    let mut params: Vec<rusqlite::types::Value> = Vec::new();
+
+    if let Some(query) = filter
+        .query
+        .as_deref()
+        .map(str::trim)
+        .filter(|q| !q.is_empty())
+    {
+        params.push(like_pattern(query).into());
+        let n = params.len();
+        clauses.push(format!(
+            "(s.name LIKE ?{0} ESCAPE '\\' OR s.description LIKE ?{0} ESCAPE '\\'
+            OR s.body LIKE ?{0} ESCAPE '\\'
+            OR EXISTS (SELECT 1 FROM saved_script_tags t
+                WHERE t.script_id = s.id AND t.tag LIKE ?{0} ESCAPE '\\'))",
+            n
+        ));
+    }
+    if let Some(folder) = normalize_folder(filter.folder.as_deref()) {
+        params.push(folder.into());
+        clauses.push(format!(
+            "(s.folder = ?{0} OR substr(s.folder, 1, length(?{0}) + 1) = ?{0} || '/')",
+            params.len()
+        ));
+    }
+    for tag in &filter.tags {
+        params.push(tag.clone().into());
+        clauses.push(format!(
+            "EXISTS (SELECT 1 FROM saved_script_tags t WHERE t.script_id = s.id AND t.tag = ?{})",
+            params.len()
+        ));
+    }
+    if let Some(saved_db_id) = filter.saved_db_id {
+        params.push(saved_db_id.into());
+        clauses.push(format!("s.saved_db_id = ?{}", params.len()));
+    }
+
+    let where_clause = match clauses.is_empty() {
+        true => String::new(),
+        false => format!("WHERE {}", clauses.join(" AND ")),
+    };
+    let con = conn()?;
+    let mut stmt = con.prepare(&format!(
+        "{} {} ORDER BY s.folder IS NOT NULL, s.folder, s.name",
+        SELECT_SAVED_SCRIPT, where_clause
+    ))?;
+    let rows = stmt.query_map(rusqlite::params_from_iter(params), saved_script_from_row)?;
+
+    Ok(rows.collect::<rusqlite::Result<_>>()?)
+}
+
+/// The editor tabs that were open when the app was last closed.
+#[derive(Debug, Default, Serialize, Deserialize)]
+#[serde(rename_all = "camelCase", default)]
+pub struct Workspace {
+    /// In the order they are shown.
+    pub tabs: Vec<WorkspaceTab>,
+}
+
+#[derive(Debug, Default, Serialize, Deserialize)]
+#[serde(rename_all = "camelCase", default)]
+pub struct WorkspaceTab {
+    /// The saved connection the tab belongs to. Tabs of connections that
+    /// weren't saved have none and can't be reconnected on their own.
+    pub saved_db_id: Option<i32>,
+    /// The saved script the tab is editing, if any. Dropped when the script
+    /// was deleted in the meantime.
+    pub saved_script_id: Option<i32>,
+    pub title: Option<String>,
+    pub script: String,
+    pub database: Option<String>,
+    pub cursor: Option<Cursor>,
+    pub last_result: Option<ResultSummary>,
+    /// The tab of its connection that was in front.
+    pub active: bool,
+}
+
+#[derive(Debug, Clone, Copy, Serialize, Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub struct Cursor {
+    pub line: u32,
+    pub column: u32,
+}
+
+/// What the last run of a tab's script returned, without the result itself.
+#[derive(Debug, Default, Serialize, Deserialize)]
+#[serde(rename_all = "camelCase", default)]
+pub struct ResultSummary {
+    pub result_count: Option<i64>,
+    pub duration_ms: Option<i64>,
+    pub error: Option<String>,
+    pub executed_at: Option<i64>,
+}
+
+/// Replaces the stored tabs of the saved connection `saved_db_id`, or of the
+/// connections that weren't saved when `None`, with the tabs of `workspace`.
+/// The tabs of other connections are kept.
+pub fn save_workspace(saved_db_id: Option<i32>, workspace: &Workspace) -> Result<(), Error> {
+    let mut con = conn()?;
+    let tx = con.transaction()?;
+    write_workspace(&tx, saved_db_id, workspace)?;
+    tx.commit()?;
+    Ok(())
+}
+
+fn write_workspace(
+    con: &Connection,
+    saved_db_id: Option<i32>,
+    workspace: &Workspace,
+) -> Result<(), Error> {
+    if workspace
+        .tabs
+        .iter()
+        .any(|tab| tab.saved_db_id != saved_db_id)
+    {
+        return Err(Error::InvalidArgument(
+            "every tab has to belong to the connection being saved".to_string(),
+        ));
+    }
+    if let Some(id) = saved_db_id {
+        let exists = con
+            .query_row("SELECT 1 FROM saved_dbs WHERE id = ?1", (id,), |_| Ok(()))
+            .optional()?
+            .is_some();
+        if !exists {
+            return Err(not_found(id));
+        }
+    }
+    con.execute(
+        "DELETE FROM workspace WHERE saved_db_id IS ?1",
+        (saved_db_id,),
+    )?;
+    for (position, tab) in workspace.tabs.iter().enumerate() {
+        let summary = tab.last_result.as_ref();
+        con.execute(
+            "INSERT INTO workspace (position, saved_db_id, saved_script_id, title, script,
+                database, cursor_line, cursor_column, result_count, duration_ms, error,
+                executed_at, active)
+            VALUES (?1, ?2, (SELECT id FROM saved_scripts WHERE id = ?3), ?4, ?5, ?6, ?7, ?8, ?9, ?10, ?11, ?12, ?13)",
+            rusqlite::params![
+                position,
+                tab.saved_db_id,
+                tab.saved_script_id,
+                tab.title,
+                tab.script,
+                tab.database,
+                tab.cursor.map(|cursor| cursor.line),
+                tab.cursor.map(|cursor| cursor.column),
+                summary.and_then(|summary| summary.result_count),
+                summary.and_then(|summary| summary.duration_ms),
+                summary.and_then(|summary| summary.error.as_ref()),
+                summary.and_then(|summary| summary.executed_at),
+                tab.active,
+            ],
+        )?;
+    }
+    Ok(())
+}
+
+/// Returns the stored tabs of the saved connection `saved_db_id`, or of the
+/// connections that weren't saved when `None`, in the order they were saved
+/// in.
+pub fn load_workspace(saved_db_id: Option<i32>) -> Result<Workspace, Error> {
+    let con = conn()?;
+    read_workspace(&con, saved_db_id)
+}
+
+fn read_workspace(con: &Connection, saved_db_id: Option<i32>) -> Result<Workspace, Error> {
+    let mut stmt = con.prepare(
+        "SELECT saved_db_id, saved_script_id, title, script, database, cursor_line,
+            cursor_column, result_count, duration_ms, error, executed_at, active
+        FROM workspace WHERE saved_db_id IS ?1 ORDER BY position",
+    )?;
+    let rows = stmt.query_map((saved_db_id,), |row| {
+        let cursor = match (row.get(5)?, row.get(6)?) {
+            (Some(line), Some(column)) => Some(Cursor { line, column }),
+            _ => None,
+        };
+        let summary = ResultSummary {
+            result_count: row.get(7)?,
+            duration_ms: row.get(8)?,
+            error: row.get(9)?,
+            executed_at: row.get(10)?,
+        };
+        let ran = summary.result_count.is_some()
+            || summary.duration_ms.is_some()
+            || summary.error.is_some()
+            || summary.executed_at.is_some();
+        Ok(WorkspaceTab {
+            saved_db_id: row.get(0)?,
+            saved_script_id: row.get(1)?,
+            title: row.get(2)?,
+            script: row.get(3)?,
+            database: row.get(4)?,
+            cursor,
+            last_result: ran.then_some(summary),
+            active: row.get(11)?,
+        })
+    })?;
+
+    Ok(Workspace {
+        tabs: rows.collect::<rusqlite::Result<_>>()?,
+    })
+}
+
+/// The directory `load()` and `import` resolve relative paths against.
+pub fn get_scripts_dir() -> Result<Option<PathBuf>, Error> {
+    let con = conn()?;
+    get_setting(&con, SCRIPTS_DIR)
+}
+
+pub fn set_scripts_dir(dir: Option<&Path>) -> Result<(), Error> {
+    if let Some(dir) = dir {
+        if !dir.is_dir() {
+            return Err(Error::InvalidArgument(format!(
+                "{} is not a directory",
+                dir.display()
+            )));
+        }
+    }
+    let con = conn()?;
+    match dir {
+        Some(dir) => set_setting(&con, SCRIPTS_DIR, &dir),
+        None => {
+            con.execute("DELETE FROM settings WHERE key = ?1", (SCRIPTS_DIR,))?;
+            Ok(())
+        }
+    }
+}
+
+pub fn get_vault() -> Result<Option<(Vec<u8>, Vec<u8>)>, Error> {
+    let con = conn()?;
+    let vault = con
+        .query_row("SELECT salt, verifier FROM vault WHERE id = 1", (), |row| {
+            Ok((row.get(0)?, row.get(1)?))
+        })
+        .optional()?;
+    Ok(vault)
+}
+
+pub fn create_vault(salt: &[u8], verifier: &[u8]) -> Result<(), Error> {
+    let con = conn()?;
+    con.execute(
+        "INSERT INTO vault (id, salt, verifier) VALUES (1, ?1, ?2)",
+        (salt, verifier),
+    )?;
+    Ok(())
+}
+
+/// Moves credentials still embedded in saved URIs into the vault.
+pub fn encrypt_plaintext_credentials() -> Result<(), Error> {
+    let mut con = conn()?;
+    let tx = con.transaction()?;
+    let rows = {
+        let mut stmt = tx.prepare("SELECT id, uri FROM saved_dbs")?;
+        let rows = stmt.query_map((), |row| {
+            Ok((row.get::<_, i32>(0)?, row.get::<_, String>(1)?))
+        })?;
+        rows.collect::<rusqlite::Result<Vec<_>>>()?
+    };
+    let mut moved = false;
+    for (id, uri) in rows {
+        let (uri, Some(credentials)) = vault::split_credentials(&uri) else {
+            continue;
+        };
+        tx.execute("UPDATE saved_dbs SET uri = ?1 WHERE id = ?2", (uri, id))?;
+        tx.execute(
+            "INSERT OR REPLACE INTO saved_db_credentials (saved_db_id, credentials) VALUES (?1, ?2)",
+            (id, vault::encrypt(&credentials)?),
+        )?;
+        moved = true;
+    }
+    tx.commit()?;
+    if moved {
+        // The plain text URIs may sit in pages freed before secure_delete
+        // was on, rewrite the file without them.
+        con.execute("VACUUM", ())?;
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// The schema as created before migrations existed.
+    fn baseline() -> Connection {
+        let con = Connection::open_in_memory().unwrap();
+        con.execute(
+            "CREATE TABLE IF NOT EXISTS saved_dbs (
+                id         INTEGER PRIMARY KEY,
+                name       TEXT NOT NULL UNIQUE,
+                uri        TEXT NOT NULL,
+                created_at INTEGER NOT NULL
+            )",
+            (),
+        )
+        .unwrap();
+        con.execute(
+            "INSERT INTO saved_dbs (name, uri, created_at) VALUES ('local', 'mongodb://localhost', 1)",
+            (),
+        )
+        .unwrap();
+        con
+    }
+
+    fn user_version(con: &Connection) -> usize {
+        con.query_row("PRAGMA user_version", (), |row| row.get(0))
+            .unwrap()
+    }
+
+    fn table_exists(con: &Connection, name: &str) -> bool {
+        con.query_row(
+            "SELECT EXISTS (SELECT 1 FROM sqlite_master WHERE type = 'table' AND name = ?1)",
+            (name,),
+            |row| row.get(0),
+        )
+        .unwrap()
+    }
+
+    #[test]
+    fn migrates_baseline_schema() {
+        let mut con = baseline();
+        migrate(&mut con, MIGRATIONS, None).unwrap();
+
+        assert_eq!(user_version(&con), MIGRATIONS.len());
+        assert!(table_exists(&con, "vault"));
+        assert!(table_exists(&con, "saved_db_credentials"));
+        let name: String = con
+            .query_row("SELECT name FROM saved_dbs WHERE id = 1", (), |row| {
+                row.get(0)
+            })
+            .unwrap();
+        assert_eq!(name, "local");
+        let read_only: bool = con
+            .query_row("SELECT read_only FROM saved_dbs WHERE id = 1", (), |row| {
+                row.get(0)
+            })
+            .unwrap();
+        assert!(!read_only);
+    }
+
+    #[test]
+    fn migrates_fresh_database() {
+        let mut con = Connection::open_in_memory().unwrap();
+        migrate(&mut con, MIGRATIONS, None).unwrap();
+
+        assert_eq!(user_version(&con), MIGRATIONS.len());
+        assert!(table_exists(&con, "saved_dbs"));
+        assert!(table_exists(&con, "history"));
+        assert!(table_exists(&con, "settings"));
+        assert!(table_exists(&con, "saved_scripts"));
+        assert!(table_exists(&con, "workspace"));
+    }
+
+    #[test]
+    fn migrating_twice_is_a_no_op() {
+        let mut con = baseline();
+        migrate(&mut con, MIGRATIONS, None).unwrap();
+        migrate(&mut con, MIGRATIONS, None).unwrap();
+
+        assert_eq!(user_version(&con), MIGRATIONS.len());
+    }
+
+    #[test]
+    fn failed_migration_is_rolled_back() {
+        let mut con = baseline();
+        let migrations = [
+            MIGRATIONS[0],
+            "CREATE TABLE half_done (id INTEGER); SELECT * FROM missing_table;",
+        ];
+
+        assert!(migrate(&mut con, &migrations, None).is_err());
+        assert_eq!(user_version(&con), 1);
+        assert!(!table_exists(&con, "half_done"));
+    }
+
+    #[test]
+    fn removes_the_backup_on_the_next_open() {
+        let dir = std::env::temp_dir().join(format!("mongoui-test-{}", uuid::Uuid::new_v4()));
+        std::fs::create_dir_all(&dir).unwrap();
+        let path = dir.join("saved.db");
+        let path = path.to_str().unwrap();
+        let backup = format!("{}.bak", path);
+
+        let mut con = Connection::open(path).unwrap();
+        con.execute_batch(MIGRATIONS[0]).unwrap();
+        migrate(&mut con, MIGRATIONS, Some(path)).unwrap();
+        assert_eq!(user_version(&Connection::open(&backup).unwrap()), 0);
+
+        migrate(&mut con, MIGRATIONS, Some(path)).unwrap();
+        assert!(!Path::new(&backup).exists());
+
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn keeps_the_backup_when_upgrading_fails() {
+        let dir = std::env::temp_dir().join(format!("mongoui-test-{}", uuid::Uuid::new_v4()));
+        std::fs::create_dir_all(&dir).unwrap();
+        let path = dir.join("saved.db");
+        let path = path.to_str().unwrap();
+
+        let mut con = Connection::open(path).unwrap();
+        con.execute_batch(MIGRATIONS[0]).unwrap();
+        let migrations = [
+            MIGRATIONS[0],
+            "CREATE TABLE vault (id INTEGER); SELECT nope FROM nowhere;",
+        ];
+        assert!(migrate(&mut con, &migrations, Some(path)).is_err());
+
+        let backup = Connection::open(format!("{}.bak", path)).unwrap();
+        assert_eq!(user_version(&backup), 0);
+        assert!(table_exists(&backup, "saved_dbs"));
+        assert!(!table_exists(&backup, "vault"));
+
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn deleting_a_connection_follows_the_schema() {
+        let dir = std::env::temp_dir().join(format!("mongoui-test-{}", uuid::Uuid::new_v4()));
+        std::fs::create_dir_all(&dir).unwrap();
+        let con = open(&dir.join("saved.db")).unwrap();
+        con.execute_batch(
+            "INSERT INTO saved_dbs (name, uri, created_at) VALUES ('a', 'mongodb://a', 1);
+            INSERT INTO saved_db_credentials (saved_db_id, credentials) VALUES (1, x'01');
+            INSERT INTO saved_db_tags (saved_db_id, tag) VALUES (1, 'eu');
+            INSERT INTO history (saved_db_id, host, database, script, duration_ms, executed_at)
+                VALUES (1, 'a', 'test', 'x', 1, 1);
+            INSERT INTO saved_scripts (name, body, saved_db_id, created_at, updated_at)
+                VALUES ('s', 'x', 1, 1, 1);
+            INSERT INTO saved_script_tags (script_id, tag) VALUES (1, 'eu');
+            INSERT INTO workspace (position, saved_db_id, script) VALUES (0, 1, 'x');
+            INSERT INTO workspace (position, saved_script_id, script) VALUES (0, 1, 'x');",
+        )
+        .unwrap();
+        let count = |sql: &str| -> i64 { con.query_row(sql, (), |row| row.get(0)).unwrap() };
+
+        con.execute("DELETE FROM saved_dbs WHERE id = 1", ())
+            .unwrap();
+        assert_eq!(count("SELECT count(*) FROM saved_db_credentials"), 0);
+        assert_eq!(count("SELECT count(*) FROM saved_db_tags"), 0);
+        assert_eq!(count("SELECT count(*) FROM workspace"), 1);
+        assert_eq!(
+            count("SELECT count(*) FROM history WHERE saved_db_id IS NULL"),
+            1
+        );
+        assert_eq!(
+            count("SELECT count(*) FROM saved_scripts WHERE saved_db_id IS NULL"),
+            1
+        );
+
+        con.execute("DELETE FROM saved_scripts WHERE id = 1", ())
+            .unwrap();
+        assert_eq!(count("SELECT count(*) FROM saved_script_tags"), 0);
+        assert_eq!(
+            count("SELECT count(*) FROM workspace WHERE saved_script_id IS NULL"),
+            1
+        );
+
+        let tab = WorkspaceTab {
+            saved_db_id: Some(1),
+            ..Default::default()
+        };
+        assert!(write_workspace(&con, Some(1), &Workspace { tabs: vec![tab] }).is_err());
+
+        drop(con);
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn writing_a_profile_without_secrets_keeps_the_stored_ones() {
+        let mut con = Connection::open_in_memory().unwrap();
+        migrate(&mut con, MIGRATIONS, None).unwrap();
+        con.execute(
+            "INSERT INTO saved_dbs (name, uri, created_at, ssh_password, proxy_password)
+            VALUES ('a', 'mongodb://h', 1, x'01', x'02')",
+            (),
+        )
+        .unwrap();
+        let secret = |con: &Connection, column: &str| -> Option<Vec<u8>> {
+            con.query_row(&format!("SELECT {} FROM saved_dbs", column), (), |row| {
+                row.get(0)
+            })
+            .unwrap()
+        };
+
+        let mut profile = ConnectionProfile {
+            ssh_tunnel: Some(SshTunnelOptions {
+                host: "bastion".to_string(),
+                username: "deploy".to_string(),
+                ..Default::default()
+            }),
+            ..Default::default()
+        };
+        write_profile(&con, 1, &profile).unwrap();
+        assert_eq!(secret(&con, "ssh_password"), Some(vec![1]));
+
+        profile.ssh_tunnel = None;
+        write_profile(&con, 1, &profile).unwrap();
+        assert_eq!(secret(&con, "ssh_password"), None);
+        assert_eq!(secret(&con, "proxy_password"), None);
+
+        con.execute("UPDATE saved_dbs SET proxy_password = x'02'", ())
+            .unwrap();
+        profile.proxy = Some(ProxyOptions {
+            host: "proxy".to_string(),
+            username: Some("user".to_string()),
+            ..Default::default()
+        });
+        write_profile(&con, 1, &profile).unwrap();
+        assert_eq!(secret(&con, "proxy_password"), Some(vec![2]));
+        let kind: String = con
+            .query_row("SELECT proxy_kind FROM saved_dbs", (), |row| row.get(0))
+            .unwrap();
+        assert_eq!(kind, "socks5");
+    }
+
+    #[test]
+    fn importing_over_a_connection_keeps_its_secrets() {
+        let mut con = Connection::open_in_memory().unwrap();
+        migrate(&mut con, MIGRATIONS, None).unwrap();
+        con.execute_batch(
+            "INSERT INTO saved_dbs (name, uri, created_at, ssh_host, ssh_username, ssh_password)
+            VALUES ('app', 'mongodb://old', 1, 'bastion', 'deploy', x'01');
+            INSERT INTO saved_db_credentials (saved_db_id, credentials) VALUES (1, x'02');",
+        )
+        .unwrap();
+
+        let imported = |name: &str| ImportedDb {
+            name: name.to_string(),
+            uri: "mongodb://new".to_string(),
+            profile: ConnectionProfile {
+                ssh_tunnel: Some(SshTunnelOptions {
+                    host: "bastion".to_string(),
+                    username: "deploy".to_string(),
+                    ..Default::default()
+                }),
+                ..Default::default()
+            },
+            folder: Some("Production".to_string()),
+            tags: vec!["eu".to_string()],
+            favorite: true,
+        };
+        let counts = import_into(
+            &con,
+            &[],
+            vec![imported("app"), imported("other")],
+            OnConflict::Overwrite,
+        )
+        .unwrap();
+        assert_eq!((counts.created, counts.updated, counts.skipped), (1, 1, 0));
+
+        let (uri, ssh_password, credentials, favorite): (String, Vec<u8>, Vec<u8>, bool) = con
+            .query_row(
+                "SELECT s.uri, s.ssh_password, c.credentials, s.favorite
+                FROM saved_dbs s JOIN saved_db_credentials c ON c.saved_db_id = s.id
+                WHERE s.name = 'app'",
+                (),
+                |row| Ok((row.get(0)?, row.get(1)?, row.get(2)?, row.get(3)?)),
+            )
+            .unwrap();
+        assert_eq!(uri, "mongodb://new");
+        assert_eq!(ssh_password, [1]);
+        assert_eq!(credentials, [2]);
+        assert!(favorite);
+        let folders: i64 = con
+            .query_row("SELECT count(*) FROM folders", (), |row| row.get(0))
+            .unwrap();
+        assert_eq!(folders, 1);
+
+        let counts = import_into(&con, &[], vec![imported("app")], OnConflict::Rename).unwrap();
+        assert_eq!(counts.created, 1);
+        assert_eq!(saved_db_id(&con, "app (imported)").unwrap(), Some(3));
+    }
+
+    #[test]
+    fn saving_a_workspace_keeps_the_other_connections_tabs() {
+        let mut con = Connection::open_in_memory().unwrap();
+        migrate(&mut con, MIGRATIONS, None).unwrap();
+        con.execute_batch(
+            "INSERT INTO saved_dbs (name, uri, created_at) VALUES ('a', 'mongodb://a', 1);
+            INSERT INTO saved_dbs (name, uri, created_at) VALUES ('b', 'mongodb://b', 1);",
+        )
+        .unwrap();
+        let tab = |saved_db_id: Option<i32>, script: &str| WorkspaceTab {
+            saved_db_id,
+            script: script.to_string(),
+            ..Default::default()
+        };
+        let scripts = |con: &Connection, saved_db_id: Option<i32>| {
+            read_workspace(con, saved_db_id)
+                .unwrap()
+                .tabs
+                .into_iter()
+                .map(|tab| tab.script)
+                .collect::<Vec<_>>()
+        };
+
+        let workspace = |tabs| Workspace { tabs };
+        write_workspace(
+            &con,
+            Some(1),
+            &workspace(vec![tab(Some(1), "a1"), tab(Some(1), "a2")]),
+        )
+        .unwrap();
+        write_workspace(&con, Some(2), &workspace(vec![tab(Some(2), "b1")])).unwrap();
+        write_workspace(&con, None, &workspace(vec![tab(None, "c1")])).unwrap();
+        assert_eq!(scripts(&con, Some(1)), ["a1", "a2"]);
+        assert_eq!(scripts(&con, None), ["c1"]);
+
+        write_workspace(&con, Some(1), &workspace(vec![tab(Some(1), "a3")])).unwrap();
+        assert_eq!(scripts(&con, Some(1)), ["a3"]);
+        assert_eq!(scripts(&con, Some(2)), ["b1"]);
+        assert!(write_workspace(&con, Some(2), &workspace(vec![tab(Some(1), "a4")])).is_err());
+    }
+
+    #[test]
+    fn a_failed_run_without_timings_is_kept() {
+        let mut con = Connection::open_in_memory().unwrap();
+        migrate(&mut con, MIGRATIONS, None).unwrap();
+        let tabs = vec![
+            WorkspaceTab {
+                script: "x".to_string(),
+                last_result: Some(ResultSummary {
+                    error: Some("boom".to_string()),
+                    ..Default::default()
+                }),
+                ..Default::default()
+            },
+            WorkspaceTab {
+                script: "y".to_string(),
+                last_result: Some(ResultSummary::default()),
+                ..Default::default()
+            },
+        ];
+        write_workspace(&con, None, &Workspace { tabs }).unwrap();
+
+        let tabs = read_workspace(&con, None).unwrap().tabs;
+        let error = tabs[0]
+            .last_result
+            .as_ref()
+            .and_then(|r| r.error.as_deref());
+        assert_eq!(error, Some("boom"));
+        assert!(tabs[1].last_result.is_none());
+    }
 }
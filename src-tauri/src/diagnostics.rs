@@ -0,0 +1,550 @@
+use std::{
+    io::BufReader,
+    net::{SocketAddr, TcpStream, ToSocketAddrs},
+    sync::Arc,
+    time::{Duration, Instant, SystemTime},
+};
+
+use mongodb::{
+    bson::{doc, Document},
+    options::{ClientOptions, ConnectionString, HostInfo, ServerAddress, Tls, TlsOptions},
+};
+use serde::{Deserialize, Serialize};
+use serde_json::{json, Value};
+
+use crate::client::{self, ConnectionProfile};
+
+#[derive(Deserialize, Default)]
+#[serde(rename_all = "camelCase", default)]
+pub struct TestConnectionOptions {
+    /// Timeout applied to each network step, defaults to 5 seconds.
+    pub timeout_ms: Option<u64>,
+}
+
+#[derive(Serialize)]
+#[serde(rename_all = "camelCase")]
+enum StepStatus {
+    Ok,
+    Failed,
+    Skipped,
+}
+
+#[derive(Serialize)]
+#[serde(rename_all = "camelCase")]
+pub struct DiagnosticStep {
+    name: &'static str,
+    status: StepStatus,
+    duration_ms: u64,
+    message: Option<String>,
+    details: Option<Value>,
+}
+
+#[derive(Serialize)]
+#[serde(rename_all = "camelCase")]
+pub struct ServerInfo {
+    version: Option<String>,
+    topology_type: &'static str,
+    set_name: Option<String>,
+    min_wire_version: Option<i32>,
+    max_wire_version: Option<i32>,
+}
+
+#[derive(Serialize, Default)]
+#[serde(rename_all = "camelCase")]
+pub struct ConnectionDiagnostics {
+    ok: bool,
+    steps: Vec<DiagnosticStep>,
+    server: Option<ServerInfo>,
+}
+
+type StepResult<T> = Result<(T, Option<Value>), String>;
+
+impl ConnectionDiagnostics {
+    /// Runs a step and records its outcome. Returns `None` when it failed.
+    fn step<T>(&mut self, name: &'static str, f: impl FnOnce() -> StepResult<T>) -> Option<T> {
+        let started = Instant::now();
+        let result = f();
+        let duration_ms = started.elapsed().as_millis() as u64;
+        match result {
+            Ok((value, details)) => {
+                self.steps.push(DiagnosticStep {
+                    name,
+                    status: StepStatus::Ok,
+                    duration_ms,
+                    message: None,
+                    details,
+                });
+                Some(value)
+            }
+            Err(message) => {
+                self.steps.push(DiagnosticStep {
+                    name,
+                    status: StepStatus::Failed,
+                    duration_ms,
+                    message: Some(message),
+                    details: None,
+                });
+                None
+            }
+        }
+    }
+
+    fn skip(&mut self, name: &'static str, reason: &str) {
+        self.steps.push(DiagnosticStep {
+            name,
+            status: StepStatus::Skipped,
+            duration_ms: 0,
+            message: Some(reason.to_string()),
+            details: None,
+        });
+    }
+}
+
+/// Checks a connection string with `profile` applied step by step, going
+/// through its SSH tunnel or proxy the way [`client::connect`] does, and
+/// stopping at the first failure. Nothing is registered in `CLIENTS`.
+pub fn test_connection(
+    uri: &str,
+    profile: &ConnectionProfile,
+    options: TestConnectionOptions,
+) -> ConnectionDiagnostics {
+    let timeout = Duration::from_millis(options.timeout_ms.unwrap_or(5000));
+    let mut diagnostics = ConnectionDiagnostics::default();
+    diagnostics.ok = run(uri, profile, timeout, &mut diagnostics).is_some();
+    diagnostics
+}
+
+fn run(
+    uri: &str,
+    profile: &ConnectionProfile,
+    timeout: Duration,
+    diagnostics: &mut ConnectionDiagnostics,
+) -> Option<()> {
+    diagnostics.step("parseUri", || {
+        let conn_str = ConnectionString::parse(uri).map_err(|err| err.kind.to_string())?;
+        profile.validate(uri).map_err(|err| err.to_string())?;
+        let details = match &conn_str.host_info {
+            HostInfo::HostIdentifiers(hosts) => json!({
+                "srv": false,
+                "hosts": hosts.iter().map(ToString::to_string).collect::<Vec<_>>(),
+            }),
+            HostInfo::DnsRecord(record) => json!({ "srv": true, "record": record }),
+            _ => json!({}),
+        };
+        Ok(((), Some(details)))
+    })?;
+
+    // Kept open until every step is done.
+    let mut tunnelled = None;
+    let _tunnel = match (&profile.ssh_tunnel, &profile.proxy) {
+        (None, None) => {
+            diagnostics.skip("openTunnel", "no SSH tunnel or proxy is set on the profile");
+            None
+        }
+        (ssh, _) => {
+            let (options, tunnel) = diagnostics.step("openTunnel", || {
+                // Tunnels don't take `mongodb+srv` URIs, so nothing is looked up.
+                let mut options = parse_options(uri, profile)?;
+                let tunnel = client::open_tunnel(profile, &mut options)
+                    .map_err(|err| err.to_string())?
+                    .ok_or("no tunnel was opened")?;
+                let details = json!({
+                    "kind": if ssh.is_some() { "ssh" } else { "proxy" },
+                    "localPort": tunnel.local_port(),
+                });
+                Ok(((options, tunnel), Some(details)))
+            })?;
+            tunnelled = Some(options);
+            Some(tunnel)
+        }
+    };
+
+    let (client_options, hosts) = diagnostics.step("resolveDns", || {
+        // For `mongodb+srv` URIs this performs the SRV and TXT lookups.
+        let client_options = match tunnelled {
+            Some(options) => options,
+            None => parse_options(uri, profile)?,
+        };
+
+        let mut hosts = Vec::new();
+        let mut details = Vec::new();
+        for address in &client_options.hosts {
+            let ServerAddress::Tcp { host, port } = address else {
+                continue;
+            };
+            let port = port.unwrap_or(27017);
+            match (host.as_str(), port).to_socket_addrs() {
+                Ok(addrs) => {
+                    let addrs = addrs.collect::<Vec<_>>();
+                    details.push(json!({
+                        "host": address.to_string(),
+                        "addresses": addrs.iter().map(ToString::to_string).collect::<Vec<_>>(),
+                    }));
+                    hosts.push((host.clone(), addrs));
+                }
+                Err(err) => {
+                    details.push(json!({ "host": address.to_string(), "error": err.to_string() }))
+                }
+            }
+        }
+        if hosts.is_empty() {
+            return Err(format!(
+                "none of the hosts could be resolved: {}",
+                Value::from(details)
+            ));
+        }
+        Ok(((client_options, hosts), Some(Value::from(details))))
+    })?;
+
+    let (host, stream) = diagnostics.step("connectTcp", || {
+        let mut failures = Vec::new();
+        for (host, addrs) in &hosts {
+            for addr in addrs {
+                match TcpStream::connect_timeout(addr, timeout) {
+                    Ok(stream) => {
+                        let details = json!({ "host": host, "address": addr.to_string() });
+                        return Ok(((host.clone(), stream), Some(details)));
+                    }
+                    Err(err) => failures.push(format!("{}: {}", addr, err)),
+                }
+            }
+        }
+        Err(format!("no host is reachable ({})", failures.join(", ")))
+    })?;
+
+    match &client_options.tls {
+        Some(Tls::Enabled(tls)) => {
+            diagnostics.step("tlsHandshake", || {
+                let identity = client::client_identity(profile).map_err(|err| err.to_string())?;
+                tls_handshake(&host, stream, tls, identity, timeout)
+            })?;
+        }
+        _ => diagnostics.skip("tlsHandshake", "TLS is not enabled for this connection"),
+    }
+
+    let mut client_options = client_options;
+    client_options.server_selection_timeout = Some(timeout);
+    client_options.connect_timeout = Some(timeout);
+
+    // Selecting a server doesn't need credentials, so it is checked on its
+    // own first and an authentication failure can't hide an unreachable
+    // deployment, or the other way round.
+    let credential = client_options.credential.take();
+    let anonymous = diagnostics.step("selectServer", || ping(profile, client_options.clone()))?;
+
+    let client = match credential {
+        Some(credential) => {
+            anonymous.shutdown_immediate();
+            client_options.credential = Some(credential);
+            diagnostics.step("authenticate", || ping(profile, client_options))?
+        }
+        None => {
+            diagnostics.skip("authenticate", "no credentials in the connection string");
+            anonymous
+        }
+    };
+
+    let server = diagnostics.step("hello", || {
+        let admin = client.database("admin");
+        let hello = admin
+            .run_command(doc! { "hello": 1 }, None)
+            .map_err(|err| err.kind.to_string())?;
+        // buildInfo may be forbidden for restricted users, the version is optional.
+        let version = admin
+            .run_command(doc! { "buildInfo": 1 }, None)
+            .ok()
+            .and_then(|info| info.get_str("version").ok().map(str::to_string));
+        let server = server_info(&hello, version);
+        Ok((
+            server,
+            Some(mongodb::bson::Bson::from(hello).into_relaxed_extjson()),
+        ))
+    });
+    client.shutdown_immediate();
+
+    diagnostics.server = Some(server?);
+    Some(())
+}
+
+/// The options of `uri` with `profile` applied, looking up SRV records for
+/// `mongodb+srv` URIs.
+fn parse_options(uri: &str, profile: &ConnectionProfile) -> Result<ClientOptions, String> {
+    let conn_str = ConnectionString::parse(uri).map_err(|err| err.kind.to_string())?;
+    let mut options = ClientOptions::parse_connection_string_sync(conn_str)
+        .map_err(|err| err.kind.to_string())?;
+    profile.apply(&mut options).map_err(|err| err.to_string())?;
+    Ok(options)
+}
+
+/// Builds a client like [`client::connect`] does and pings the deployment
+/// with it.
+fn ping(profile: &ConnectionProfile, options: ClientOptions) -> StepResult<mongodb::sync::Client> {
+    let client = client::build_client(profile, options).map_err(|err| err.to_string())?;
+    match client
+        .database("admin")
+        .run_command(doc! { "ping": 1 }, None)
+    {
+        Ok(_) => Ok((client, None)),
+        Err(err) => {
+            client.shutdown_immediate();
+            Err(err.kind.to_string())
+        }
+    }
+}
+
+fn server_info(hello: &Document, version: Option<String>) -> ServerInfo {
+    let set_name = hello.get_str("setName").ok().map(str::to_string);
+    let topology_type = if hello.get_str("msg") == Ok("isdbgrid") {
+        "Sharded"
+    } else if set_name.is_some() {
+        "ReplicaSet"
+    } else {
+        "Single"
+    };
+    ServerInfo {
+        version,
+        topology_type,
+        set_name,
+        min_wire_version: hello.get_i32("minWireVersion").ok(),
+        max_wire_version: hello.get_i32("maxWireVersion").ok(),
+    }
+}
+
+struct AcceptAnyCertificate;
+
+impl rustls::client::ServerCertVerifier for AcceptAnyCertificate {
+    fn verify_server_cert(
+        &self,
+        _end_entity: &rustls::Certificate,
+        _intermediates: &[rustls::Certificate],
+        _server_name: &rustls::ServerName,
+        _scts: &mut dyn Iterator<Item = &[u8]>,
+        _ocsp_response: &[u8],
+        _now: SystemTime,
+    ) -> Result<rustls::client::ServerCertVerified, rustls::Error> {
+        Ok(rustls::client::ServerCertVerified::assertion())
+    }
+}
+
+fn tls_handshake(
+    host: &str,
+    mut stream: TcpStream,
+    tls: &TlsOptions,
+    identity: Option<(Vec<rustls::Certificate>, rustls::PrivateKey)>,
+    timeout: Duration,
+) -> StepResult<()> {
+    let mut roots = rustls::RootCertStore::empty();
+    roots.add_trust_anchors(webpki_roots::TLS_SERVER_ROOTS.iter().map(|ta| {
+        rustls::OwnedTrustAnchor::from_subject_spki_name_constraints(
+            ta.subject,
+            ta.spki,
+            ta.name_constraints,
+        )
+    }));
+    if let Some(path) = &tls.ca_file_path {
+        let file = std::fs::File::open(path)
+            .map_err(|err| format!("failed to open CA file {}: {}", path.display(), err))?;
+        let certs = rustls_pemfile::certs(&mut BufReader::new(file))
+            .map_err(|err| format!("failed to read CA file {}: {}", path.display(), err))?;
+        roots.add_parsable_certificates(&certs);
+    }
+
+    let builder = rustls::ClientConfig::builder()
+        .with_safe_defaults()
+        .with_root_certificates(roots);
+    let mut config = match identity {
+        Some((certs, key)) => builder
+            .with_client_auth_cert(certs, key)
+            .map_err(|err| format!("invalid client certificate: {}", err))?,
+        None => builder.with_no_client_auth(),
+    };
+    if tls.allow_invalid_certificates == Some(true) {
+        config
+            .dangerous()
+            .set_certificate_verifier(Arc::new(AcceptAnyCertificate));
+    }
+
+    let server_name = rustls::ServerName::try_from(host).map_err(|err| err.to_string())?;
+    let mut conn = rustls::ClientConnection::new(Arc::new(config), server_name)
+        .map_err(|err| err.to_string())?;
+    stream
+        .set_read_timeout(Some(timeout))
+        .and_then(|_| stream.set_write_timeout(Some(timeout)))
+        .map_err(|err| err.to_string())?;
+    while conn.is_handshaking() {
+        conn.complete_io(&mut stream)
+            .map_err(|err| err.to_string())?;
+    }
+
+    let peer = stream
+        .peer_addr()
+        .map(|addr: SocketAddr| addr.to_string())
+        .ok();
+    let details = json!({
+        "peer": peer,
+        "protocolVersion": conn.protocol_version().map(|v| format!("{:?}", v)),
+        "cipherSuite": conn.negotiated_cipher_suite().map(|s| format!("{:?}", s.suite())),
+    });
+    Ok(((), Some(details)))
+}
+
+#[cfg(test)]
+mod tests {
+    use std::net::TcpListener;
+
+    use crate::proxy::{ProxyKind, ProxyOptions};
+
+    use super::*;
+
+    fn check(uri: &str, profile: &ConnectionProfile) -> ConnectionDiagnostics {
+        let options = TestConnectionOptions {
+            timeout_ms: Some(500),
+        };
+        test_connection(uri, profile, options)
+    }
+
+    fn steps(diagnostics: &ConnectionDiagnostics) -> Vec<(&str, &str)> {
+        diagnostics
+            .steps
+            .iter()
+            .map(|step| {
+                let status = match step.status {
+                    StepStatus::Ok => "ok",
+                    StepStatus::Failed => "failed",
+                    StepStatus::Skipped => "skipped",
+                };
+                (step.name, status)
+            })
+            .collect()
+    }
+
+    /// A port nothing listens on.
+    fn closed_port() -> u16 {
+        TcpListener::bind("127.0.0.1:0")
+            .unwrap()
+            .local_addr()
+            .unwrap()
+            .port()
+    }
+
+    /// A port that accepts connections and closes them right away.
+    fn closing_port() -> u16 {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let port = listener.local_addr().unwrap().port();
+        std::thread::spawn(move || {
+            for stream in listener.incoming() {
+                drop(stream);
+            }
+        });
+        port
+    }
+
+    #[test]
+    fn reports_the_hosts_of_the_uri() {
+        let diagnostics = check(
+            &format!("mongodb://127.0.0.1:{},localhost:1", closed_port()),
+            &ConnectionProfile::default(),
+        );
+        assert_eq!(diagnostics.steps[0].name, "parseUri");
+        let details = diagnostics.steps[0].details.as_ref().unwrap();
+        assert_eq!(details["srv"], false);
+        assert_eq!(details["hosts"].as_array().unwrap().len(), 2);
+    }
+
+    #[test]
+    fn an_invalid_uri_fails_to_parse() {
+        let diagnostics = check("localhost:27017", &ConnectionProfile::default());
+        assert!(!diagnostics.ok);
+        assert_eq!(steps(&diagnostics), [("parseUri", "failed")]);
+    }
+
+    #[test]
+    fn an_invalid_profile_fails_to_parse() {
+        let profile = ConnectionProfile {
+            tls: Some(false),
+            tls_ca_file: Some("ca.pem".to_string()),
+            ..Default::default()
+        };
+        let diagnostics = check("mongodb://localhost", &profile);
+        assert_eq!(steps(&diagnostics), [("parseUri", "failed")]);
+        let message = diagnostics.steps[0].message.as_deref().unwrap();
+        assert!(message.contains("TLS is turned off"), "{}", message);
+    }
+
+    #[test]
+    fn an_unknown_host_fails_to_resolve() {
+        let diagnostics = check("mongodb://mongoui.invalid", &ConnectionProfile::default());
+        assert_eq!(
+            steps(&diagnostics),
+            [
+                ("parseUri", "ok"),
+                ("openTunnel", "skipped"),
+                ("resolveDns", "failed")
+            ]
+        );
+    }
+
+    #[test]
+    fn a_closed_port_fails_to_connect() {
+        let diagnostics = check(
+            &format!("mongodb://127.0.0.1:{}", closed_port()),
+            &ConnectionProfile::default(),
+        );
+        assert_eq!(
+            steps(&diagnostics),
+            [
+                ("parseUri", "ok"),
+                ("openTunnel", "skipped"),
+                ("resolveDns", "ok"),
+                ("connectTcp", "failed")
+            ]
+        );
+    }
+
+    #[test]
+    fn a_server_without_tls_fails_the_handshake() {
+        let diagnostics = check(
+            &format!("mongodb://127.0.0.1:{}/?tls=true", closing_port()),
+            &ConnectionProfile::default(),
+        );
+        assert_eq!(
+            steps(&diagnostics)[3..],
+            [("connectTcp", "ok"), ("tlsHandshake", "failed")]
+        );
+    }
+
+    #[test]
+    fn a_server_that_isnt_mongodb_fails_selection() {
+        let diagnostics = check(
+            &format!("mongodb://127.0.0.1:{}", closing_port()),
+            &ConnectionProfile::default(),
+        );
+        assert_eq!(
+            steps(&diagnostics)[3..],
+            [
+                ("connectTcp", "ok"),
+                ("tlsHandshake", "skipped"),
+                ("selectServer", "failed")
+            ]
+        );
+    }
+
+    #[test]
+    fn an_unreachable_proxy_fails_to_open_the_tunnel() {
+        let profile = ConnectionProfile {
+            proxy: Some(ProxyOptions {
+                kind: ProxyKind::Socks5,
+                host: "127.0.0.1".to_string(),
+                port: Some(closed_port()),
+                username: None,
+                password: None,
+            }),
+            ..Default::default()
+        };
+        let diagnostics = check("mongodb://db.internal:27017", &profile);
+        assert_eq!(
+            steps(&diagnostics),
+            [("parseUri", "ok"), ("openTunnel", "failed")]
+        );
+    }
+}
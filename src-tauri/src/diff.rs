@@ -0,0 +1,811 @@
+use std::{
+    cmp::Ordering,
+    fs::File,
+    io::{BufWriter, Write},
+    path::{Path, PathBuf},
+};
+
+use mongodb::{
+    bson::{doc, Bson, Document},
+    options::{Collation, FindOptions},
+    sync::{Client, Cursor},
+};
+use serde::{Deserialize, Serialize};
+use serde_json::{json, Value};
+
+use crate::{error::Error, export::query_document};
+
+/// Differences sent to the UI at a time.
+const DIFFERENCES_PER_EVENT: usize = 100;
+
+const PROGRESS_INTERVAL: u64 = 1000;
+
+/// A collection on one side of a diff.
+pub struct DiffSide<'a> {
+    pub client: &'a Client,
+    /// Names the connection in the report.
+    pub host: String,
+    pub database: &'a str,
+    pub collection: &'a str,
+}
+
+#[derive(Deserialize, Default)]
+#[serde(rename_all = "camelCase", default)]
+pub struct DiffOptions {
+    /// Extended JSON filter applied to both collections.
+    pub filter: Option<Value>,
+    /// Extended JSON projection applied to both collections, which has to
+    /// keep `_id`.
+    pub projection: Option<Value>,
+    /// Also writes the differences to this file as a JSON report.
+    pub report_path: Option<String>,
+}
+
+/// A document that differs between the source and the target.
+#[derive(Serialize, Clone)]
+#[serde(tag = "kind", rename_all = "camelCase")]
+pub enum DiffEntry {
+    /// Only in the source.
+    Missing { id: Value, document: Value },
+    /// Only in the target.
+    Extra { id: Value, document: Value },
+    /// In both, with different fields.
+    Changed { id: Value, fields: Vec<FieldDiff> },
+}
+
+/// A field that differs, by dotted path. A side without the field leaves
+/// its value out, which tells it from a `null`.
+#[derive(Serialize, Clone)]
+#[serde(rename_all = "camelCase")]
+pub struct FieldDiff {
+    pub path: String,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub source: Option<Value>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub target: Option<Value>,
+}
+
+#[derive(Serialize, Default, Clone)]
+#[serde(rename_all = "camelCase")]
+pub struct DiffSummary {
+    pub identical: u64,
+    pub missing: u64,
+    pub extra: u64,
+    pub changed: u64,
+}
+
+/// Sent with the differences found since the last event, and at the end.
+#[derive(Serialize, Clone)]
+#[serde(rename_all = "camelCase")]
+pub struct DiffProgress<'a> {
+    pub summary: &'a DiffSummary,
+    pub differences: &'a [DiffEntry],
+}
+
+/// Compares two collections document by document, walking both in `_id`
+/// order at once so that neither is held in memory. Field order is not
+/// compared.
+pub fn diff_collections(
+    source: DiffSide,
+    target: DiffSide,
+    options: DiffOptions,
+    on_progress: &mut dyn FnMut(&DiffProgress),
+) -> Result<DiffSummary, Error> {
+    let filter = query_document(options.filter, "filter")?;
+    let projection = query_document(options.projection, "projection")?;
+    let mut report = options
+        .report_path
+        .as_deref()
+        .map(|path| Report::create(path.as_ref(), &source, &target))
+        .transpose()?;
+
+    let find = |side: &DiffSide| -> Result<Cursor<Document>, Error> {
+        let options = FindOptions::builder()
+            .sort(doc! { "_id": 1 })
+            .projection(projection.clone())
+            // Sorts the way `compare` does, whatever the collection's
+            // default collation.
+            .collation(Collation::builder().locale("simple").build())
+            .build();
+        Ok(side
+            .client
+            .database(side.database)
+            .collection::<Document>(side.collection)
+            .find(filter.clone(), options)?)
+    };
+    let mut source_documents = Ordered::new(find(&source)?, "source");
+    let mut target_documents = Ordered::new(find(&target)?, "target");
+
+    let mut summary = DiffSummary::default();
+    let mut differences = Vec::new();
+    let mut compared: u64 = 0;
+    let mut next_source = source_documents.next()?;
+    let mut next_target = target_documents.next()?;
+    loop {
+        let entry = match (next_source.take(), next_target.take()) {
+            (None, None) => break,
+            (Some(document), None) => {
+                next_source = source_documents.next()?;
+                summary.missing += 1;
+                missing(document)?
+            }
+            (None, Some(document)) => {
+                next_target = target_documents.next()?;
+                summary.extra += 1;
+                extra(document)?
+            }
+            (Some(source_document), Some(target_document)) => {
+                match compare(id(&source_document)?, id(&target_document)?) {
+                    Ordering::Less => {
+                        next_source = source_documents.next()?;
+                        next_target = Some(target_document);
+                        summary.missing += 1;
+                        missing(source_document)?
+                    }
+                    Ordering::Greater => {
+                        next_source = Some(source_document);
+                        next_target = target_documents.next()?;
+                        summary.extra += 1;
+                        extra(target_document)?
+                    }
+                    Ordering::Equal => {
+                        next_source = source_documents.next()?;
+                        next_target = target_documents.next()?;
+                        let mut fields = Vec::new();
+                        diff_documents("", &source_document, &target_document, &mut fields);
+                        if fields.is_empty() {
+                            summary.identical += 1;
+                            None
+                        } else {
+                            summary.changed += 1;
+                            let id = source_document.get("_id").cloned().unwrap_or(Bson::Null);
+                            Some(DiffEntry::Changed {
+                                id: id.into(),
+                                fields,
+                            })
+                        }
+                    }
+                }
+            }
+        };
+
+        compared += 1;
+        if let Some(entry) = entry {
+            if let Some(report) = &mut report {
+                report.write(&entry)?;
+            }
+            differences.push(entry);
+        }
+        if differences.len() >= DIFFERENCES_PER_EVENT || compared.is_multiple_of(PROGRESS_INTERVAL)
+        {
+            on_progress(&DiffProgress {
+                summary: &summary,
+                differences: &differences,
+            });
+            differences.clear();
+        }
+    }
+
+    on_progress(&DiffProgress {
+        summary: &summary,
+        differences: &differences,
+    });
+    if let Some(report) = report {
+        report.finish(&summary)?;
+    }
+    Ok(summary)
+}
+
+/// The documents of one side, checked to come in the `_id` order [`compare`]
+/// expects, without which the walk would report documents on both sides as
+/// missing and extra.
+struct Ordered {
+    cursor: Cursor<Document>,
+    side: &'static str,
+    last_id: Option<Bson>,
+}
+
+impl Ordered {
+    fn new(cursor: Cursor<Document>, side: &'static str) -> Self {
+        Self {
+            cursor,
+            side,
+            last_id: None,
+        }
+    }
+
+    fn next(&mut self) -> Result<Option<Document>, Error> {
+        let Some(document) = self.cursor.next().transpose()? else {
+            return Ok(None);
+        };
+        let id = id(&document)?;
+        if let Some(last_id) = &self.last_id {
+            if compare(last_id, id) != Ordering::Less {
+                return Err(Error::InvalidArgument(format!(
+                    "the {} documents came out of _id order at {} after {}",
+                    self.side, id, last_id
+                )));
+            }
+        }
+        self.last_id = Some(id.clone());
+        Ok(Some(document))
+    }
+}
+
+fn id(document: &Document) -> Result<&Bson, Error> {
+    document.get("_id").ok_or_else(|| {
+        Error::InvalidArgument("the projection has to keep _id to diff documents".to_string())
+    })
+}
+
+fn missing(document: Document) -> Result<Option<DiffEntry>, Error> {
+    Ok(Some(DiffEntry::Missing {
+        id: id(&document)?.clone().into(),
+        document: Bson::Document(document).into(),
+    }))
+}
+
+fn extra(document: Document) -> Result<Option<DiffEntry>, Error> {
+    Ok(Some(DiffEntry::Extra {
+        id: id(&document)?.clone().into(),
+        document: Bson::Document(document).into(),
+    }))
+}
+
+fn diff_documents(prefix: &str, source: &Document, target: &Document, fields: &mut Vec<FieldDiff>) {
+    let keys = source
+        .keys()
+        .chain(target.keys().filter(|key| !source.contains_key(key)));
+    for key in keys {
+        let path = match prefix {
+            "" => key.clone(),
+            _ => format!("{}.{}", prefix, key),
+        };
+        diff_values(path, source.get(key), target.get(key), fields);
+    }
+}
+
+fn diff_values(
+    path: String,
+    source: Option<&Bson>,
+    target: Option<&Bson>,
+    fields: &mut Vec<FieldDiff>,
+) {
+    match (source, target) {
+        (Some(Bson::Document(source)), Some(Bson::Document(target))) => {
+            diff_documents(&path, source, target, fields)
+        }
+        (Some(Bson::Array(source)), Some(Bson::Array(target))) => {
+            for index in 0..source.len().max(target.len()) {
+                diff_values(
+                    format!("{}.{}", path, index),
+                    source.get(index),
+                    target.get(index),
+                    fields,
+                );
+            }
+        }
+        (source, target) if source == target => {}
+        // Unlike `==`, a NaN is the same as itself.
+        (Some(Bson::Double(source)), Some(Bson::Double(target)))
+            if source.is_nan() && target.is_nan() => {}
+        (source, target) => {
+            let relaxed = |value: Option<&Bson>| value.cloned().map(Bson::into_relaxed_extjson);
+            let (mut source_json, mut target_json) = (relaxed(source), relaxed(target));
+            // A change of numeric type alone would look like no change.
+            if source_json == target_json {
+                let canonical =
+                    |value: Option<&Bson>| value.cloned().map(Bson::into_canonical_extjson);
+                (source_json, target_json) = (canonical(source), canonical(target));
+            }
+            fields.push(FieldDiff {
+                path,
+                source: source_json,
+                target: target_json,
+            })
+        }
+    }
+}
+
+/// Orders values like the server sorts them with the simple collation:
+/// first by type, numbers of any type together, then by value.
+fn compare(a: &Bson, b: &Bson) -> Ordering {
+    let rank = type_rank(a).cmp(&type_rank(b));
+    if rank != Ordering::Equal {
+        return rank;
+    }
+    match (a, b) {
+        (Bson::Int32(_) | Bson::Int64(_), Bson::Int32(_) | Bson::Int64(_)) => {
+            integer(a).cmp(&integer(b))
+        }
+        // NaN sorts before every other number.
+        (Bson::Double(a), Bson::Double(b)) => match (a.is_nan(), b.is_nan()) {
+            (true, true) => Ordering::Equal,
+            (true, false) => Ordering::Less,
+            (false, true) => Ordering::Greater,
+            (false, false) => a.partial_cmp(b).unwrap_or(Ordering::Equal),
+        },
+        (a, b) if type_rank(a) == 10 => Number::from(a).cmp(&Number::from(b)),
+        (Bson::String(a) | Bson::Symbol(a), Bson::String(b) | Bson::Symbol(b)) => a.cmp(b),
+        (Bson::Document(a), Bson::Document(b)) => {
+            for ((a_key, a_value), (b_key, b_value)) in a.iter().zip(b.iter()) {
+                let order = type_rank(a_value)
+                    .cmp(&type_rank(b_value))
+                    .then_with(|| a_key.cmp(b_key))
+                    .then_with(|| compare(a_value, b_value));
+                if order != Ordering::Equal {
+                    return order;
+                }
+            }
+            a.len().cmp(&b.len())
+        }
+        (Bson::Array(a), Bson::Array(b)) => a
+            .iter()
+            .zip(b.iter())
+            .map(|(a, b)| compare(a, b))
+            .find(|order| *order != Ordering::Equal)
+            .unwrap_or_else(|| a.len().cmp(&b.len())),
+        (Bson::Binary(a), Bson::Binary(b)) => a
+            .bytes
+            .len()
+            .cmp(&b.bytes.len())
+            .then_with(|| u8::from(a.subtype).cmp(&u8::from(b.subtype)))
+            .then_with(|| a.bytes.cmp(&b.bytes)),
+        (Bson::ObjectId(a), Bson::ObjectId(b)) => a.bytes().cmp(&b.bytes()),
+        (Bson::Boolean(a), Bson::Boolean(b)) => a.cmp(b),
+        (Bson::DateTime(a), Bson::DateTime(b)) => a.cmp(b),
+        (Bson::Timestamp(a), Bson::Timestamp(b)) => {
+            (a.time, a.increment).cmp(&(b.time, b.increment))
+        }
+        (Bson::RegularExpression(a), Bson::RegularExpression(b)) => a
+            .pattern
+            .cmp(&b.pattern)
+            .then_with(|| a.options.cmp(&b.options)),
+        // By the length of the namespace, then byte by byte.
+        (Bson::DbPointer(_), Bson::DbPointer(_)) => {
+            let (a, b) = (db_pointer(a), db_pointer(b));
+            (a.0.len(), a).cmp(&(b.0.len(), b))
+        }
+        (Bson::JavaScriptCode(a), Bson::JavaScriptCode(b)) => a.cmp(b),
+        (Bson::JavaScriptCodeWithScope(a), Bson::JavaScriptCodeWithScope(b)) => {
+            a.code.cmp(&b.code).then_with(|| {
+                compare(
+                    &Bson::Document(a.scope.clone()),
+                    &Bson::Document(b.scope.clone()),
+                )
+            })
+        }
+        // Null, undefined, MinKey and MaxKey are alike within their type.
+        _ => Ordering::Equal,
+    }
+}
+
+/// The server's canonical type order.
+fn type_rank(value: &Bson) -> u8 {
+    match value {
+        Bson::MinKey => 0,
+        Bson::Undefined => 1,
+        Bson::Null => 5,
+        Bson::Int32(_) | Bson::Int64(_) | Bson::Double(_) | Bson::Decimal128(_) => 10,
+        Bson::String(_) | Bson::Symbol(_) => 15,
+        Bson::Document(_) => 20,
+        Bson::Array(_) => 25,
+        Bson::Binary(_) => 30,
+        Bson::ObjectId(_) => 35,
+        Bson::Boolean(_) => 40,
+        Bson::DateTime(_) => 45,
+        Bson::Timestamp(_) => 47,
+        Bson::RegularExpression(_) => 50,
+        Bson::DbPointer(_) => 55,
+        Bson::JavaScriptCode(_) => 60,
+        Bson::JavaScriptCodeWithScope(_) => 65,
+        Bson::MaxKey => 127,
+    }
+}
+
+fn integer(value: &Bson) -> i64 {
+    match value {
+        Bson::Int32(n) => *n as i64,
+        Bson::Int64(n) => *n,
+        _ => 0,
+    }
+}
+
+/// The namespace and the hex id of a DBPointer, whose fields the driver
+/// keeps to itself.
+fn db_pointer(value: &Bson) -> (String, String) {
+    let json = value.clone().into_relaxed_extjson();
+    let pointer = &json["$dbPointer"];
+    (
+        pointer["$ref"].as_str().unwrap_or_default().to_string(),
+        pointer["$id"]["$oid"]
+            .as_str()
+            .unwrap_or_default()
+            .to_string(),
+    )
+}
+
+/// A number of any type, ordered by its exact value like the server does:
+/// NaN first, then from negative to positive infinity.
+#[derive(PartialEq, Eq, PartialOrd, Ord)]
+enum Number {
+    NaN,
+    NegativeInfinity,
+    Finite(Decimal),
+    Infinity,
+}
+
+impl From<&Bson> for Number {
+    fn from(value: &Bson) -> Self {
+        match value {
+            Bson::Int32(n) => Number::Finite(Decimal::new(*n < 0, n.unsigned_abs() as u128, 0)),
+            Bson::Int64(n) => Number::Finite(Decimal::new(*n < 0, n.unsigned_abs() as u128, 0)),
+            Bson::Double(n) if n.is_nan() => Number::NaN,
+            Bson::Double(n) if n.is_infinite() => match n.is_sign_negative() {
+                true => Number::NegativeInfinity,
+                false => Number::Infinity,
+            },
+            Bson::Double(n) => Number::Finite(Decimal::from_f64(*n)),
+            Bson::Decimal128(n) => decimal128(&n.bytes()),
+            _ => Number::NaN,
+        }
+    }
+}
+
+/// Decodes the IEEE 754 decimal128 a BSON Decimal128 is, stored little
+/// endian.
+fn decimal128(bytes: &[u8; 16]) -> Number {
+    let bits = u128::from_le_bytes(*bytes);
+    let negative = bits >> 127 == 1;
+    if (bits >> 123) & 0xf == 0xf {
+        return match ((bits >> 122) & 1 == 1, negative) {
+            (true, _) => Number::NaN,
+            (false, true) => Number::NegativeInfinity,
+            (false, false) => Number::Infinity,
+        };
+    }
+    // Coefficients that would need the implicit `100` prefix are over the
+    // 34 digits allowed, and read as zero.
+    let (exponent, coefficient) = match (bits >> 125) & 0x3 {
+        0x3 => ((bits >> 111) & 0x3fff, 0),
+        _ => ((bits >> 113) & 0x3fff, bits & ((1 << 113) - 1)),
+    };
+    let coefficient = match coefficient < 10_u128.pow(34) {
+        true => coefficient,
+        false => 0,
+    };
+    Number::Finite(Decimal::new(negative, coefficient, exponent as i32 - 6176))
+}
+
+/// An exact decimal, `digits` (from the least significant) times ten to the
+/// `exponent`. Leading and trailing zeros are dropped, so that every value
+/// has a single form.
+#[derive(PartialEq, Eq)]
+struct Decimal {
+    negative: bool,
+    digits: Vec<u8>,
+    exponent: i32,
+}
+
+impl Decimal {
+    fn new(negative: bool, mut coefficient: u128, exponent: i32) -> Self {
+        let mut digits = Vec::new();
+        while coefficient > 0 {
+            digits.push((coefficient % 10) as u8);
+            coefficient /= 10;
+        }
+        Self::normalized(negative, digits, exponent)
+    }
+
+    /// The exact value of a finite double, which is a whole number times a
+    /// power of two.
+    fn from_f64(n: f64) -> Self {
+        let bits = n.to_bits();
+        let fraction = bits & ((1 << 52) - 1);
+        let (mut mantissa, mut exponent) = match ((bits >> 52) & 0x7ff) as i32 {
+            0 => (fraction, -1074),
+            biased => (fraction | 1 << 52, biased - 1075),
+        };
+        if mantissa == 0 {
+            return Self::new(false, 0, 0);
+        }
+        let zeros = mantissa.trailing_zeros();
+        mantissa >>= zeros;
+        exponent += zeros as i32;
+        let mut digits = Self::new(false, mantissa as u128, 0).digits;
+        // m * 2^-k is m * 5^k * 10^-k.
+        let (factor, times, exponent) = match exponent {
+            0.. => (2, exponent, 0),
+            _ => (5, -exponent, exponent),
+        };
+        for _ in 0..times {
+            let mut carry = 0;
+            for digit in &mut digits {
+                let product = *digit * factor + carry;
+                *digit = product % 10;
+                carry = product / 10;
+            }
+            if carry > 0 {
+                digits.push(carry);
+            }
+        }
+        Self::normalized(n.is_sign_negative(), digits, exponent)
+    }
+
+    fn normalized(negative: bool, mut digits: Vec<u8>, exponent: i32) -> Self {
+        while digits.last() == Some(&0) {
+            digits.pop();
+        }
+        let zeros = digits.iter().take_while(|digit| **digit == 0).count();
+        digits.drain(..zeros);
+        match digits.is_empty() {
+            // -0 is 0.
+            true => Self {
+                negative: false,
+                digits,
+                exponent: 0,
+            },
+            false => Self {
+                negative,
+                digits,
+                exponent: exponent + zeros as i32,
+            },
+        }
+    }
+
+    /// Compares the absolute values.
+    fn cmp_magnitude(&self, other: &Self) -> Ordering {
+        match (self.digits.is_empty(), other.digits.is_empty()) {
+            (true, true) => Ordering::Equal,
+            (true, false) => Ordering::Less,
+            (false, true) => Ordering::Greater,
+            // By the position of the first digit, then digit by digit.
+            (false, false) => (self.digits.len() as i32 + self.exponent)
+                .cmp(&(other.digits.len() as i32 + other.exponent))
+                .then_with(|| self.digits.iter().rev().cmp(other.digits.iter().rev())),
+        }
+    }
+}
+
+impl Ord for Decimal {
+    fn cmp(&self, other: &Self) -> Ordering {
+        match (self.negative, other.negative) {
+            (false, false) => self.cmp_magnitude(other),
+            (true, true) => other.cmp_magnitude(self),
+            (true, false) => Ordering::Less,
+            (false, true) => Ordering::Greater,
+        }
+    }
+}
+
+impl PartialOrd for Decimal {
+    fn partial_cmp(&self, other: &Self) -> Option<Ordering> {
+        Some(self.cmp(other))
+    }
+}
+
+/// A JSON file of the differences, written as they are found.
+struct Report {
+    writer: BufWriter<File>,
+    path: PathBuf,
+    written: u64,
+}
+
+impl Report {
+    fn create(path: &Path, source: &DiffSide, target: &DiffSide) -> Result<Self, Error> {
+        let file = File::create(path).map_err(|err| {
+            Error::Storage(format!("failed to create {}: {}", path.display(), err))
+        })?;
+        let mut report = Report {
+            writer: BufWriter::new(file),
+            path: path.to_path_buf(),
+            written: 0,
+        };
+        let side = |side: &DiffSide| {
+            json!({
+                "host": side.host,
+                "database": side.database,
+                "collection": side.collection,
+            })
+        };
+        // The header is written by hand so that the differences can follow
+        // one at a time.
+        let header = format!(
+            "{{\"source\":{},\"target\":{},\"differences\":[",
+            side(source),
+            side(target)
+        );
+        report.write_bytes(header.as_bytes())?;
+        Ok(report)
+    }
+
+    fn write(&mut self, entry: &DiffEntry) -> Result<(), Error> {
+        let separator = match self.written {
+            0 => "\n",
+            _ => ",\n",
+        };
+        self.written += 1;
+        let entry = serde_json::to_string(entry).map_err(|err| self.error(err.into()))?;
+        self.write_bytes(format!("{}{}", separator, entry).as_bytes())
+    }
+
+    fn finish(mut self, summary: &DiffSummary) -> Result<(), Error> {
+        let summary = serde_json::to_string(summary).map_err(|err| self.error(err.into()))?;
+        self.write_bytes(format!("\n],\"summary\":{}}}\n", summary).as_bytes())?;
+        self.writer.flush().map_err(|err| self.error(err))
+    }
+
+    fn write_bytes(&mut self, bytes: &[u8]) -> Result<(), Error> {
+        self.writer.write_all(bytes).map_err(|err| self.error(err))
+    }
+
+    fn error(&self, err: std::io::Error) -> Error {
+        Error::Storage(format!("failed to write {}: {}", self.path.display(), err))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::str::FromStr;
+
+    use mongodb::bson::{oid::ObjectId, Decimal128, JavaScriptCodeWithScope};
+
+    use super::*;
+
+    fn decimal(s: &str) -> Bson {
+        Bson::Decimal128(Decimal128::from_str(s).unwrap())
+    }
+
+    fn assert_order(values: &[Bson]) {
+        for (index, a) in values.iter().enumerate() {
+            for (other, b) in values.iter().enumerate() {
+                assert_eq!(compare(a, b), index.cmp(&other), "{} against {}", a, b);
+            }
+        }
+    }
+
+    #[test]
+    fn orders_numbers_by_exact_value() {
+        assert_order(&[
+            Bson::Double(f64::NAN),
+            Bson::Double(f64::NEG_INFINITY),
+            decimal("-1E+400"),
+            Bson::Int64(i64::MIN),
+            Bson::Double(-1.5),
+            Bson::Int32(-1),
+            decimal("-0.1"),
+            Bson::Double(5e-324),
+            decimal("0.1000000000000000000000000000000001"),
+            Bson::Double(0.1),
+            Bson::Int32(1),
+            decimal("1.000000000000000000000000000000001"),
+            Bson::Int64(9_007_199_254_740_992),
+            Bson::Int64(9_007_199_254_740_993),
+            Bson::Double(9_223_372_036_854_775_808.0),
+            decimal("1E+400"),
+            Bson::Double(f64::INFINITY),
+        ]);
+    }
+
+    #[test]
+    fn equal_numbers_of_any_type_are_equal() {
+        let ones = [
+            Bson::Int32(1),
+            Bson::Int64(1),
+            Bson::Double(1.0),
+            decimal("1"),
+            decimal("1.000"),
+            decimal("0.1E+1"),
+        ];
+        for a in &ones {
+            for b in &ones {
+                assert_eq!(compare(a, b), Ordering::Equal, "{} against {}", a, b);
+            }
+        }
+        // Both are 2^53 as doubles.
+        assert_eq!(
+            compare(
+                &Bson::Int64(9_007_199_254_740_993),
+                &Bson::Double(9_007_199_254_740_992.0)
+            ),
+            Ordering::Greater
+        );
+        assert_eq!(compare(&Bson::Double(-0.0), &decimal("0")), Ordering::Equal);
+        assert_eq!(compare(&decimal("-0"), &Bson::Int32(0)), Ordering::Equal);
+        assert_eq!(
+            compare(&decimal("NaN"), &Bson::Double(f64::NAN)),
+            Ordering::Equal
+        );
+        assert_eq!(
+            compare(&decimal("-Infinity"), &Bson::Double(f64::NEG_INFINITY)),
+            Ordering::Equal
+        );
+    }
+
+    #[test]
+    fn orders_by_type_then_value() {
+        let id = ObjectId::parse_str("65f000000000000000000000").unwrap();
+        let code_with_scope = |code: &str, n: i32| {
+            Bson::JavaScriptCodeWithScope(JavaScriptCodeWithScope {
+                code: code.to_string(),
+                scope: doc! { "n": n },
+            })
+        };
+        assert_order(&[
+            Bson::MinKey,
+            Bson::Null,
+            Bson::Int32(5),
+            Bson::String("a".to_string()),
+            Bson::Symbol("b".to_string()),
+            Bson::Document(doc! { "a": 1 }),
+            Bson::Document(doc! { "a": "1" }),
+            Bson::Array(vec![Bson::Int32(1)]),
+            Bson::Array(vec![Bson::Int32(1), Bson::Int32(0)]),
+            Bson::ObjectId(id),
+            Bson::Boolean(false),
+            Bson::Boolean(true),
+            Bson::JavaScriptCode("a".to_string()),
+            Bson::JavaScriptCode("b".to_string()),
+            code_with_scope("a", 1),
+            code_with_scope("a", 2),
+            code_with_scope("b", 0),
+            Bson::MaxKey,
+        ]);
+    }
+
+    #[test]
+    fn orders_db_pointers_by_namespace_length_first() {
+        let pointer = |namespace: &str, id: &str| {
+            let json = json!({ "$dbPointer": { "$ref": namespace, "$id": { "$oid": id } } });
+            Bson::try_from(json).unwrap()
+        };
+        assert_order(&[
+            pointer("db.b", "65f000000000000000000001"),
+            pointer("db.b", "65f000000000000000000002"),
+            pointer("db.c", "65f000000000000000000000"),
+            pointer("db.aa", "65f000000000000000000000"),
+        ]);
+    }
+
+    fn diff(source: Bson, target: Bson) -> Vec<Value> {
+        let mut fields = Vec::new();
+        diff_values("a".to_string(), Some(&source), Some(&target), &mut fields);
+        fields
+            .into_iter()
+            .map(|field| serde_json::to_value(field).unwrap())
+            .collect()
+    }
+
+    #[test]
+    fn diffs_nested_values_by_path() {
+        let source = Bson::Document(doc! { "b": 1, "c": [1, 2], "d": { "e": null } });
+        let target = Bson::Document(doc! { "b": 2, "c": [1], "d": { "f": true } });
+        assert_eq!(
+            diff(source, target),
+            [
+                json!({ "path": "a.b", "source": 1, "target": 2 }),
+                json!({ "path": "a.c.1", "source": 2 }),
+                json!({ "path": "a.d.e", "source": null }),
+                json!({ "path": "a.d.f", "target": true }),
+            ]
+        );
+    }
+
+    #[test]
+    fn a_change_of_numeric_type_is_a_difference() {
+        assert_eq!(
+            diff(Bson::Int32(1), Bson::Int64(1)),
+            [
+                json!({ "path": "a", "source": { "$numberInt": "1" }, "target": { "$numberLong": "1" } })
+            ]
+        );
+    }
+
+    #[test]
+    fn identical_values_are_no_difference() {
+        assert!(diff(Bson::Double(f64::NAN), Bson::Double(f64::NAN)).is_empty());
+        let document = Bson::Document(doc! { "n": f64::NAN, "s": "x", "l": [null] });
+        assert!(diff(document.clone(), document).is_empty());
+        assert!(diff(decimal("NaN"), decimal("NaN")).is_empty());
+    }
+}
@@ -0,0 +1,1140 @@
+use std::{
+    collections::{BTreeMap, HashMap},
+    fs::{self, File},
+    io::{self, BufRead, BufReader, BufWriter, Read, Write},
+    path::{Path, PathBuf},
+};
+
+use flate2::{read::MultiGzDecoder, write::GzEncoder, Compression};
+use mongodb::{
+    bson::{doc, Bson, Document, RawDocumentBuf},
+    error::ErrorKind,
+    sync::{Client, Database},
+};
+use serde::{Deserialize, Serialize};
+use serde_json::Value;
+
+use crate::{error::Error, export::WriteError, import};
+
+/// Databases a dump leaves out unless they are asked for by name, like
+/// `mongodump` does.
+const INTERNAL_DATABASES: [&str; 3] = ["admin", "config", "local"];
+
+/// First bytes of an `--archive` file.
+const ARCHIVE_MAGIC: u32 = 0x8199_e26d;
+
+/// Ends the prelude and every namespace segment of an archive.
+const TERMINATOR: u32 = 0xffff_ffff;
+
+const PROGRESS_INTERVAL: u64 = 1000;
+
+/// Longest BSON document read from a dump, the 16 MiB a server stores plus
+/// the room it leaves for command overhead.
+const MAX_DOCUMENT_SIZE: u32 = 16 * 1024 * 1024 + 16 * 1024;
+
+#[derive(Deserialize, Default)]
+#[serde(rename_all = "camelCase", default)]
+pub struct DumpOptions {
+    /// Databases to dump, every one but `admin`, `config` and `local` if
+    /// empty.
+    pub databases: Vec<String>,
+    /// Namespaces (`db.collection`) to dump, where `*` matches any run of
+    /// characters. Everything if empty.
+    pub include: Vec<String>,
+    /// Namespaces to leave out, in the same form as `include`.
+    pub exclude: Vec<String>,
+    /// Writes a single `--archive` file instead of a directory.
+    pub archive: bool,
+    /// Compresses every file, or the archive, with gzip.
+    pub gzip: bool,
+}
+
+/// Whether the dump is gzipped is told from the files themselves.
+#[derive(Deserialize)]
+#[serde(rename_all = "camelCase", default)]
+pub struct RestoreOptions {
+    /// Namespaces to restore, in the same form as [`DumpOptions::include`].
+    pub include: Vec<String>,
+    pub exclude: Vec<String>,
+    /// Reads a single `--archive` file instead of a directory.
+    pub archive: bool,
+    /// Drops every collection before restoring it.
+    pub drop: bool,
+    pub restore_indexes: bool,
+    /// Documents sent to the server at a time.
+    pub batch_size: usize,
+}
+
+impl Default for RestoreOptions {
+    fn default() -> Self {
+        Self {
+            include: Vec::new(),
+            exclude: Vec::new(),
+            archive: false,
+            drop: false,
+            restore_indexes: true,
+            batch_size: 1000,
+        }
+    }
+}
+
+/// Sent after every collection and every [`PROGRESS_INTERVAL`] documents,
+/// and returned once the dump or restore is done.
+#[derive(Serialize, Default, Clone)]
+#[serde(rename_all = "camelCase")]
+pub struct DumpProgress {
+    /// The collection in progress, as `db.collection`.
+    pub namespace: Option<String>,
+    /// Collections done.
+    pub collections: u64,
+    pub documents: u64,
+    /// Documents the server rejected on restore, such as duplicate keys.
+    pub failed: u64,
+}
+
+/// A collection as `mongodump` describes it in `<coll>.metadata.json`.
+pub struct CollectionMeta {
+    pub database: String,
+    pub name: String,
+    /// `collection`, `view` or `timeseries`.
+    pub kind: String,
+    /// The options the collection was created with.
+    pub options: Document,
+    pub indexes: Vec<Document>,
+    uuid: Option<String>,
+}
+
+impl CollectionMeta {
+    fn namespace(&self) -> String {
+        format!("{}.{}", self.database, self.name)
+    }
+
+    pub fn is_view(&self) -> bool {
+        self.kind == "view"
+    }
+
+    fn to_json(&self) -> String {
+        let mut metadata = doc! {
+            "options": self.options.clone(),
+            "indexes": self.indexes.clone(),
+        };
+        if let Some(uuid) = &self.uuid {
+            metadata.insert("uuid", uuid);
+        }
+        metadata.insert("collectionName", &self.name);
+        metadata.insert("type", &self.kind);
+        Bson::Document(metadata)
+            .into_canonical_extjson()
+            .to_string()
+    }
+
+    fn from_json(database: &str, name: &str, json: &str) -> Result<Self, Error> {
+        let invalid =
+            || Error::InvalidArgument(format!("invalid metadata for {}.{}", database, name));
+        let value: Value = serde_json::from_str(json).map_err(|_| invalid())?;
+        let Ok(Bson::Document(metadata)) = Bson::try_from(value) else {
+            return Err(invalid());
+        };
+        Ok(Self {
+            database: database.to_string(),
+            name: name.to_string(),
+            kind: metadata.get_str("type").unwrap_or("collection").to_string(),
+            options: metadata
+                .get_document("options")
+                .cloned()
+                .unwrap_or_default(),
+            indexes: metadata
+                .get_array("indexes")
+                .map(|indexes| {
+                    indexes
+                        .iter()
+                        .filter_map(Bson::as_document)
+                        .cloned()
+                        .collect()
+                })
+                .unwrap_or_default(),
+            uuid: metadata.get_str("uuid").ok().map(String::from),
+        })
+    }
+
+    /// A collection dumped without metadata.
+    pub fn plain(database: &str, name: &str) -> Self {
+        Self {
+            database: database.to_string(),
+            name: name.to_string(),
+            kind: "collection".to_string(),
+            options: Document::new(),
+            indexes: Vec::new(),
+            uuid: None,
+        }
+    }
+}
+
+struct NamespaceFilter<'a> {
+    include: &'a [String],
+    exclude: &'a [String],
+}
+
+impl NamespaceFilter<'_> {
+    fn matches(&self, database: &str, collection: &str) -> bool {
+        if collection.starts_with("system.") {
+            return false;
+        }
+        let namespace = format!("{}.{}", database, collection);
+        (self.include.is_empty()
+            || self
+                .include
+                .iter()
+                .any(|pattern| wildcard_match(pattern, &namespace)))
+            && !self
+                .exclude
+                .iter()
+                .any(|pattern| wildcard_match(pattern, &namespace))
+    }
+}
+
+fn wildcard_match(pattern: &str, text: &str) -> bool {
+    let mut parts = pattern.split('*');
+    let Some(mut rest) = text.strip_prefix(parts.next().unwrap_or_default()) else {
+        return false;
+    };
+    let mut parts: Vec<&str> = parts.collect();
+    let Some(last) = parts.pop() else {
+        return rest.is_empty();
+    };
+    for part in parts {
+        match rest.find(part) {
+            Some(index) => rest = &rest[index + part.len()..],
+            None => return false,
+        }
+    }
+    rest.ends_with(last)
+}
+
+/// Escapes a collection name for use as a file name, like `mongodump`.
+fn escape_name(name: &str) -> String {
+    name.replace('%', "%25").replace('/', "%2F")
+}
+
+fn unescape_name(name: &str) -> String {
+    let mut bytes = Vec::with_capacity(name.len());
+    let mut rest = name.as_bytes();
+    while let Some((&byte, tail)) = rest.split_first() {
+        let decoded = (byte == b'%')
+            .then(|| tail.get(..2))
+            .flatten()
+            .and_then(|hex| std::str::from_utf8(hex).ok())
+            .and_then(|hex| u8::from_str_radix(hex, 16).ok());
+        match decoded {
+            Some(decoded) => {
+                bytes.push(decoded);
+                rest = &tail[2..];
+            }
+            None => {
+                bytes.push(byte);
+                rest = tail;
+            }
+        }
+    }
+    String::from_utf8_lossy(&bytes).into_owned()
+}
+
+/// CRC-64 with the ECMA polynomial, as `mongorestore` checks archive
+/// namespaces with.
+const CRC64_TABLE: [u64; 256] = {
+    let mut table = [0; 256];
+    let mut i = 0;
+    while i < 256 {
+        let mut crc = i as u64;
+        let mut bit = 0;
+        while bit < 8 {
+            crc = match crc & 1 {
+                1 => (crc >> 1) ^ 0xc96c_5795_d787_0f42,
+                _ => crc >> 1,
+            };
+            bit += 1;
+        }
+        table[i] = crc;
+        i += 1;
+    }
+    table
+};
+
+fn crc64(crc: u64, bytes: &[u8]) -> u64 {
+    !bytes.iter().fold(!crc, |crc, &byte| {
+        CRC64_TABLE[((crc as u8) ^ byte) as usize] ^ (crc >> 8)
+    })
+}
+
+/// A file being written, gzipped or not.
+enum Output {
+    Plain(BufWriter<File>),
+    Gzip(GzEncoder<BufWriter<File>>),
+}
+
+impl Output {
+    fn create(path: &Path, gzip: bool) -> Result<Self, Error> {
+        let file = File::create(path).map_err(|err| {
+            Error::Storage(format!("failed to create {}: {}", path.display(), err))
+        })?;
+        let writer = BufWriter::new(file);
+        Ok(match gzip {
+            true => Output::Gzip(GzEncoder::new(writer, Compression::default())),
+            false => Output::Plain(writer),
+        })
+    }
+
+    fn finish(self) -> io::Result<()> {
+        match self {
+            Output::Plain(mut writer) => writer.flush(),
+            Output::Gzip(encoder) => encoder.finish()?.flush(),
+        }
+    }
+}
+
+impl Write for Output {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        match self {
+            Output::Plain(writer) => writer.write(buf),
+            Output::Gzip(encoder) => encoder.write(buf),
+        }
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        match self {
+            Output::Plain(writer) => writer.flush(),
+            Output::Gzip(encoder) => encoder.flush(),
+        }
+    }
+}
+
+/// Opens a file of a dump, gunzipping it if it starts like gzip.
+fn open(path: &Path) -> Result<Box<dyn Read>, Error> {
+    let read_error = |err: io::Error| read_error(path, err);
+    let mut reader = BufReader::new(File::open(path).map_err(read_error)?);
+    let gzipped = reader
+        .fill_buf()
+        .map_err(read_error)?
+        .starts_with(&[0x1f, 0x8b]);
+    Ok(match gzipped {
+        true => Box::new(MultiGzDecoder::new(reader)),
+        false => Box::new(reader),
+    })
+}
+
+fn read_error(path: &Path, err: io::Error) -> Error {
+    Error::Storage(format!("failed to read {}: {}", path.display(), err))
+}
+
+fn write_error(path: &Path, err: io::Error) -> Error {
+    Error::Storage(format!("failed to write {}: {}", path.display(), err))
+}
+
+enum Chunk {
+    /// The bytes of a BSON document.
+    Document(Vec<u8>),
+    Terminator,
+    End,
+}
+
+fn read_chunk(reader: &mut dyn Read) -> io::Result<Chunk> {
+    let mut length = [0; 4];
+    let mut filled = 0;
+    while filled < length.len() {
+        match reader.read(&mut length[filled..])? {
+            0 if filled == 0 => return Ok(Chunk::End),
+            0 => return Err(io::ErrorKind::UnexpectedEof.into()),
+            n => filled += n,
+        }
+    }
+    let size = u32::from_le_bytes(length);
+    if size == TERMINATOR {
+        return Ok(Chunk::Terminator);
+    }
+    if !(5..=MAX_DOCUMENT_SIZE).contains(&size) {
+        return Err(io::Error::new(
+            io::ErrorKind::InvalidData,
+            format!("invalid BSON document length {}", size),
+        ));
+    }
+    let mut bytes = vec![0; size as usize];
+    bytes[..4].copy_from_slice(&length);
+    reader.read_exact(&mut bytes[4..])?;
+    Ok(Chunk::Document(bytes))
+}
+
+fn parse_document(bytes: &[u8], path: &Path) -> Result<Document, Error> {
+    Document::from_reader(bytes)
+        .map_err(|err| Error::Storage(format!("invalid BSON in {}: {}", path.display(), err)))
+}
+
+/// Dumps databases to `path`, either as a directory of `<db>/<coll>.bson`
+/// and `<coll>.metadata.json` files or as a single archive, both readable by
+/// `mongorestore`.
+pub fn dump(
+    client: &Client,
+    path: &Path,
+    options: &DumpOptions,
+    on_progress: &mut dyn FnMut(&DumpProgress),
+) -> Result<DumpProgress, Error> {
+    let filter = NamespaceFilter {
+        include: &options.include,
+        exclude: &options.exclude,
+    };
+    let databases = match options.databases.is_empty() {
+        true => client
+            .list_database_names(None, None)?
+            .into_iter()
+            .filter(|name| !INTERNAL_DATABASES.contains(&name.as_str()))
+            .collect(),
+        false => options.databases.clone(),
+    };
+    let mut collections = Vec::new();
+    for database in &databases {
+        collections.extend(
+            list_collections(&client.database(database), None)?
+                .into_iter()
+                .filter(|meta| filter.matches(&meta.database, &meta.name)),
+        );
+    }
+
+    let mut dumper = Dumper {
+        client,
+        progress: DumpProgress::default(),
+        on_progress,
+    };
+    match options.archive {
+        true => dumper.write_archive(&collections, path, options.gzip)?,
+        false => dumper.write_directory(&collections, path, options.gzip)?,
+    }
+    dumper.progress.namespace = None;
+    (dumper.on_progress)(&dumper.progress);
+    Ok(dumper.progress)
+}
+
+/// The collections of `database` matching the `listCollections` `filter`,
+/// with their options and indexes.
+pub fn list_collections(
+    database: &Database,
+    filter: Option<Document>,
+) -> Result<Vec<CollectionMeta>, Error> {
+    let mut collections = Vec::new();
+    let mut cursor = database.list_collections(filter, None)?;
+    while cursor.advance()? {
+        let spec = Document::try_from(cursor.current()).map_err(mongodb::error::Error::from)?;
+        let name = spec.get_str("name").unwrap_or_default().to_string();
+        let kind = spec.get_str("type").unwrap_or("collection").to_string();
+        let uuid = match spec.get_document("info").map(|info| info.get("uuid")) {
+            Ok(Some(Bson::Binary(uuid))) => Some(
+                uuid.bytes
+                    .iter()
+                    .map(|byte| format!("{:02x}", byte))
+                    .collect(),
+            ),
+            _ => None,
+        };
+        let mut indexes = Vec::new();
+        if kind != "view" {
+            let mut cursor = database.collection::<Document>(&name).list_indexes(None)?;
+            while cursor.advance()? {
+                indexes.push(
+                    Document::try_from(cursor.current()).map_err(mongodb::error::Error::from)?,
+                );
+            }
+        }
+        collections.push(CollectionMeta {
+            database: database.name().to_string(),
+            name,
+            kind,
+            options: spec.get_document("options").cloned().unwrap_or_default(),
+            indexes,
+            uuid,
+        });
+    }
+    collections.sort_by(|a, b| a.name.cmp(&b.name));
+    Ok(collections)
+}
+
+struct Dumper<'a> {
+    client: &'a Client,
+    progress: DumpProgress,
+    on_progress: &'a mut dyn FnMut(&DumpProgress),
+}
+
+impl Dumper<'_> {
+    fn write_directory(
+        &mut self,
+        collections: &[CollectionMeta],
+        root: &Path,
+        gzip: bool,
+    ) -> Result<(), Error> {
+        let suffix = if gzip { ".gz" } else { "" };
+        for meta in collections {
+            let dir = root.join(&meta.database);
+            fs::create_dir_all(&dir).map_err(|err| write_error(&dir, err))?;
+            let name = escape_name(&meta.name);
+
+            let path = dir.join(format!("{}.metadata.json{}", name, suffix));
+            let mut output = Output::create(&path, gzip)?;
+            output
+                .write_all(meta.to_json().as_bytes())
+                .and_then(|_| output.finish())
+                .map_err(|err| write_error(&path, err))?;
+
+            // Views have a metadata file only.
+            if !meta.is_view() {
+                let path = dir.join(format!("{}.bson{}", name, suffix));
+                let mut output = Output::create(&path, gzip)?;
+                self.write_documents(meta, &mut |bytes| output.write_all(bytes))
+                    .map_err(|err| err.into_error(&path))?;
+                output.finish().map_err(|err| write_error(&path, err))?;
+            }
+            self.finish_collection(meta);
+        }
+        Ok(())
+    }
+
+    /// Writes the archive one collection after another, where `mongodump`
+    /// interleaves them.
+    fn write_archive(
+        &mut self,
+        collections: &[CollectionMeta],
+        path: &Path,
+        gzip: bool,
+    ) -> Result<(), Error> {
+        let server_version = self
+            .client
+            .database("admin")
+            .run_command(doc! { "buildInfo": 1 }, None)?
+            .get_str("version")
+            .unwrap_or_default()
+            .to_string();
+        let mut archive = ArchiveWriter {
+            output: Output::create(path, gzip)?,
+        };
+        archive
+            .write_prelude(&server_version, collections)
+            .map_err(|err| write_error(path, err))?;
+        for meta in collections {
+            archive
+                .write_collection(meta, |write| self.write_documents(meta, write))
+                .map_err(|err| err.into_error(path))?;
+            self.finish_collection(meta);
+        }
+        archive
+            .output
+            .finish()
+            .map_err(|err| write_error(path, err))
+    }
+
+    /// Streams the raw documents of a collection to `write`.
+    fn write_documents(
+        &mut self,
+        meta: &CollectionMeta,
+        write: &mut dyn FnMut(&[u8]) -> io::Result<()>,
+    ) -> Result<(), WriteError> {
+        self.progress.namespace = Some(meta.namespace());
+        let mut cursor = self
+            .client
+            .database(&meta.database)
+            .collection::<Document>(&meta.name)
+            .find(None, None)
+            .map_err(|err| WriteError::Source(err.into()))?;
+        while cursor
+            .advance()
+            .map_err(|err| WriteError::Source(err.into()))?
+        {
+            write(cursor.current().as_bytes())?;
+            self.progress.documents += 1;
+            if self.progress.documents.is_multiple_of(PROGRESS_INTERVAL) {
+                (self.on_progress)(&self.progress);
+            }
+        }
+        Ok(())
+    }
+
+    fn finish_collection(&mut self, meta: &CollectionMeta) {
+        self.progress.namespace = Some(meta.namespace());
+        self.progress.collections += 1;
+        (self.on_progress)(&self.progress);
+    }
+}
+
+/// Writes the `--archive` format of `mongodump`.
+struct ArchiveWriter<W> {
+    output: W,
+}
+
+impl<W: Write> ArchiveWriter<W> {
+    /// Writes the header, then the metadata of every collection.
+    fn write_prelude(
+        &mut self,
+        server_version: &str,
+        collections: &[CollectionMeta],
+    ) -> io::Result<()> {
+        let mut prelude = ARCHIVE_MAGIC.to_le_bytes().to_vec();
+        let header = doc! {
+            "version": "0.1",
+            "server_version": server_version,
+            "tool_version": concat!("mongo-ui ", env!("CARGO_PKG_VERSION")),
+            "concurrent_collections": 1,
+        };
+        write_bson(&header, &mut prelude)?;
+        for meta in collections {
+            let metadata = doc! {
+                "db": &meta.database,
+                "collection": &meta.name,
+                "metadata": meta.to_json(),
+                // Only used for `mongorestore`'s progress bars.
+                "size": 0_i64,
+                "type": &meta.kind,
+            };
+            write_bson(&metadata, &mut prelude)?;
+        }
+        prelude.extend(TERMINATOR.to_le_bytes());
+        self.output.write_all(&prelude)
+    }
+
+    /// Writes the documents `documents` hands to its callback as a single
+    /// segment, then the EOF header closing the collection.
+    fn write_collection(
+        &mut self,
+        meta: &CollectionMeta,
+        documents: impl FnOnce(&mut dyn FnMut(&[u8]) -> io::Result<()>) -> Result<(), WriteError>,
+    ) -> Result<(), WriteError> {
+        let mut crc = 0;
+        if !meta.is_view() {
+            self.write_namespace_header(meta, false, 0)?;
+            let output = &mut self.output;
+            documents(&mut |bytes| {
+                crc = crc64(crc, bytes);
+                output.write_all(bytes)
+            })?;
+            self.output.write_all(&TERMINATOR.to_le_bytes())?;
+        }
+        self.write_namespace_header(meta, true, crc)?;
+        self.output.write_all(&TERMINATOR.to_le_bytes())?;
+        Ok(())
+    }
+
+    fn write_namespace_header(
+        &mut self,
+        meta: &CollectionMeta,
+        eof: bool,
+        crc: u64,
+    ) -> io::Result<()> {
+        let header = doc! {
+            "db": &meta.database,
+            "collection": &meta.name,
+            "EOF": eof,
+            "CRC": crc as i64,
+        };
+        let mut bytes = Vec::new();
+        write_bson(&header, &mut bytes)?;
+        self.output.write_all(&bytes)
+    }
+}
+
+fn write_bson(document: &Document, bytes: &mut Vec<u8>) -> io::Result<()> {
+    document
+        .to_writer(bytes)
+        .map_err(|err| io::Error::new(io::ErrorKind::InvalidData, err))
+}
+
+/// What [`ArchiveReader`] reads past the prelude.
+enum ArchiveItem {
+    /// The start of a segment of documents of a namespace.
+    Segment(String),
+    /// A document of the segment being read.
+    Document(Vec<u8>),
+    /// The end of a namespace, once its checksum matched.
+    Eof(String),
+}
+
+/// Reads an archive written by [`ArchiveWriter`] or `mongodump`, whose
+/// segments may interleave collections.
+struct ArchiveReader<'a, R> {
+    reader: R,
+    path: &'a Path,
+    /// Checksums of the namespaces not closed yet.
+    crcs: HashMap<String, u64>,
+    /// The namespace of the segment being read.
+    segment: Option<String>,
+}
+
+impl<'a, R: Read> ArchiveReader<'a, R> {
+    fn new(reader: R, path: &'a Path) -> Self {
+        Self {
+            reader,
+            path,
+            crcs: HashMap::new(),
+            segment: None,
+        }
+    }
+
+    /// Reads the header and returns the metadata of every collection.
+    fn read_prelude(&mut self) -> Result<Vec<Document>, Error> {
+        let mut magic = [0; 4];
+        self.reader
+            .read_exact(&mut magic)
+            .map_err(|err| read_error(self.path, err))?;
+        if u32::from_le_bytes(magic) != ARCHIVE_MAGIC {
+            return Err(self.invalid("not a mongodump archive"));
+        }
+        let mut metadata = Vec::new();
+        let mut header_read = false;
+        loop {
+            let bytes = match self.read_chunk()? {
+                Chunk::Document(bytes) => bytes,
+                Chunk::Terminator => return Ok(metadata),
+                Chunk::End => return Err(self.invalid("truncated archive prelude")),
+            };
+            if std::mem::replace(&mut header_read, true) {
+                metadata.push(parse_document(&bytes, self.path)?);
+            }
+        }
+    }
+
+    fn next(&mut self) -> Result<Option<ArchiveItem>, Error> {
+        loop {
+            let chunk = self.read_chunk()?;
+            if let Some(namespace) = &self.segment {
+                match chunk {
+                    Chunk::Document(bytes) => {
+                        if let Some(crc) = self.crcs.get_mut(namespace) {
+                            *crc = crc64(*crc, &bytes);
+                        }
+                        return Ok(Some(ArchiveItem::Document(bytes)));
+                    }
+                    Chunk::Terminator => {
+                        self.segment = None;
+                        continue;
+                    }
+                    Chunk::End => return Err(self.invalid("truncated archive")),
+                }
+            }
+            let bytes = match chunk {
+                Chunk::Document(bytes) => bytes,
+                Chunk::Terminator => continue,
+                Chunk::End => return Ok(None),
+            };
+            let header = parse_document(&bytes, self.path)?;
+            let namespace = format!(
+                "{}.{}",
+                header.get_str("db").unwrap_or_default(),
+                header.get_str("collection").unwrap_or_default()
+            );
+            if header.get_bool("EOF").unwrap_or(false) {
+                let crc = self.crcs.remove(&namespace).unwrap_or_default();
+                if matches!(header.get_i64("CRC"), Ok(expected) if expected != crc as i64) {
+                    return Err(self.invalid(&format!("checksum mismatch for {}", namespace)));
+                }
+                return Ok(Some(ArchiveItem::Eof(namespace)));
+            }
+            self.crcs.entry(namespace.clone()).or_default();
+            self.segment = Some(namespace.clone());
+            return Ok(Some(ArchiveItem::Segment(namespace)));
+        }
+    }
+
+    fn read_chunk(&mut self) -> Result<Chunk, Error> {
+        read_chunk(&mut self.reader).map_err(|err| read_error(self.path, err))
+    }
+
+    fn invalid(&self, message: &str) -> Error {
+        read_error(
+            self.path,
+            io::Error::new(io::ErrorKind::InvalidData, message.to_string()),
+        )
+    }
+}
+
+/// Restores a dump written by [`dump`] or `mongodump`.
+pub fn restore(
+    client: &Client,
+    path: &Path,
+    options: &RestoreOptions,
+    on_progress: &mut dyn FnMut(&DumpProgress),
+) -> Result<DumpProgress, Error> {
+    let mut restorer = Restorer {
+        client,
+        options,
+        filter: NamespaceFilter {
+            include: &options.include,
+            exclude: &options.exclude,
+        },
+        progress: DumpProgress::default(),
+        on_progress,
+    };
+    match options.archive {
+        true => restorer.read_archive(path)?,
+        false => restorer.read_directory(path)?,
+    }
+    restorer.progress.namespace = None;
+    (restorer.on_progress)(&restorer.progress);
+    Ok(restorer.progress)
+}
+
+struct Restorer<'a> {
+    client: &'a Client,
+    options: &'a RestoreOptions,
+    filter: NamespaceFilter<'a>,
+    progress: DumpProgress,
+    on_progress: &'a mut dyn FnMut(&DumpProgress),
+}
+
+impl Restorer<'_> {
+    fn read_directory(&mut self, root: &Path) -> Result<(), Error> {
+        for dir in sorted_entries(root)? {
+            if !dir.is_dir() {
+                continue;
+            }
+            let database = dir
+                .file_name()
+                .map(|name| name.to_string_lossy().into_owned())
+                .unwrap_or_default();
+            // The metadata and BSON file of each collection, by name.
+            let mut files: BTreeMap<String, (Option<PathBuf>, Option<PathBuf>)> = BTreeMap::new();
+            for path in sorted_entries(&dir)? {
+                let file_name = path
+                    .file_name()
+                    .map(|name| name.to_string_lossy().into_owned())
+                    .unwrap_or_default();
+                let file_name = file_name.strip_suffix(".gz").unwrap_or(&file_name);
+                if let Some(name) = file_name.strip_suffix(".metadata.json") {
+                    files.entry(unescape_name(name)).or_default().0 = Some(path.clone());
+                } else if let Some(name) = file_name.strip_suffix(".bson") {
+                    files.entry(unescape_name(name)).or_default().1 = Some(path.clone());
+                }
+            }
+
+            for (name, (metadata, documents)) in files {
+                if !self.filter.matches(&database, &name) {
+                    continue;
+                }
+                let meta = match metadata {
+                    Some(path) => {
+                        let mut json = String::new();
+                        open(&path)?
+                            .read_to_string(&mut json)
+                            .map_err(|err| read_error(&path, err))?;
+                        CollectionMeta::from_json(&database, &name, &json)?
+                    }
+                    None => CollectionMeta::plain(&database, &name),
+                };
+                self.create_collection(&meta)?;
+                if let Some(path) = documents.filter(|_| !meta.is_view()) {
+                    let mut reader = open(&path)?;
+                    let mut batch = Vec::new();
+                    loop {
+                        match read_chunk(&mut reader).map_err(|err| read_error(&path, err))? {
+                            Chunk::Document(bytes) => {
+                                batch.push(raw_document(bytes, &path)?);
+                                if batch.len() >= self.options.batch_size.max(1) {
+                                    self.insert(&meta, std::mem::take(&mut batch))?;
+                                }
+                            }
+                            Chunk::Terminator => {
+                                return Err(read_error(
+                                    &path,
+                                    io::Error::new(
+                                        io::ErrorKind::InvalidData,
+                                        "unexpected archive terminator",
+                                    ),
+                                ))
+                            }
+                            Chunk::End => break,
+                        }
+                    }
+                    self.insert(&meta, batch)?;
+                }
+                self.create_indexes(&meta)?;
+                self.finish_collection(&meta);
+            }
+        }
+        Ok(())
+    }
+
+    fn read_archive(&mut self, path: &Path) -> Result<(), Error> {
+        let mut archive = ArchiveReader::new(open(path)?, path);
+        let mut collections = HashMap::new();
+        for metadata in archive.read_prelude()? {
+            let database = metadata.get_str("db").unwrap_or_default();
+            let name = metadata.get_str("collection").unwrap_or_default();
+            if !self.filter.matches(database, name) {
+                continue;
+            }
+            let meta = CollectionMeta::from_json(
+                database,
+                name,
+                metadata.get_str("metadata").unwrap_or("{}"),
+            )?;
+            self.create_collection(&meta)?;
+            collections.insert(meta.namespace(), meta);
+        }
+
+        let mut batches: HashMap<String, Vec<RawDocumentBuf>> = HashMap::new();
+        let mut namespace = String::new();
+        while let Some(item) = archive.next()? {
+            match item {
+                ArchiveItem::Segment(segment) => {
+                    batches.entry(segment.clone()).or_default();
+                    namespace = segment;
+                }
+                ArchiveItem::Document(bytes) => {
+                    // Documents of left out collections are read past.
+                    let (Some(meta), Some(batch)) =
+                        (collections.get(&namespace), batches.get_mut(&namespace))
+                    else {
+                        continue;
+                    };
+                    batch.push(raw_document(bytes, path)?);
+                    if batch.len() >= self.options.batch_size.max(1) {
+                        let batch = std::mem::take(batch);
+                        self.insert(meta, batch)?;
+                    }
+                }
+                ArchiveItem::Eof(namespace) => {
+                    if let Some(meta) = collections.get(&namespace) {
+                        let batch = batches.remove(&namespace).unwrap_or_default();
+                        self.insert(meta, batch)?;
+                        self.create_indexes(meta)?;
+                        self.finish_collection(meta);
+                    }
+                }
+            }
+        }
+        Ok(())
+    }
+
+    fn create_collection(&mut self, meta: &CollectionMeta) -> Result<(), Error> {
+        self.progress.namespace = Some(meta.namespace());
+        let database = self.client.database(&meta.database);
+        if self.options.drop {
+            database.collection::<Document>(&meta.name).drop(None)?;
+        }
+        create_collection(&database, &meta.name, &meta.options)
+    }
+
+    fn insert(&mut self, meta: &CollectionMeta, batch: Vec<RawDocumentBuf>) -> Result<(), Error> {
+        if batch.is_empty() {
+            return Ok(());
+        }
+        let collection = self.client.database(&meta.database).collection(&meta.name);
+        let inserted = import::insert_documents(&collection, batch, false)?;
+        self.progress.failed += inserted.rejected;
+        self.progress.documents += inserted.inserted;
+        (self.on_progress)(&self.progress);
+        Ok(())
+    }
+
+    fn create_indexes(&mut self, meta: &CollectionMeta) -> Result<(), Error> {
+        if !self.options.restore_indexes || meta.is_view() {
+            return Ok(());
+        }
+        create_indexes(
+            &self.client.database(&meta.database),
+            &meta.name,
+            &meta.indexes,
+        )
+    }
+
+    fn finish_collection(&mut self, meta: &CollectionMeta) {
+        self.progress.namespace = Some(meta.namespace());
+        self.progress.collections += 1;
+        (self.on_progress)(&self.progress);
+    }
+}
+
+/// Creates a collection, or a view, with the given options unless it
+/// already exists.
+pub fn create_collection(database: &Database, name: &str, options: &Document) -> Result<(), Error> {
+    let mut command = doc! { "create": name };
+    command.extend(options.clone());
+    match database.run_command(command, None) {
+        Ok(_) => Ok(()),
+        // NamespaceExists: writing into a collection that is there.
+        Err(err) if matches!(err.kind.as_ref(), ErrorKind::Command(err) if err.code == 48) => {
+            Ok(())
+        }
+        Err(err) => Err(err.into()),
+    }
+}
+
+/// Recreates the indexes listed by `listIndexes`, but for the `_id` index
+/// every collection has.
+pub fn create_indexes(database: &Database, name: &str, indexes: &[Document]) -> Result<(), Error> {
+    let indexes: Vec<Document> = indexes
+        .iter()
+        .filter(|index| index.get_str("name") != Ok("_id_"))
+        .map(|index| {
+            let mut index = index.clone();
+            index.remove("ns");
+            index
+        })
+        .collect();
+    if indexes.is_empty() {
+        return Ok(());
+    }
+    database.run_command(doc! { "createIndexes": name, "indexes": indexes }, None)?;
+    Ok(())
+}
+
+fn raw_document(bytes: Vec<u8>, path: &Path) -> Result<RawDocumentBuf, Error> {
+    RawDocumentBuf::from_bytes(bytes)
+        .map_err(|err| Error::Storage(format!("invalid BSON in {}: {}", path.display(), err)))
+}
+
+fn sorted_entries(dir: &Path) -> Result<Vec<PathBuf>, Error> {
+    let mut entries = fs::read_dir(dir)
+        .and_then(|entries| {
+            entries
+                .map(|entry| entry.map(|entry| entry.path()))
+                .collect::<io::Result<Vec<_>>>()
+        })
+        .map_err(|err| read_error(dir, err))?;
+    entries.sort();
+    Ok(entries)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn crc64_matches_the_check_value() {
+        assert_eq!(crc64(0, b"123456789"), 0x995d_c9bb_df19_39fa);
+        assert_eq!(crc64(crc64(0, b"1234"), b"56789"), 0x995d_c9bb_df19_39fa);
+    }
+
+    #[test]
+    fn matches_wildcards() {
+        assert!(wildcard_match("db.users", "db.users"));
+        assert!(wildcard_match("db.*", "db.users"));
+        assert!(wildcard_match("*.users", "db.users"));
+        assert!(wildcard_match("db.u*s", "db.users"));
+        assert!(wildcard_match("a*b*c", "abc"));
+        assert!(wildcard_match("*", ""));
+        assert!(!wildcard_match("db", "db.users"));
+        assert!(!wildcard_match("db.u*s", "db.user"));
+        assert!(!wildcard_match("a*a", "a"));
+    }
+
+    #[test]
+    fn escapes_collection_names() {
+        assert_eq!(escape_name("a/b%c"), "a%2Fb%25c");
+        assert_eq!(unescape_name("a%2Fb%25c"), "a/b%c");
+        assert_eq!(unescape_name(&escape_name("%2F/%")), "%2F/%");
+        assert_eq!(unescape_name("100%"), "100%");
+        assert_eq!(unescape_name("%zz"), "%zz");
+    }
+
+    #[test]
+    fn rejects_oversized_documents() {
+        let mut bytes: &[u8] = &u32::MAX.wrapping_sub(1).to_le_bytes();
+        let err = read_chunk(&mut bytes).err().unwrap();
+        assert_eq!(err.kind(), io::ErrorKind::InvalidData);
+        let mut bytes: &[u8] = &4_u32.to_le_bytes();
+        assert!(read_chunk(&mut bytes).is_err());
+    }
+
+    fn archive(collections: &[CollectionMeta], documents: &[Document]) -> Vec<u8> {
+        let mut bytes = Vec::new();
+        let mut archive = ArchiveWriter { output: &mut bytes };
+        archive.write_prelude("7.0.0", collections).unwrap();
+        for meta in collections {
+            archive
+                .write_collection(meta, |write| {
+                    for document in documents {
+                        write(&mongodb::bson::to_vec(document).unwrap())?;
+                    }
+                    Ok(())
+                })
+                .unwrap();
+        }
+        bytes
+    }
+
+    fn view(database: &str, name: &str) -> CollectionMeta {
+        CollectionMeta {
+            kind: "view".to_string(),
+            options: doc! { "viewOn": "users", "pipeline": [] },
+            ..CollectionMeta::plain(database, name)
+        }
+    }
+
+    #[test]
+    fn reads_back_an_archive() {
+        let documents = [doc! { "_id": 1 }, doc! { "_id": 2, "name": "b" }];
+        let bytes = archive(
+            &[CollectionMeta::plain("db", "users"), view("db", "names")],
+            &documents,
+        );
+        let path = Path::new("dump.archive");
+        let mut reader = ArchiveReader::new(bytes.as_slice(), path);
+
+        let prelude = reader.read_prelude().unwrap();
+        let collections: Vec<_> = prelude
+            .iter()
+            .map(|metadata| {
+                CollectionMeta::from_json(
+                    metadata.get_str("db").unwrap(),
+                    metadata.get_str("collection").unwrap(),
+                    metadata.get_str("metadata").unwrap(),
+                )
+                .unwrap()
+            })
+            .collect();
+        assert_eq!(collections.len(), 2);
+        assert_eq!(collections[0].namespace(), "db.users");
+        assert!(!collections[0].is_view());
+        assert_eq!(collections[1].namespace(), "db.names");
+        assert!(collections[1].is_view());
+        assert_eq!(
+            collections[1].options,
+            doc! { "viewOn": "users", "pipeline": [] }
+        );
+
+        let mut read = Vec::new();
+        while let Some(item) = reader.next().unwrap() {
+            read.push(match item {
+                ArchiveItem::Segment(namespace) => format!("segment {}", namespace),
+                ArchiveItem::Document(bytes) => parse_document(&bytes, path).unwrap().to_string(),
+                ArchiveItem::Eof(namespace) => format!("eof {}", namespace),
+            });
+        }
+        assert_eq!(
+            read,
+            [
+                "segment db.users".to_string(),
+                documents[0].to_string(),
+                documents[1].to_string(),
+                "eof db.users".to_string(),
+                "eof db.names".to_string(),
+            ]
+        );
+    }
+
+    #[test]
+    fn rejects_an_archive_with_a_bad_checksum() {
+        let mut bytes = archive(&[CollectionMeta::plain("db", "users")], &[doc! { "n": 1 }]);
+        let document = mongodb::bson::to_vec(&doc! { "n": 1 }).unwrap();
+        let at = bytes
+            .windows(document.len())
+            .position(|window| window == document)
+            .unwrap();
+        // The low byte of `n`, followed by three more and the end of the
+        // document.
+        bytes[at + document.len() - 5] = 2;
+        let mut reader = ArchiveReader::new(bytes.as_slice(), Path::new("dump.archive"));
+        reader.read_prelude().unwrap();
+        let err = loop {
+            match reader.next() {
+                Ok(Some(_)) => continue,
+                Ok(None) => panic!("the checksum was not checked"),
+                Err(err) => break err,
+            }
+        };
+        assert!(err.to_string().contains("checksum mismatch for db.users"));
+    }
+}
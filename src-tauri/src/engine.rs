@@ -1,29 +1,61 @@
 use boa_engine::{
-    builtins::{date::Date, object::OrdinaryObject, typed_array::TypedArray},
+    builtins::{
+        date::Date, object::OrdinaryObject, promise::PromiseState, typed_array::TypedArray,
+    },
     class::{Class, ClassBuilder},
     error::JsNativeError,
     js_string,
-    native_function::NativeFunction,
+    native_function::{NativeFunction, NativeFunctionPointer},
     object::{
-        builtins::{JsArray, JsArrayBuffer, JsDate, JsUint8Array},
+        builtins::{JsArray, JsArrayBuffer, JsDate, JsPromise, JsUint8Array},
         ObjectInitializer,
     },
-    Context, JsData, JsObject, JsResult, JsString, JsValue,
+    property::{Attribute, PropertyDescriptor},
+    Context, JsBigInt, JsData, JsError, JsObject, JsResult, JsString, JsValue, Module, Script,
+    Source,
 };
 
 pub mod bson;
+pub mod loader;
+pub mod params;
 
 use mongodb::bson::{doc, Bson};
 
 use boa_gc::{Finalize, Trace};
 use mongodb::bson::Document;
 
-use crate::{engine::bson::JsObjectId, CLIENTS};
+use crate::{client::CLIENTS, engine::bson::JsObjectId, error::mongo_error_code};
 
 #[derive(Debug, JsData, Trace, Finalize)]
 pub struct Db {
     name: String,
     client_id: String,
+    /// When set, collection methods return promises instead of plain values.
+    promises: bool,
+}
+
+impl Db {
+    /// Defines the `db` global a script runs against.
+    pub fn register(
+        name: &str,
+        client_id: &str,
+        promises: bool,
+        context: &mut Context,
+    ) -> JsResult<()> {
+        let db = Db::from_data(
+            Db {
+                name: name.to_string(),
+                client_id: client_id.to_string(),
+                promises,
+            },
+            context,
+        )?;
+        context.register_global_property(
+            js_string!("db"),
+            db,
+            Attribute::READONLY | Attribute::NON_ENUMERABLE | Attribute::PERMANENT,
+        )
+    }
 }
 
 impl Class for Db {
@@ -49,7 +81,18 @@ impl Class for Db {
             .to_std_string()
             .map_err(|err| JsNativeError::typ().with_message(err.to_string()))?;
 
-        Ok(Self { name, client_id })
+        let promises = match args.get(2) {
+            Some(JsValue::Object(options)) => {
+                options.get(js_string!("promises"), context)?.to_boolean()
+            }
+            _ => false,
+        };
+
+        Ok(Self {
+            name,
+            client_id,
+            promises,
+        })
     }
 
     /// Here is where the class is initialized.
@@ -86,12 +129,23 @@ pub struct Collection {
     db: JsObject<Db>,
 }
 
+/// The largest integer a JavaScript number holds exactly, 2^53 - 1.
+const MAX_SAFE_INTEGER: i64 = (1 << 53) - 1;
+
+/// Int64 values are numbers when they fit one exactly, so that they mix with
+/// other numbers in arithmetic, and BigInts past that so no digit is lost.
+fn int64_to_js(n: i64) -> JsValue {
+    match n.unsigned_abs() <= MAX_SAFE_INTEGER as u64 {
+        true => JsValue::from(n),
+        false => JsBigInt::from(n).into(),
+    }
+}
+
 fn bson_to_js(bson_doc: Bson, context: &mut Context) -> JsValue {
-    println!("doc_to_js: {:?}", bson_doc);
     let js_value: JsValue = match bson_doc {
         Bson::String(s) => JsString::from(s).into(),
         Bson::Int32(i) => JsValue::from(i),
-        Bson::Int64(i) => JsValue::from(i),
+        Bson::Int64(i) => int64_to_js(i),
         Bson::Double(d) => JsValue::from(d),
         Bson::Array(a) => {
             let js_array = JsArray::new(context);
@@ -158,7 +212,9 @@ pub fn js_to_bson(js_value: JsValue, context: &mut Context) -> JsResult<Bson> {
         JsValue::Integer(n) => Bson::Int32(n),
         JsValue::Boolean(b) => Bson::Boolean(b),
         JsValue::Rational(d) => Bson::Double(d),
-        JsValue::BigInt(v) => Bson::Int64(v.to_f64() as i64),
+        JsValue::BigInt(v) => Bson::Int64(v.to_string().parse().map_err(|_| {
+            JsNativeError::range().with_message(format!("{}n doesn't fit in an Int64", v))
+        })?),
         JsValue::Object(obj) => {
             if obj.is_array() {
                 let arr = JsArray::from_object(obj)?;
@@ -254,11 +310,11 @@ impl Collection {
             .database(&db.name)
             .collection::<Document>(collection.name.as_str())
             .find(args, None)
-            .map_err(|err| JsNativeError::error().with_message(err.to_string()))?;
+            .map_err(|err| mongo_error(err, context))?;
 
         let mut data = Vec::new();
         while let Some(el) = cursor.next() {
-            let el = el.map_err(|err| JsNativeError::error().with_message(err.to_string()))?;
+            let el = el.map_err(|err| mongo_error(err, context))?;
             data.push(el);
         }
         let bson_data = Bson::from(data);
@@ -297,7 +353,7 @@ impl Collection {
             .database(&db.name)
             .collection::<Document>(collection.name.as_str())
             .find_one(args, None)
-            .map_err(|err| JsNativeError::error().with_message(err.to_string()))?;
+            .map_err(|err| mongo_error(err, context))?;
 
         if res.is_none() {
             return Ok(JsValue::null());
@@ -331,13 +387,18 @@ impl Collection {
             .iter()
             .find(|client| client.id == db.client_id)
             .ok_or(JsNativeError::error().with_message("client not intialized"))?;
+        if entry.profile.read_only {
+            return Err(JsNativeError::error()
+                .with_message("connection is read-only")
+                .into());
+        }
 
         let res = entry
             .client
             .database(&db.name)
             .collection::<Document>(collection.name.as_str())
             .insert_one(args, None)
-            .map_err(|err| JsNativeError::error().with_message(err.to_string()))?;
+            .map_err(|err| mongo_error(err, context))?;
 
         let inserted = doc! {
             "acknowledged" : true,
@@ -379,13 +440,18 @@ impl Collection {
             .iter()
             .find(|client| client.id == db.client_id)
             .ok_or(JsNativeError::error().with_message("client not intialized"))?;
+        if entry.profile.read_only {
+            return Err(JsNativeError::error()
+                .with_message("connection is read-only")
+                .into());
+        }
 
         let res = entry
             .client
             .database(&db.name)
             .collection::<Document>(collection.name.as_str())
             .insert_many(args, None)
-            .map_err(|err| JsNativeError::error().with_message(err.to_string()))?;
+            .map_err(|err| mongo_error(err, context))?;
 
         let inserted_ids = res
             .inserted_ids
@@ -403,6 +469,38 @@ impl Collection {
     }
 }
 
+/// Every method of `Collection`, all of which talk to the server and so go
+/// through [`promisable`].
+const COLLECTION_METHODS: [(&str, usize, NativeFunctionPointer); 4] = [
+    ("find", 1, Collection::find),
+    ("findOne", 1, Collection::find_one),
+    ("insertOne", 1, Collection::insert_one),
+    ("insertMany", 1, Collection::insert_many),
+];
+
+/// Wraps a collection method so that its outcome is settled into a promise when
+/// the collection's `Db` was created with `{ promises: true }`.
+fn promisable(method: NativeFunctionPointer) -> NativeFunction {
+    NativeFunction::from_copy_closure(move |this, args, context| {
+        let result = method(this, args, context).map_err(|err| with_stack(err, context));
+
+        let promises = this
+            .as_object()
+            .and_then(|obj| obj.downcast_ref::<Collection>())
+            .map(|collection| collection.db.borrow().data().promises)
+            .unwrap_or(false);
+        if !promises {
+            return result;
+        }
+
+        let promise = match result {
+            Ok(value) => JsPromise::resolve(value, context),
+            Err(err) => JsPromise::reject(err, context),
+        };
+        Ok(promise.into())
+    })
+}
+
 impl Class for Collection {
     const NAME: &'static str = "Collection";
     const LENGTH: usize = 1;
@@ -433,30 +531,430 @@ impl Class for Collection {
     }
 
     fn init(class: &mut ClassBuilder<'_>) -> JsResult<()> {
-        class.method(
-            js_string!("find"),
-            1,
-            NativeFunction::from_fn_ptr(Self::find),
-        );
+        for (name, length, method) in COLLECTION_METHODS {
+            class.method(JsString::from(name), length, promisable(method));
+        }
 
-        class.method(
-            js_string!("findOne"),
-            1,
-            NativeFunction::from_fn_ptr(Self::find_one),
-        );
+        return Ok(());
+    }
+}
 
-        class.method(
-            js_string!("insertOne"),
-            1,
-            NativeFunction::from_fn_ptr(Self::insert_one),
-        );
+/// Function names of the frames currently on the JS call stack, innermost first.
+fn stack_trace(context: &Context) -> String {
+    context
+        .stack_trace()
+        .map(|frame| {
+            let name = frame.code_block().name().to_std_string_escaped();
+            if name.is_empty() {
+                "    at <anonymous>".to_string()
+            } else {
+                format!("    at {}", name)
+            }
+        })
+        .collect::<Vec<_>>()
+        .join("\n")
+}
 
-        class.method(
-            js_string!("insertMany"),
-            1,
-            NativeFunction::from_fn_ptr(Self::insert_many),
-        );
+/// Converts a driver error into a JS error object carrying the server's
+/// `code`, `codeName` and `errorLabels`, so scripts can inspect them in a
+/// `catch` and `exec_script` can report them.
+fn mongo_error(err: mongodb::error::Error, context: &mut Context) -> JsError {
+    let (code, code_name) = mongo_error_code(&err);
+    let labels = err
+        .labels()
+        .iter()
+        .map(|label| JsValue::from(js_string!(label.as_str())))
+        .collect::<Vec<_>>();
+    let name = if code.is_some() {
+        "MongoServerError"
+    } else {
+        "MongoError"
+    };
 
-        return Ok(());
+    let error = JsNativeError::error()
+        .with_message(err.kind.to_string())
+        .to_opaque(context);
+    let properties = [
+        (js_string!("name"), js_string!(name).into()),
+        (
+            js_string!("code"),
+            code.map_or(JsValue::Undefined, JsValue::from),
+        ),
+        (
+            js_string!("codeName"),
+            code_name.map_or(JsValue::Undefined, |name| js_string!(name).into()),
+        ),
+        (
+            js_string!("errorLabels"),
+            JsArray::from_iter(labels, context).into(),
+        ),
+    ];
+    for (key, value) in properties {
+        set_hidden(&error, key, value);
+    }
+
+    JsError::from_opaque(error.into())
+}
+
+/// Gives a thrown error object the JS call stack as its `stack`, unless it has
+/// one already. Called while the native function that failed still has its
+/// callers on the stack, since they are gone once the error reaches
+/// [`ScriptError::new`].
+pub(crate) fn with_stack(err: JsError, context: &mut Context) -> JsError {
+    let thrown = err.to_opaque(context);
+    if let Some(object) = thrown.as_object() {
+        if let Ok(JsValue::Undefined) = object.get(js_string!("stack"), context) {
+            set_hidden(
+                object,
+                js_string!("stack"),
+                js_string!(stack_trace(context)).into(),
+            );
+        }
+    }
+    JsError::from_opaque(thrown)
+}
+
+/// Defines a writable, non-enumerable property, as error objects have their
+/// `message`. Defining it outright cannot fail, unlike `set`.
+fn set_hidden(object: &JsObject, key: JsString, value: JsValue) {
+    object.insert_property(
+        key,
+        PropertyDescriptor::builder()
+            .value(value)
+            .writable(true)
+            .enumerable(false)
+            .configurable(true),
+    );
+}
+
+/// A script failure in a shape the editor can display.
+#[derive(thiserror::Error, Debug, serde::Serialize)]
+#[error("{kind}: {message}")]
+#[serde(rename_all = "camelCase")]
+pub struct ScriptError {
+    /// The error's `name`, e.g. `SyntaxError` or `MongoServerError`.
+    pub kind: String,
+    pub message: String,
+    /// 1-based position in the user's source, when the error message carries
+    /// one. boa 0.18 keeps no source positions in its call frames, so errors
+    /// thrown at runtime have none and `stack` names the calling functions
+    /// instead.
+    pub line: Option<u32>,
+    pub column: Option<u32>,
+    pub stack: Option<String>,
+    /// Set when the error came from the MongoDB driver.
+    pub code: Option<i32>,
+    pub code_name: Option<String>,
+    pub labels: Vec<String>,
+}
+
+impl ScriptError {
+    /// Builds a `ScriptError` from a thrown value. `line_offset` is the number
+    /// of lines injected in front of the user's source.
+    pub fn new(err: JsError, line_offset: u32, context: &mut Context) -> Self {
+        let thrown = err.to_opaque(context);
+        let Some(object) = thrown.as_object() else {
+            return Self {
+                kind: "Error".to_string(),
+                message: thrown
+                    .to_string(context)
+                    .map(|s| s.to_std_string_escaped())
+                    .unwrap_or_else(|_| thrown.display().to_string()),
+                line: None,
+                column: None,
+                stack: None,
+                code: None,
+                code_name: None,
+                labels: Vec::new(),
+            };
+        };
+
+        let get_string = |key: JsString, context: &mut Context| -> Option<String> {
+            match object.get(key, context) {
+                Ok(JsValue::String(s)) => Some(s.to_std_string_escaped()),
+                _ => None,
+            }
+        };
+        let kind = get_string(js_string!("name"), context).unwrap_or_else(|| "Error".to_string());
+        let mut message = get_string(js_string!("message"), context).unwrap_or_default();
+        let stack = get_string(js_string!("stack"), context)
+            .or_else(|| Some(stack_trace(context)))
+            .filter(|s| !s.is_empty());
+        let code_name = get_string(js_string!("codeName"), context);
+
+        let code = match object.get(js_string!("code"), context) {
+            Ok(JsValue::Integer(code)) => Some(code),
+            Ok(JsValue::Rational(code)) => Some(code as i32),
+            _ => None,
+        };
+        let labels = object
+            .get(js_string!("errorLabels"), context)
+            .ok()
+            .and_then(|labels| js_to_bson(labels, context).ok())
+            .and_then(|labels| match labels {
+                Bson::Array(labels) => Some(labels),
+                _ => None,
+            })
+            .unwrap_or_default()
+            .into_iter()
+            .filter_map(|label| label.as_str().map(str::to_string))
+            .collect();
+
+        // boa appends the position to the message, e.g.
+        // "unexpected token ')' at line 3, col 7".
+        let mut line = None;
+        let mut column = None;
+        if let Some(idx) = message.rfind(" at line ") {
+            let position = message[idx + " at line ".len()..]
+                .split_once(", col ")
+                .and_then(|(l, c)| Some((l.parse::<u32>().ok()?, c.parse::<u32>().ok()?)));
+            if let Some((l, c)) = position {
+                line = Some(l.saturating_sub(line_offset).max(1));
+                column = Some(c);
+                message.truncate(idx);
+            }
+        }
+
+        Self {
+            kind,
+            message,
+            line,
+            column,
+            stack,
+            code,
+            code_name,
+            labels,
+        }
+    }
+}
+
+/// Directive that switches a script to async mode when it is the first
+/// statement, e.g. `"use async";`.
+const ASYNC_DIRECTIVE: &str = "use async";
+
+/// A parsed user script.
+pub struct UserScript {
+    code: Code,
+    line_offset: u32,
+    /// The script opted into async mode with [`ASYNC_DIRECTIVE`]: it runs
+    /// inside an async function, so its result is whatever it returns rather
+    /// than its last expression, and collection methods return promises.
+    pub promises: bool,
+}
+
+enum Code {
+    Script(Script),
+    /// A script with `import` or `export` declarations. Its result is the
+    /// default export.
+    Module(Module),
+}
+
+impl UserScript {
+    pub fn parse(source: &str, context: &mut Context) -> Result<Self, ScriptError> {
+        // Top-level `await` and `return` need the script to run inside an
+        // async function, which changes both its result and what collection
+        // methods return, so it only happens when asked for.
+        let promises = has_directive(source, ASYNC_DIRECTIVE);
+        let wrapped = format!("(async () => {{\n{}\n}})()", source);
+        if !promises {
+            let err = match Script::parse(Source::from_bytes(source), None, context) {
+                Ok(script) => {
+                    return Ok(Self {
+                        code: Code::Script(script),
+                        line_offset: 0,
+                        promises,
+                    })
+                }
+                Err(err) => err,
+            };
+            // Checked before trying a module, which would accept top-level
+            // `await` as well and drop the script's value.
+            if Script::parse(Source::from_bytes(wrapped.as_str()), None, context).is_ok() {
+                let mut err = ScriptError::new(err, 0, context);
+                err.message = format!(
+                    "{} (top-level await and return need \"{}\"; as the first line)",
+                    err.message, ASYNC_DIRECTIVE
+                );
+                return Err(err);
+            }
+            if let Ok(module) = Module::parse(Source::from_bytes(source), None, context) {
+                return Ok(Self {
+                    code: Code::Module(module),
+                    line_offset: 0,
+                    promises,
+                });
+            }
+            return Err(ScriptError::new(err, 0, context));
+        }
+
+        // If the wrapped source fails to parse its error is the more useful
+        // one, since it points past any `await`, unless the source parses as
+        // a module.
+        let err = match Script::parse(Source::from_bytes(wrapped.as_str()), None, context) {
+            Ok(script) => {
+                return Ok(Self {
+                    code: Code::Script(script),
+                    line_offset: 1,
+                    promises,
+                })
+            }
+            Err(err) => err,
+        };
+        if let Ok(module) = Module::parse(Source::from_bytes(source), None, context) {
+            return Ok(Self {
+                code: Code::Module(module),
+                line_offset: 0,
+                promises,
+            });
+        }
+
+        let mut err = ScriptError::new(err, 1, context);
+        // An unexpected end of input is reported on the wrapper's closing
+        // line, move it to the end of the user's source.
+        let last_line = source.lines().count().max(1) as u32;
+        if err.line.is_some_and(|line| line > last_line) {
+            let width = source.lines().last().map_or(0, |l| l.chars().count());
+            err.line = Some(last_line);
+            err.column = Some(width as u32 + 1);
+        }
+        Err(err)
+    }
+
+    /// Evaluates the script, drains the job queue and unwraps the completion
+    /// value if it is a promise.
+    pub fn evaluate(&self, context: &mut Context) -> Result<JsValue, ScriptError> {
+        self.evaluate_inner(context)
+            .map_err(|err| ScriptError::new(err, self.line_offset, context))
+    }
+
+    fn evaluate_inner(&self, context: &mut Context) -> JsResult<JsValue> {
+        let value = match &self.code {
+            Code::Script(script) => script.evaluate(context)?,
+            Code::Module(module) => {
+                let evaluated = module.load_link_evaluate(context);
+                context.run_jobs();
+                settle(evaluated)?;
+                let namespace = module.namespace(context);
+                return namespace.get(js_string!("default"), context);
+            }
+        };
+        context.run_jobs();
+
+        let promise = match value
+            .as_object()
+            .and_then(|obj| JsPromise::from_object(obj.clone()).ok())
+        {
+            Some(promise) => promise,
+            None => return Ok(value),
+        };
+        settle(promise)
+    }
+}
+
+fn settle(promise: JsPromise) -> JsResult<JsValue> {
+    match promise.state() {
+        PromiseState::Fulfilled(value) => Ok(value),
+        PromiseState::Rejected(reason) => Err(JsError::from_opaque(reason)),
+        PromiseState::Pending => Err(JsNativeError::error()
+            .with_message("script finished with an unsettled promise")
+            .into()),
+    }
+}
+
+/// Whether the first statement of `source`, past any comments, is the string
+/// `directive`.
+fn has_directive(source: &str, directive: &str) -> bool {
+    let mut rest = source.trim_start();
+    loop {
+        if let Some(comment) = rest.strip_prefix("//") {
+            rest = comment.split_once('\n').map_or("", |(_, rest)| rest);
+        } else if let Some(comment) = rest.strip_prefix("/*") {
+            rest = comment.split_once("*/").map_or("", |(_, rest)| rest);
+        } else {
+            break;
+        }
+        rest = rest.trim_start();
+    }
+    ['"', '\''].into_iter().any(|quote| {
+        rest.strip_prefix(quote)
+            .and_then(|rest| rest.strip_prefix(directive))
+            .and_then(|rest| rest.strip_prefix(quote))
+            .map(|rest| rest.trim_start_matches([' ', '\t']))
+            .is_some_and(|rest| rest.is_empty() || rest.starts_with([';', '\n', '\r']))
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn run(source: &str) -> Result<(UserScript, JsValue), ScriptError> {
+        let mut context = Context::default();
+        let script = UserScript::parse(source, &mut context)?;
+        let value = script.evaluate(&mut context)?;
+        Ok((script, value))
+    }
+
+    #[test]
+    fn directive_is_found_past_comments() {
+        assert!(has_directive("\"use async\";\nx", ASYNC_DIRECTIVE));
+        assert!(has_directive(
+            "// setup\n/* a\nb */ 'use async'\nx",
+            ASYNC_DIRECTIVE
+        ));
+        assert!(!has_directive("x;\n\"use async\";", ASYNC_DIRECTIVE));
+        assert!(!has_directive("\"use async\" + 1", ASYNC_DIRECTIVE));
+        assert!(!has_directive("\"use asynchronous\"", ASYNC_DIRECTIVE));
+    }
+
+    #[test]
+    fn db_names_are_not_script_source() {
+        let mut context = Context::default();
+        context.register_global_class::<Db>().unwrap();
+        Db::register("a'); throw 1; ('", "client", false, &mut context).unwrap();
+        let script = UserScript::parse("db instanceof Db", &mut context).unwrap();
+        let value = script.evaluate(&mut context).unwrap();
+        assert_eq!(value, JsValue::from(true));
+    }
+
+    #[test]
+    fn plain_scripts_keep_their_completion_value() {
+        let (script, value) = run("const x = 20;\nx + 22").unwrap();
+        assert!(!script.promises);
+        assert_eq!(value, JsValue::from(42));
+    }
+
+    #[test]
+    fn top_level_await_needs_the_directive() {
+        let err = run("const x = await Promise.resolve(42);\nx")
+            .err()
+            .unwrap();
+        assert_eq!(err.kind, "SyntaxError");
+        assert!(err.message.contains("\"use async\""), "{}", err.message);
+
+        let (script, value) =
+            run("\"use async\";\nconst x = await Promise.resolve(42);\nreturn x;").unwrap();
+        assert!(script.promises);
+        assert_eq!(value, JsValue::from(42));
+    }
+
+    #[test]
+    fn native_errors_carry_the_js_stack() {
+        let mut context = Context::default();
+        context.register_global_class::<Db>().unwrap();
+        context.register_global_class::<Collection>().unwrap();
+        let source = "function lookup() {\n  return new Db('test', 'missing').getCollection('c').find({});\n}\nlookup()";
+        let script = UserScript::parse(source, &mut context).unwrap();
+        let err = script.evaluate(&mut context).err().unwrap();
+        assert_eq!(err.message, "client not intialized");
+        assert!(err.stack.unwrap().contains("at lookup"));
+        assert_eq!(err.line, None);
+    }
+
+    #[test]
+    fn async_errors_point_into_the_users_source() {
+        let err = run("\"use async\";\nawait 1;\nconst = 2;").err().unwrap();
+        assert_eq!(err.kind, "SyntaxError");
+        assert_eq!(err.line, Some(3));
     }
 }
@@ -41,7 +41,6 @@ impl JsObjectId {
         _args: &[JsValue],
         _context: &mut boa_engine::Context,
     ) -> boa_engine::JsResult<JsValue> {
-        println!("to string called");
         if let Some(object) = this.as_object() {
             if let Some(oid) = object.downcast_ref::<JsObjectId>() {
                 let s = JsString::from_str(oid.0.to_string().as_str())
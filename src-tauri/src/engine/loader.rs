@@ -0,0 +1,406 @@
+use std::{
+    cell::RefCell,
+    collections::HashMap,
+    path::{Path, PathBuf},
+    rc::Rc,
+    sync::{Arc, Mutex, PoisonError},
+    time::SystemTime,
+};
+
+use boa_engine::{
+    js_string,
+    module::{ModuleLoader, Referrer},
+    Context, JsArgs, JsNativeError, JsResult, JsString, JsValue, Module, NativeFunction, Script,
+    Source,
+};
+use boa_gc::{Finalize, Trace};
+
+use crate::db;
+
+/// Prefix of the keys of saved scripts, which have no path.
+const SAVED_PREFIX: &str = "saved:";
+
+/// Where a module or a `load()`ed script comes from.
+enum Location {
+    File(PathBuf),
+    /// The body of a saved script.
+    Saved(String),
+}
+
+/// The files the scripts of a connection imported or loaded, kept between
+/// runs. Modules can't outlive the context they were parsed in, so each run
+/// parses them again, but a file is only read again once it changed.
+#[derive(Default)]
+pub struct SourceCache {
+    /// The text of each file, with the modification time and length it was
+    /// read at.
+    files: HashMap<PathBuf, (SystemTime, u64, String)>,
+}
+
+/// Finds the helpers a script pulls in with `import` or `load()`.
+///
+/// A specifier is looked up as a file first, relative to the file that
+/// imports it or, failing that, the scripts directory, with or without a
+/// `.js` extension. Otherwise it names a saved script. Every module and
+/// script is parsed once per run, and an import or load that leads back to
+/// itself is an error rather than a half-initialised module.
+pub struct ScriptLoader {
+    scripts_dir: Option<PathBuf>,
+    sources: Arc<Mutex<SourceCache>>,
+    modules: RefCell<HashMap<String, Module>>,
+    module_keys: RefCell<HashMap<Module, String>>,
+    /// The imports of each module, by key.
+    imports: RefCell<HashMap<String, Vec<String>>>,
+    scripts: RefCell<HashMap<String, Script>>,
+    /// Keys of the scripts `load()` is running, innermost last.
+    loading: RefCell<Vec<String>>,
+}
+
+impl ScriptLoader {
+    pub fn new(scripts_dir: Option<PathBuf>, sources: Arc<Mutex<SourceCache>>) -> Self {
+        Self {
+            scripts_dir,
+            sources,
+            modules: RefCell::default(),
+            module_keys: RefCell::default(),
+            imports: RefCell::default(),
+            scripts: RefCell::default(),
+            loading: RefCell::default(),
+        }
+    }
+
+    /// Resolves `specifier` as seen from the script or module `base`.
+    fn resolve(&self, specifier: &str, base: Option<&str>) -> JsResult<(String, Location)> {
+        let base_dir = base
+            .filter(|key| !key.starts_with(SAVED_PREFIX))
+            .and_then(|key| Path::new(key).parent())
+            .or(self.scripts_dir.as_deref());
+        let path = Path::new(specifier);
+        let path = match (path.is_absolute(), base_dir) {
+            (true, _) => Some(path.to_path_buf()),
+            (false, Some(dir)) => Some(dir.join(path)),
+            (false, None) => None,
+        };
+        if let Some(path) = path {
+            let with_extension = path
+                .extension()
+                .is_none()
+                .then(|| path.with_extension("js"));
+            for candidate in std::iter::once(path).chain(with_extension) {
+                if candidate.is_file() {
+                    let candidate = candidate.canonicalize().map_err(|err| {
+                        JsNativeError::error()
+                            .with_message(format!("failed to resolve '{}': {}", specifier, err))
+                    })?;
+                    return Ok((
+                        candidate.to_string_lossy().into_owned(),
+                        Location::File(candidate),
+                    ));
+                }
+            }
+        }
+
+        let name = specifier.strip_suffix(".js").unwrap_or(specifier);
+        for name in [specifier, name] {
+            let saved = db::find_saved_script(name)
+                .map_err(|err| JsNativeError::error().with_message(err.to_string()))?;
+            if let Some(saved) = saved {
+                return Ok((
+                    format!("{}{}", SAVED_PREFIX, saved.name),
+                    Location::Saved(saved.body),
+                ));
+            }
+        }
+        Err(JsNativeError::error()
+            .with_message(format!(
+                "cannot find '{}' in the scripts directory or the saved scripts",
+                specifier
+            ))
+            .into())
+    }
+
+    fn read(&self, key: &str, location: Location) -> JsResult<String> {
+        let path = match location {
+            Location::File(path) => path,
+            Location::Saved(body) => return Ok(body),
+        };
+        let failed = |err: std::io::Error| {
+            JsNativeError::error().with_message(format!("failed to read {}: {}", key, err))
+        };
+        let metadata = std::fs::metadata(&path).map_err(failed)?;
+        let modified = metadata.modified().map_err(failed)?;
+
+        // A cache left behind by a panic is still usable.
+        let mut sources = self.sources.lock().unwrap_or_else(PoisonError::into_inner);
+        if let Some((at, len, text)) = sources.files.get(&path) {
+            if *at == modified && *len == metadata.len() {
+                return Ok(text.clone());
+            }
+        }
+        let text = std::fs::read_to_string(&path).map_err(failed)?;
+        sources
+            .files
+            .insert(path, (modified, metadata.len(), text.clone()));
+        Ok(text)
+    }
+
+    /// Records that `from` imports `to`, unless that closes a cycle.
+    fn add_import(&self, from: &str, to: &str) -> JsResult<()> {
+        if let Some(mut path) = self.import_path(to, from) {
+            path.push(to.to_string());
+            return Err(cycle_error("import", &path));
+        }
+        self.imports
+            .borrow_mut()
+            .entry(from.to_string())
+            .or_default()
+            .push(to.to_string());
+        Ok(())
+    }
+
+    /// The chain of imports leading from `from` to `to`, if there is one.
+    fn import_path(&self, from: &str, to: &str) -> Option<Vec<String>> {
+        if from == to {
+            return Some(vec![from.to_string()]);
+        }
+        let imports = self.imports.borrow().get(from).cloned().unwrap_or_default();
+        imports.iter().find_map(|next| {
+            let mut path = self.import_path(next, to)?;
+            path.insert(0, from.to_string());
+            Some(path)
+        })
+    }
+
+    fn load_module(
+        &self,
+        referrer: &Referrer,
+        specifier: &str,
+        context: &mut Context,
+    ) -> JsResult<Module> {
+        let base = match referrer {
+            Referrer::Module(module) => self.module_keys.borrow().get(module).cloned(),
+            // A dynamic `import()` from a script.
+            _ => self.loading.borrow().last().cloned(),
+        };
+        let (key, location) = self.resolve(specifier, base.as_deref())?;
+        if let Some(base) = &base {
+            self.add_import(base, &key)?;
+        }
+        if let Some(module) = self.modules.borrow().get(&key) {
+            return Ok(module.clone());
+        }
+
+        let source = self.read(&key, location)?;
+        let module =
+            Module::parse(Source::from_bytes(source.as_bytes()), None, context).map_err(|err| {
+                JsNativeError::syntax()
+                    .with_message(format!("failed to parse {}", key))
+                    .with_cause(err)
+            })?;
+        self.modules
+            .borrow_mut()
+            .insert(key.clone(), module.clone());
+        self.module_keys.borrow_mut().insert(module.clone(), key);
+        Ok(module)
+    }
+
+    /// Runs a script in the global scope, like the shell's `load()`.
+    fn load(&self, specifier: &str, context: &mut Context) -> JsResult<()> {
+        let base = self.loading.borrow().last().cloned();
+        let (key, location) = self.resolve(specifier, base.as_deref())?;
+        if let Some(start) = self.loading.borrow().iter().position(|k| *k == key) {
+            let mut path = self.loading.borrow()[start..].to_vec();
+            path.push(key);
+            return Err(cycle_error("load", &path));
+        }
+
+        let cached = self.scripts.borrow().get(&key).cloned();
+        let script = match cached {
+            Some(script) => script,
+            None => {
+                let source = self.read(&key, location)?;
+                let script = Script::parse(Source::from_bytes(source.as_bytes()), None, context)
+                    .map_err(|err| {
+                        JsNativeError::syntax()
+                            .with_message(format!("failed to parse {}", key))
+                            .with_cause(err)
+                    })?;
+                self.scripts
+                    .borrow_mut()
+                    .insert(key.clone(), script.clone());
+                script
+            }
+        };
+
+        self.loading.borrow_mut().push(key);
+        let result = script.evaluate(context);
+        self.loading.borrow_mut().pop();
+        result.map(|_| ())
+    }
+}
+
+fn cycle_error(kind: &str, path: &[String]) -> boa_engine::JsError {
+    JsNativeError::error()
+        .with_message(format!("{} cycle: {}", kind, path.join(" -> ")))
+        .into()
+}
+
+impl ModuleLoader for ScriptLoader {
+    fn load_imported_module(
+        &self,
+        referrer: Referrer,
+        specifier: JsString,
+        finish_load: Box<dyn FnOnce(JsResult<Module>, &mut Context)>,
+        context: &mut Context,
+    ) {
+        let result = self.load_module(&referrer, &specifier.to_std_string_escaped(), context);
+        finish_load(result, context);
+    }
+}
+
+#[derive(Trace, Finalize)]
+struct LoadCaptures {
+    #[unsafe_ignore_trace]
+    loader: Rc<ScriptLoader>,
+}
+
+/// Defines the global `load(path)`.
+pub fn register_load(loader: Rc<ScriptLoader>, context: &mut Context) -> JsResult<()> {
+    let load = NativeFunction::from_copy_closure_with_captures(
+        |_, args, captures: &LoadCaptures, context| {
+            let specifier = args
+                .get_or_undefined(0)
+                .to_string(context)?
+                .to_std_string_escaped();
+            captures
+                .loader
+                .load(&specifier, context)
+                .map_err(|err| super::with_stack(err, context))?;
+            Ok(JsValue::from(true))
+        },
+        LoadCaptures { loader },
+    );
+    context.register_global_builtin_callable(js_string!("load"), 1, load)?;
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use std::fs;
+
+    use super::*;
+    use crate::engine::{ScriptError, UserScript};
+
+    /// A scripts directory holding `files`, removed when dropped.
+    struct ScriptsDir(PathBuf);
+
+    impl ScriptsDir {
+        fn new(files: &[(&str, &str)]) -> Self {
+            let dir = std::env::temp_dir().join(format!("mongoui-test-{}", uuid::Uuid::new_v4()));
+            for (name, source) in files {
+                let path = dir.join(name);
+                fs::create_dir_all(path.parent().unwrap()).unwrap();
+                fs::write(path, source).unwrap();
+            }
+            Self(dir.canonicalize().unwrap())
+        }
+
+        fn key(&self, name: &str) -> String {
+            self.0.join(name).to_string_lossy().into_owned()
+        }
+    }
+
+    impl Drop for ScriptsDir {
+        fn drop(&mut self) {
+            let _ = fs::remove_dir_all(&self.0);
+        }
+    }
+
+    fn run(dir: &ScriptsDir, source: &str) -> Result<JsValue, ScriptError> {
+        let loader = Rc::new(ScriptLoader::new(Some(dir.0.clone()), Arc::default()));
+        let mut context = Context::builder()
+            .module_loader(loader.clone())
+            .build()
+            .unwrap();
+        register_load(loader, &mut context).unwrap();
+        UserScript::parse(source, &mut context)?.evaluate(&mut context)
+    }
+
+    #[test]
+    fn files_resolve_relative_to_the_importer() {
+        let dir = ScriptsDir::new(&[("lib/a.js", ""), ("lib/b.js", ""), ("b.js", "")]);
+        let loader = ScriptLoader::new(Some(dir.0.clone()), Arc::default());
+        let resolve = |specifier: &str, base: Option<&str>| {
+            loader.resolve(specifier, base).map(|(key, _)| key).unwrap()
+        };
+
+        assert_eq!(resolve("lib/a", None), dir.key("lib/a.js"));
+        assert_eq!(resolve("lib/a.js", None), dir.key("lib/a.js"));
+        assert_eq!(resolve("b", None), dir.key("b.js"));
+        let a = dir.key("lib/a.js");
+        assert_eq!(resolve("./b", Some(&a)), dir.key("lib/b.js"));
+        assert_eq!(resolve("../b.js", Some(&a)), dir.key("b.js"));
+        assert_eq!(resolve(&dir.key("b.js"), Some(&a)), dir.key("b.js"));
+    }
+
+    #[test]
+    fn imports_and_loads_run() {
+        let dir = ScriptsDir::new(&[
+            (
+                "lib/a.js",
+                "import { b } from './b.js';\nexport const a = b + 1;",
+            ),
+            ("lib/b.js", "export const b = 40;"),
+            ("helpers.js", "var helper = 1;"),
+        ]);
+        let value = run(
+            &dir,
+            "import { a } from 'lib/a';\nload('helpers');\nexport default a + helper;",
+        )
+        .unwrap();
+        assert_eq!(value, JsValue::from(42));
+    }
+
+    #[test]
+    fn import_cycles_are_reported() {
+        let dir = ScriptsDir::new(&[
+            ("a.js", "import { b } from './b.js';\nexport const a = 1;"),
+            ("b.js", "import { a } from './a.js';\nexport const b = 1;"),
+        ]);
+        let err = run(&dir, "import { a } from 'a';\nexport default a;").unwrap_err();
+        let expected = format!(
+            "import cycle: {0} -> {1} -> {0}",
+            dir.key("a.js"),
+            dir.key("b.js")
+        );
+        assert!(err.message.contains(&expected), "{}", err.message);
+    }
+
+    #[test]
+    fn load_cycles_are_reported() {
+        let dir = ScriptsDir::new(&[("a.js", "load('b');"), ("b.js", "load('a');")]);
+        let err = run(&dir, "load('a');").unwrap_err();
+        let expected = format!(
+            "load cycle: {0} -> {1} -> {0}",
+            dir.key("a.js"),
+            dir.key("b.js")
+        );
+        assert!(err.message.contains(&expected), "{}", err.message);
+    }
+
+    #[test]
+    fn files_are_read_again_once_changed() {
+        let dir = ScriptsDir::new(&[("a.js", "1")]);
+        let sources = Arc::default();
+        let read = || {
+            let loader = ScriptLoader::new(Some(dir.0.clone()), Arc::clone(&sources));
+            let (key, location) = loader.resolve("a", None).unwrap();
+            loader.read(&key, location).unwrap()
+        };
+
+        assert_eq!(read(), "1");
+        assert_eq!(sources.lock().unwrap().files.len(), 1);
+        fs::write(dir.0.join("a.js"), "22").unwrap();
+        assert_eq!(read(), "22");
+    }
+}
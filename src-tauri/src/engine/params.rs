@@ -0,0 +1,406 @@
+use boa_engine::{js_string, property::Attribute, Context, JsResult};
+use mongodb::bson::{oid::ObjectId, Bson, DateTime};
+use serde::Serialize;
+use serde_json::{Map, Value};
+
+use crate::error::Error;
+
+/// The type of a script parameter, named after the BSON constructor its value
+/// is built with.
+#[derive(Debug, Clone, Copy, PartialEq, Serialize)]
+pub enum ParamType {
+    String,
+    Double,
+    Int32,
+    Int64,
+    Boolean,
+    Date,
+    ObjectId,
+    Object,
+    Array,
+}
+
+impl ParamType {
+    fn parse(name: &str) -> Option<Self> {
+        let kind = match name {
+            "String" => ParamType::String,
+            "Double" | "Number" => ParamType::Double,
+            "Int32" | "Int" | "NumberInt" => ParamType::Int32,
+            "Int64" | "Long" | "NumberLong" => ParamType::Int64,
+            "Boolean" | "Bool" => ParamType::Boolean,
+            "Date" | "ISODate" => ParamType::Date,
+            "ObjectId" => ParamType::ObjectId,
+            "Object" | "Document" => ParamType::Object,
+            "Array" => ParamType::Array,
+            _ => return None,
+        };
+        Some(kind)
+    }
+
+    fn matches(&self, bson: &Bson) -> bool {
+        matches!(
+            (self, bson),
+            (ParamType::String, Bson::String(_))
+                | (ParamType::Double, Bson::Double(_))
+                | (ParamType::Int32, Bson::Int32(_))
+                | (ParamType::Int64, Bson::Int64(_))
+                | (ParamType::Boolean, Bson::Boolean(_))
+                | (ParamType::Date, Bson::DateTime(_))
+                | (ParamType::ObjectId, Bson::ObjectId(_))
+                | (ParamType::Object, Bson::Document(_))
+                | (ParamType::Array, Bson::Array(_))
+        )
+    }
+}
+
+/// A parameter declared in the header of a script.
+#[derive(Debug, Serialize)]
+#[serde(rename_all = "camelCase")]
+pub struct ScriptParam {
+    pub name: String,
+    #[serde(rename = "type")]
+    pub kind: ParamType,
+    /// Optional parameters are `undefined` in the script when not given.
+    pub optional: bool,
+    pub description: Option<String>,
+}
+
+/// Reads the parameters declared by a comment at the very start of the
+/// script, one per line:
+///
+/// ```js
+/// /* params
+///  * userId: ObjectId
+///  * since: Date      only orders placed after this
+///  * limit?: Int32
+///  */
+/// ```
+///
+/// Anything after the type is a description. Scripts without such a header
+/// have no parameters.
+pub fn declared_params(source: &str) -> Result<Vec<ScriptParam>, Error> {
+    let Some(header) = source.trim_start().strip_prefix("/*") else {
+        return Ok(Vec::new());
+    };
+    let Some(end) = header.find("*/") else {
+        return Ok(Vec::new());
+    };
+    let mut lines = header[..end]
+        .lines()
+        .map(|line| line.trim().trim_start_matches('*').trim())
+        .filter(|line| !line.is_empty());
+    if lines.next() != Some("params") {
+        return Ok(Vec::new());
+    }
+
+    let mut params: Vec<ScriptParam> = Vec::new();
+    for line in lines {
+        let invalid =
+            || Error::InvalidArgument(format!("invalid parameter declaration '{}'", line));
+        let (name, rest) = line.split_once(':').ok_or_else(invalid)?;
+        let (name, optional) = match name.trim().strip_suffix('?') {
+            Some(name) => (name.trim(), true),
+            None => (name.trim(), false),
+        };
+        if !is_identifier(name) || name == "db" {
+            return Err(Error::InvalidArgument(format!(
+                "'{}' can't be used as a parameter name",
+                name
+            )));
+        }
+        if params.iter().any(|param| param.name == name) {
+            return Err(Error::InvalidArgument(format!(
+                "parameter '{}' is declared twice",
+                name
+            )));
+        }
+        let rest = rest.trim();
+        let (kind, description) = match rest.split_once(char::is_whitespace) {
+            Some((kind, description)) => (kind, Some(description.trim().to_string())),
+            None => (rest, None),
+        };
+        let kind = ParamType::parse(kind).ok_or_else(|| {
+            Error::InvalidArgument(format!("unknown type '{}' of parameter '{}'", kind, name))
+        })?;
+        params.push(ScriptParam {
+            name: name.to_string(),
+            kind,
+            optional,
+            description,
+        });
+    }
+    Ok(params)
+}
+
+fn is_identifier(name: &str) -> bool {
+    let mut chars = name.chars();
+    chars
+        .next()
+        .is_some_and(|c| c.is_alphabetic() || c == '_' || c == '$')
+        && chars.all(|c| c.is_alphanumeric() || c == '_' || c == '$')
+}
+
+/// Checks `values` against the declared parameters and converts each of them
+/// to its BSON type. Values can be plain JSON, such as a hex string for an
+/// `ObjectId` or an ISO 8601 string for a `Date`, or Extended JSON.
+pub fn bind_params(
+    params: &[ScriptParam],
+    mut values: Map<String, Value>,
+) -> Result<Vec<(String, Bson)>, Error> {
+    if let Some(name) = values
+        .keys()
+        .find(|name| !params.iter().any(|param| &param.name == *name))
+    {
+        return Err(Error::InvalidArgument(format!(
+            "the script has no parameter '{}'",
+            name
+        )));
+    }
+
+    let mut bound = Vec::new();
+    for param in params {
+        let value = match values.remove(&param.name) {
+            Some(Value::Null) | None if param.optional => {
+                bound.push((param.name.clone(), Bson::Undefined));
+                continue;
+            }
+            Some(Value::Null) | None => {
+                return Err(Error::InvalidArgument(format!(
+                    "parameter '{}' is required",
+                    param.name
+                )))
+            }
+            Some(value) => value,
+        };
+        let bson = convert(param.kind, value).ok_or_else(|| {
+            Error::InvalidArgument(format!(
+                "parameter '{}' is not a valid {:?}",
+                param.name, param.kind
+            ))
+        })?;
+        bound.push((param.name.clone(), bson));
+    }
+    Ok(bound)
+}
+
+fn convert(kind: ParamType, value: Value) -> Option<Bson> {
+    let bson = match (kind, value) {
+        (ParamType::String, Value::String(s)) => Bson::String(s),
+        (ParamType::Double, Value::Number(n)) => Bson::Double(n.as_f64()?),
+        (ParamType::Int32, Value::Number(n)) => Bson::Int32(i32::try_from(n.as_i64()?).ok()?),
+        (ParamType::Int32, Value::String(s)) => Bson::Int32(s.trim().parse().ok()?),
+        (ParamType::Int64, Value::Number(n)) => Bson::Int64(n.as_i64()?),
+        // Values past 2^53 can't be JSON numbers on the way from the UI.
+        (ParamType::Int64, Value::String(s)) => Bson::Int64(s.trim().parse().ok()?),
+        (ParamType::Boolean, Value::Bool(b)) => Bson::Boolean(b),
+        (ParamType::Date, Value::Number(n)) => Bson::DateTime(DateTime::from_millis(n.as_i64()?)),
+        (ParamType::Date, Value::String(s)) => Bson::DateTime(parse_date(&s)?),
+        (ParamType::ObjectId, Value::String(s)) => {
+            Bson::ObjectId(ObjectId::parse_str(s.trim()).ok()?)
+        }
+        (_, value @ (Value::Object(_) | Value::Array(_))) => Bson::try_from(value).ok()?,
+        _ => return None,
+    };
+    (kind.matches(&bson) && !has_code(&bson)).then_some(bson)
+}
+
+/// Parses an RFC 3339 timestamp, or a bare date such as `2024-03-01` for
+/// midnight UTC.
+pub fn parse_date(s: &str) -> Option<DateTime> {
+    let s = s.trim();
+    let parsed = match s.len() {
+        10 => DateTime::parse_rfc3339_str(format!("{}T00:00:00Z", s)),
+        _ => DateTime::parse_rfc3339_str(s),
+    };
+    parsed.ok()
+}
+
+/// Whether `bson` holds JavaScript code, which isn't accepted as a value.
+fn has_code(bson: &Bson) -> bool {
+    match bson {
+        Bson::JavaScriptCode(_) | Bson::JavaScriptCodeWithScope(_) => true,
+        Bson::Document(doc) => doc.values().any(has_code),
+        Bson::Array(values) => values.iter().any(has_code),
+        _ => false,
+    }
+}
+
+/// Defines each bound parameter as a read-only global of the script. Values
+/// are converted like query results, so Int64 values are numbers unless they
+/// don't fit one exactly.
+pub fn inject(bound: Vec<(String, Bson)>, context: &mut Context) -> JsResult<()> {
+    for (name, bson) in bound {
+        let value = super::bson_to_js(bson, context);
+        context.register_global_property(
+            js_string!(name),
+            value,
+            Attribute::READONLY | Attribute::NON_ENUMERABLE | Attribute::PERMANENT,
+        )?;
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use boa_engine::Source;
+    use mongodb::bson::doc;
+    use serde_json::json;
+
+    use super::*;
+
+    fn param(name: &str, kind: ParamType, optional: bool) -> ScriptParam {
+        ScriptParam {
+            name: name.to_string(),
+            kind,
+            optional,
+            description: None,
+        }
+    }
+
+    fn values(value: Value) -> Map<String, Value> {
+        match value {
+            Value::Object(map) => map,
+            _ => unreachable!(),
+        }
+    }
+
+    #[test]
+    fn params_are_read_from_the_header() {
+        let params = declared_params(
+            "/* params\n * userId: ObjectId\n * since: Date   only orders placed after this\n * limit?: Int32\n */\ndb.orders.find({})",
+        )
+        .unwrap();
+        let summary = params
+            .iter()
+            .map(|param| (param.name.as_str(), param.kind, param.optional))
+            .collect::<Vec<_>>();
+        assert_eq!(
+            summary,
+            [
+                ("userId", ParamType::ObjectId, false),
+                ("since", ParamType::Date, false),
+                ("limit", ParamType::Int32, true),
+            ]
+        );
+        assert_eq!(
+            params[1].description.as_deref(),
+            Some("only orders placed after this")
+        );
+    }
+
+    #[test]
+    fn scripts_without_a_header_have_no_params() {
+        assert!(declared_params("db.c.find({})").unwrap().is_empty());
+        assert!(declared_params("/* a comment */ 1").unwrap().is_empty());
+        assert!(declared_params("1 /* params\n x: Int32 */")
+            .unwrap()
+            .is_empty());
+    }
+
+    #[test]
+    fn bad_declarations_are_rejected() {
+        for source in [
+            "/* params\n x Int32 */",
+            "/* params\n x: Decimal */",
+            "/* params\n db: Object */",
+            "/* params\n 1x: Int32 */",
+            "/* params\n x: Int32\n x: Int64 */",
+        ] {
+            assert!(declared_params(source).is_err(), "{}", source);
+        }
+    }
+
+    #[test]
+    fn values_are_bound_to_their_types() {
+        let params = [
+            param("name", ParamType::String, false),
+            param("count", ParamType::Int64, false),
+            param("limit", ParamType::Int32, true),
+        ];
+        let bound = bind_params(
+            &params,
+            values(json!({ "name": "a", "count": "9007199254740993" })),
+        )
+        .unwrap();
+        assert_eq!(
+            bound,
+            [
+                ("name".to_string(), Bson::String("a".to_string())),
+                ("count".to_string(), Bson::Int64(9007199254740993)),
+                ("limit".to_string(), Bson::Undefined),
+            ]
+        );
+
+        let missing = bind_params(&params, values(json!({ "name": "a" })));
+        assert!(missing.is_err());
+        let unknown = bind_params(
+            &params,
+            values(json!({ "name": "a", "count": 1, "other": 1 })),
+        );
+        assert!(unknown.is_err());
+        let mistyped = bind_params(&params, values(json!({ "name": 1, "count": 1 })));
+        assert!(mistyped.is_err());
+    }
+
+    #[test]
+    fn plain_and_extended_json_are_converted() {
+        let oid = "65f1c0c0c0c0c0c0c0c0c0c0";
+        assert_eq!(
+            convert(ParamType::ObjectId, json!(oid)),
+            Some(Bson::ObjectId(ObjectId::parse_str(oid).unwrap()))
+        );
+        assert_eq!(
+            convert(ParamType::ObjectId, json!({ "$oid": oid })),
+            Some(Bson::ObjectId(ObjectId::parse_str(oid).unwrap()))
+        );
+        assert_eq!(
+            convert(ParamType::Date, json!("2024-03-01")),
+            Some(Bson::DateTime(DateTime::from_millis(1709251200000)))
+        );
+        assert_eq!(
+            convert(ParamType::Date, json!("2024-03-01T12:00:00+01:00")),
+            Some(Bson::DateTime(DateTime::from_millis(1709290800000)))
+        );
+        assert_eq!(convert(ParamType::Int32, json!(1u64 << 31)), None);
+        assert_eq!(convert(ParamType::Double, json!("1.5")), None);
+        assert_eq!(
+            convert(
+                ParamType::Object,
+                json!({ "a": [1, { "$numberLong": "2" }] })
+            ),
+            Some(Bson::Document(doc! { "a": [1, 2i64] }))
+        );
+    }
+
+    #[test]
+    fn code_is_not_a_value() {
+        assert_eq!(convert(ParamType::Object, json!({ "$code": "1" })), None);
+        assert_eq!(
+            convert(ParamType::Object, json!({ "f": { "$code": "1" } })),
+            None
+        );
+        assert_eq!(convert(ParamType::Array, json!([{ "$code": "1" }])), None);
+    }
+
+    #[test]
+    fn int64_values_mix_with_numbers() {
+        let mut context = Context::default();
+        inject(
+            vec![
+                ("n".to_string(), Bson::Int64(9007199254740993)),
+                ("doc".to_string(), Bson::Document(doc! { "n": 2i64 })),
+            ],
+            &mut context,
+        )
+        .unwrap();
+        let value = context
+            .eval(Source::from_bytes(
+                "typeof doc.n === 'number' && String(n) === '9007199254740993' ? [doc.n + 1, n + 1n] : null",
+            ))
+            .unwrap();
+        assert_eq!(
+            crate::engine::js_to_bson(value, &mut context).unwrap(),
+            Bson::Array(vec![Bson::Int32(3), Bson::Int64(9007199254740994)])
+        );
+    }
+}
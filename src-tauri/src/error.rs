@@ -0,0 +1,220 @@
+use std::sync::PoisonError;
+
+use boa_engine::JsError;
+use mongodb::{
+    bson::Bson,
+    error::{ErrorKind, WriteFailure, RETRYABLE_WRITE_ERROR, TRANSIENT_TRANSACTION_ERROR},
+};
+use serde::Serialize;
+use serde_json::{json, Value};
+
+use crate::engine::ScriptError;
+
+#[derive(thiserror::Error, Debug)]
+pub enum Error {
+    #[error("Invalid Argument: {}", .0)]
+    InvalidArgument(String),
+
+    #[error("Not Found: {}", .0)]
+    NotFound(String),
+
+    #[error("Conflict: {}", .0)]
+    Conflict(String),
+
+    #[error("Storage Error: {}", .0)]
+    Storage(String),
+
+    #[error("The vault is locked, unlock it with the master password")]
+    VaultLocked,
+
+    #[error("Crypto Error: {}", .0)]
+    Crypto(String),
+
+    /// An SSH tunnel or a SOCKS5 or HTTP proxy failed.
+    #[error("Tunnel Error: {}", .0)]
+    Tunnel(String),
+
+    #[error("Internal state is corrupted by an earlier failure, restart the app")]
+    LockPoisoned,
+
+    #[error("{0}")]
+    JsExecution(#[from] JsError),
+
+    #[error("{0}")]
+    Script(#[from] ScriptError),
+
+    #[error("Mongo Error: {0}")]
+    Mongo(#[from] mongodb::error::Error),
+
+    #[error("Sqlite Error: {0}")]
+    Sqlite(#[from] rusqlite::Error),
+}
+
+impl<T> From<PoisonError<T>> for Error {
+    fn from(_: PoisonError<T>) -> Self {
+        Error::LockPoisoned
+    }
+}
+
+/// The shape every command error is sent to the frontend in.
+#[derive(Serialize)]
+#[serde(rename_all = "camelCase")]
+struct ErrorResponse {
+    kind: &'static str,
+    message: String,
+    code: Option<i32>,
+    code_name: Option<String>,
+    labels: Vec<String>,
+    retryable: bool,
+    details: Option<Value>,
+}
+
+impl ErrorResponse {
+    fn new(kind: &'static str, message: String) -> Self {
+        Self {
+            kind,
+            message,
+            code: None,
+            code_name: None,
+            labels: Vec::new(),
+            retryable: false,
+            details: None,
+        }
+    }
+}
+
+/// Returns the server error code and code name carried by a driver error, if any.
+pub fn mongo_error_code(err: &mongodb::error::Error) -> (Option<i32>, Option<String>) {
+    match err.kind.as_ref() {
+        ErrorKind::Command(err) => (Some(err.code), Some(err.code_name.clone())),
+        ErrorKind::Write(WriteFailure::WriteError(err)) => (Some(err.code), err.code_name.clone()),
+        ErrorKind::Write(WriteFailure::WriteConcernError(err)) => {
+            (Some(err.code), Some(err.code_name.clone()))
+        }
+        ErrorKind::BulkWrite(failure) => {
+            if let Some(err) = failure.write_errors.as_ref().and_then(|errs| errs.first()) {
+                (Some(err.code), err.code_name.clone())
+            } else if let Some(err) = &failure.write_concern_error {
+                (Some(err.code), Some(err.code_name.clone()))
+            } else {
+                (None, None)
+            }
+        }
+        _ => (None, None),
+    }
+}
+
+fn mongo_response(err: &mongodb::error::Error) -> ErrorResponse {
+    let (kind, details) = match err.kind.as_ref() {
+        ErrorKind::Authentication { .. } => ("authentication", None),
+        ErrorKind::ServerSelection { .. } => ("serverSelection", None),
+        ErrorKind::DnsResolve { .. } => ("dnsResolve", None),
+        ErrorKind::Io(_) | ErrorKind::ConnectionPoolCleared { .. } => ("network", None),
+        ErrorKind::InvalidTlsConfig { .. } => ("invalidTlsConfig", None),
+        ErrorKind::InvalidArgument { .. } => ("invalidArgument", None),
+        ErrorKind::Command(_) => ("command", None),
+        // A single write error doesn't carry the position of the write.
+        ErrorKind::Write(WriteFailure::WriteError(err)) => (
+            "writeError",
+            Some(json!({ "errInfo": err.details.clone().map(Bson::from) })),
+        ),
+        ErrorKind::Write(WriteFailure::WriteConcernError(err)) => (
+            "writeConcernError",
+            Some(json!({ "errInfo": err.details.clone().map(Bson::from) })),
+        ),
+        ErrorKind::BulkWrite(failure) => {
+            let write_errors = failure
+                .write_errors
+                .iter()
+                .flatten()
+                .map(|err| {
+                    json!({
+                        "index": err.index,
+                        "code": err.code,
+                        "codeName": err.code_name,
+                        "message": err.message,
+                        "errInfo": err.details.clone().map(Bson::from),
+                    })
+                })
+                .collect::<Vec<_>>();
+            let write_concern_error = failure.write_concern_error.as_ref().map(|err| {
+                json!({
+                    "code": err.code,
+                    "codeName": err.code_name,
+                    "message": err.message,
+                })
+            });
+            (
+                "bulkWriteError",
+                Some(json!({
+                    "writeErrors": write_errors,
+                    "writeConcernError": write_concern_error,
+                })),
+            )
+        }
+        _ => ("mongo", None),
+    };
+
+    let (code, code_name) = mongo_error_code(err);
+    let retryable = err.contains_label(RETRYABLE_WRITE_ERROR)
+        || err.contains_label(TRANSIENT_TRANSACTION_ERROR)
+        || matches!(kind, "serverSelection" | "network");
+
+    ErrorResponse {
+        code,
+        code_name,
+        labels: err.labels().iter().cloned().collect(),
+        retryable,
+        details,
+        ..ErrorResponse::new(kind, err.kind.to_string())
+    }
+}
+
+fn sqlite_response(err: &rusqlite::Error) -> ErrorResponse {
+    let mut response = ErrorResponse::new("sqlite", err.to_string());
+    if let rusqlite::Error::SqliteFailure(failure, _) = err {
+        response.code = Some(failure.extended_code);
+        response.code_name = Some(format!("{:?}", failure.code));
+        response.retryable = matches!(
+            failure.code,
+            rusqlite::ErrorCode::DatabaseBusy | rusqlite::ErrorCode::DatabaseLocked
+        );
+    }
+    response
+}
+
+impl Serialize for Error {
+    fn serialize<S>(&self, serializer: S) -> Result<S::Ok, S::Error>
+    where
+        S: serde::ser::Serializer,
+    {
+        let response = match self {
+            Error::InvalidArgument(message) => {
+                ErrorResponse::new("invalidArgument", message.clone())
+            }
+            Error::NotFound(message) => ErrorResponse::new("notFound", message.clone()),
+            Error::Conflict(message) => ErrorResponse::new("conflict", message.clone()),
+            Error::Storage(message) => ErrorResponse::new("storage", message.clone()),
+            Error::VaultLocked => ErrorResponse::new("vaultLocked", self.to_string()),
+            Error::Crypto(message) => ErrorResponse::new("crypto", message.clone()),
+            Error::Tunnel(message) => ErrorResponse::new("tunnel", message.clone()),
+            Error::LockPoisoned => ErrorResponse::new("lockPoisoned", self.to_string()),
+            Error::JsExecution(_) => ErrorResponse::new("script", self.to_string()),
+            Error::Script(err) => ErrorResponse {
+                code: err.code,
+                code_name: err.code_name.clone(),
+                labels: err.labels.clone(),
+                details: Some(json!({
+                    "name": err.kind,
+                    "line": err.line,
+                    "column": err.column,
+                    "stack": err.stack,
+                })),
+                ..ErrorResponse::new("script", err.message.clone())
+            },
+            Error::Mongo(err) => mongo_response(err),
+            Error::Sqlite(err) => sqlite_response(err),
+        };
+        response.serialize(serializer)
+    }
+}
@@ -0,0 +1,375 @@
+use std::{
+    collections::HashSet,
+    fs::File,
+    io::{BufWriter, Write},
+    path::Path,
+};
+
+use mongodb::bson::{Bson, Document};
+use serde::{Deserialize, Serialize};
+use serde_json::Value;
+
+use crate::{error::Error, import::lookup};
+
+/// What to export.
+#[derive(Deserialize)]
+#[serde(tag = "kind", rename_all = "camelCase")]
+pub enum ExportSource {
+    #[serde(rename_all = "camelCase")]
+    Find {
+        collection: String,
+        /// Extended JSON, like the rest of the query.
+        filter: Option<Value>,
+        projection: Option<Value>,
+        sort: Option<Value>,
+        skip: Option<u64>,
+        limit: Option<i64>,
+    },
+    Aggregate {
+        collection: String,
+        pipeline: Vec<Value>,
+    },
+    /// The result of a script. Unlike queries it is computed in full before
+    /// anything is written.
+    Script {
+        script: String,
+        params: Option<serde_json::Map<String, Value>>,
+    },
+}
+
+#[derive(Deserialize, Clone, Copy)]
+#[serde(rename_all = "lowercase")]
+pub enum ExportFormat {
+    /// An array of Extended JSON documents.
+    Json,
+    /// One Extended JSON document per line, like `mongoexport`.
+    Ndjson,
+    /// Comma separated values, nested fields flattened to dotted columns.
+    Csv,
+    /// Concatenated BSON documents, like `mongodump`.
+    Bson,
+}
+
+#[derive(Deserialize, Default)]
+#[serde(rename_all = "camelCase", default)]
+pub struct ExportResultsOptions {
+    /// CSV columns, as dotted paths. Defaults to the fields of the first
+    /// document; fields that only later documents have are reported in
+    /// [`ExportProgress::unseen_fields`].
+    pub fields: Vec<String>,
+    /// Writes canonical rather than relaxed Extended JSON, which keeps the
+    /// exact numeric types.
+    pub canonical: bool,
+}
+
+/// Sent every [`PROGRESS_INTERVAL`] documents, and returned once the export
+/// is done.
+#[derive(Serialize, Default, Clone)]
+#[serde(rename_all = "camelCase")]
+pub struct ExportProgress {
+    pub written: u64,
+    pub bytes_written: u64,
+    /// Fields of the documents written to CSV that have no column, so were
+    /// left out.
+    pub unseen_fields: Vec<String>,
+}
+
+const PROGRESS_INTERVAL: u64 = 1000;
+
+/// Parses an Extended JSON query document, such as a filter or a sort.
+pub fn query_document(value: Option<Value>, name: &str) -> Result<Option<Document>, Error> {
+    let Some(value) = value else {
+        return Ok(None);
+    };
+    match Bson::try_from(value) {
+        Ok(Bson::Document(document)) => Ok(Some(document)),
+        _ => Err(Error::InvalidArgument(format!(
+            "{} must be a document",
+            name
+        ))),
+    }
+}
+
+/// Whether a pipeline writes its result to a collection, with `$out` or
+/// `$merge`.
+pub fn writes_output(pipeline: &[Document]) -> bool {
+    pipeline
+        .iter()
+        .any(|stage| stage.contains_key("$out") || stage.contains_key("$merge"))
+}
+
+/// Writes `documents` to `path` one at a time, so that a cursor is never
+/// held in memory in full.
+pub fn export_results(
+    documents: impl Iterator<Item = Result<Document, Error>>,
+    path: &Path,
+    format: ExportFormat,
+    options: &ExportResultsOptions,
+    on_progress: &mut dyn FnMut(&ExportProgress),
+) -> Result<ExportProgress, Error> {
+    let file = File::create(path)
+        .map_err(|err| Error::Storage(format!("failed to create {}: {}", path.display(), err)))?;
+    let writer = CountingWriter {
+        inner: BufWriter::new(file),
+        count: 0,
+    };
+    let progress = match format {
+        ExportFormat::Csv => write_csv(documents, writer, options, on_progress),
+        _ => write_documents(documents, writer, format, options, on_progress),
+    }
+    .map_err(|err| err.into_error(path))?;
+    on_progress(&progress);
+    Ok(progress)
+}
+
+/// Keeps a failing cursor apart from a failing file.
+#[derive(Debug)]
+pub enum WriteError {
+    Io(std::io::Error),
+    Source(Error),
+}
+
+impl WriteError {
+    /// The error of writing `path`.
+    pub fn into_error(self, path: &Path) -> Error {
+        match self {
+            WriteError::Io(err) => {
+                Error::Storage(format!("failed to write {}: {}", path.display(), err))
+            }
+            WriteError::Source(err) => err,
+        }
+    }
+}
+
+impl From<std::io::Error> for WriteError {
+    fn from(err: std::io::Error) -> Self {
+        WriteError::Io(err)
+    }
+}
+
+impl From<csv::Error> for WriteError {
+    fn from(err: csv::Error) -> Self {
+        WriteError::Io(err.into())
+    }
+}
+
+fn write_documents<W: Write>(
+    documents: impl Iterator<Item = Result<Document, Error>>,
+    mut writer: CountingWriter<W>,
+    format: ExportFormat,
+    options: &ExportResultsOptions,
+    on_progress: &mut dyn FnMut(&ExportProgress),
+) -> Result<ExportProgress, WriteError> {
+    let mut progress = ExportProgress::default();
+    if let ExportFormat::Json = format {
+        writer.write_all(b"[")?;
+    }
+    for document in documents {
+        let document = document.map_err(WriteError::Source)?;
+        match format {
+            ExportFormat::Bson => document
+                .to_writer(&mut writer)
+                .map_err(std::io::Error::other)?,
+            ExportFormat::Json => {
+                let separator: &[u8] = match progress.written {
+                    0 => b"\n",
+                    _ => b",\n",
+                };
+                writer.write_all(separator)?;
+                serde_json::to_writer(&mut writer, &extended_json(document, options.canonical))
+                    .map_err(std::io::Error::from)?;
+            }
+            _ => {
+                serde_json::to_writer(&mut writer, &extended_json(document, options.canonical))
+                    .map_err(std::io::Error::from)?;
+                writer.write_all(b"\n")?;
+            }
+        }
+        progress.written += 1;
+        if progress.written.is_multiple_of(PROGRESS_INTERVAL) {
+            progress.bytes_written = writer.count;
+            on_progress(&progress);
+        }
+    }
+    if let ExportFormat::Json = format {
+        match progress.written {
+            0 => writer.write_all(b"]\n")?,
+            _ => writer.write_all(b"\n]\n")?,
+        }
+    }
+    writer.flush()?;
+    progress.bytes_written = writer.count;
+    Ok(progress)
+}
+
+fn write_csv<W: Write>(
+    documents: impl Iterator<Item = Result<Document, Error>>,
+    writer: CountingWriter<W>,
+    options: &ExportResultsOptions,
+    on_progress: &mut dyn FnMut(&ExportProgress),
+) -> Result<ExportProgress, WriteError> {
+    let mut progress = ExportProgress::default();
+    let mut writer = csv::Writer::from_writer(writer);
+    let mut columns = None;
+    if !options.fields.is_empty() {
+        writer.write_record(&options.fields)?;
+        columns = Some(options.fields.clone());
+    }
+    let mut seen = HashSet::new();
+    for document in documents {
+        let document = document.map_err(WriteError::Source)?;
+        let columns = match &mut columns {
+            Some(columns) => columns,
+            None => {
+                let fields = flattened_fields(&document);
+                writer.write_record(&fields)?;
+                columns.insert(fields)
+            }
+        };
+        for field in flattened_fields(&document) {
+            if !has_column(columns, &field) && seen.insert(field.clone()) {
+                progress.unseen_fields.push(field);
+            }
+        }
+        writer.write_record(
+            columns
+                .iter()
+                .map(|column| lookup(&document, column).map_or_else(String::new, csv_value)),
+        )?;
+        progress.written += 1;
+        if progress.written.is_multiple_of(PROGRESS_INTERVAL) {
+            // The CSV writer buffers on its own.
+            writer.flush()?;
+            progress.bytes_written = writer.get_ref().count;
+            on_progress(&progress);
+        }
+    }
+    writer.flush()?;
+    progress.bytes_written = writer.get_ref().count;
+    Ok(progress)
+}
+
+struct CountingWriter<W> {
+    inner: W,
+    count: u64,
+}
+
+impl<W: Write> Write for CountingWriter<W> {
+    fn write(&mut self, buf: &[u8]) -> std::io::Result<usize> {
+        let n = self.inner.write(buf)?;
+        self.count += n as u64;
+        Ok(n)
+    }
+
+    fn flush(&mut self) -> std::io::Result<()> {
+        self.inner.flush()
+    }
+}
+
+fn extended_json(document: Document, canonical: bool) -> Value {
+    match canonical {
+        true => Bson::Document(document).into_canonical_extjson(),
+        false => Bson::Document(document).into_relaxed_extjson(),
+    }
+}
+
+/// The dotted paths of every value in `document`, descending into nested
+/// documents but not into arrays.
+fn flattened_fields(document: &Document) -> Vec<String> {
+    let mut fields = Vec::new();
+    for (key, value) in document {
+        match value {
+            Bson::Document(nested) if !nested.is_empty() => fields.extend(
+                flattened_fields(nested)
+                    .into_iter()
+                    .map(|field| format!("{}.{}", key, field)),
+            ),
+            _ => fields.push(key.clone()),
+        }
+    }
+    fields
+}
+
+/// Whether `field`, or a document it is in, has a column.
+fn has_column(columns: &[String], field: &str) -> bool {
+    columns.iter().any(|column| {
+        field
+            .strip_prefix(column.as_str())
+            .is_some_and(|rest| rest.is_empty() || rest.starts_with('.'))
+    })
+}
+
+/// Strings, numbers, dates and ids are written plainly, anything else as
+/// relaxed Extended JSON.
+fn csv_value(value: &Bson) -> String {
+    match value {
+        Bson::String(s) => s.clone(),
+        Bson::Int32(n) => n.to_string(),
+        Bson::Int64(n) => n.to_string(),
+        Bson::Double(n) => n.to_string(),
+        Bson::Decimal128(n) => n.to_string(),
+        Bson::Boolean(b) => b.to_string(),
+        Bson::ObjectId(id) => id.to_hex(),
+        Bson::DateTime(date) => date
+            .try_to_rfc3339_string()
+            .unwrap_or_else(|_| date.timestamp_millis().to_string()),
+        Bson::Null | Bson::Undefined => String::new(),
+        value => value.clone().into_relaxed_extjson().to_string(),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use mongodb::bson::doc;
+
+    use super::*;
+
+    fn csv(documents: Vec<Document>, fields: &[&str]) -> (String, ExportProgress) {
+        let mut out = Vec::new();
+        let options = ExportResultsOptions {
+            fields: fields.iter().map(|field| field.to_string()).collect(),
+            ..Default::default()
+        };
+        let progress = write_csv(
+            documents.into_iter().map(Ok),
+            CountingWriter {
+                inner: &mut out,
+                count: 0,
+            },
+            &options,
+            &mut |_| {},
+        )
+        .unwrap();
+        (String::from_utf8(out).unwrap(), progress)
+    }
+
+    #[test]
+    fn reports_fields_without_a_column() {
+        let (out, progress) = csv(
+            vec![
+                doc! { "a": 1, "b": { "c": 2 } },
+                doc! { "a": 3, "b": { "c": 4, "d": 5 }, "e": 6 },
+            ],
+            &[],
+        );
+        assert_eq!(out, "a,b.c\n1,2\n3,4\n");
+        assert_eq!(progress.unseen_fields, ["b.d", "e"]);
+    }
+
+    #[test]
+    fn a_column_covers_the_fields_under_it() {
+        let (out, progress) = csv(vec![doc! { "b": { "c": 2, "d": 3 } }], &["b"]);
+        assert_eq!(out, "b\n\"{\"\"c\"\":2,\"\"d\"\":3}\"\n");
+        assert!(progress.unseen_fields.is_empty());
+    }
+
+    #[test]
+    fn finds_pipelines_that_write() {
+        assert!(writes_output(&[
+            doc! { "$match": {} },
+            doc! { "$out": "x" }
+        ]));
+        assert!(writes_output(&[doc! { "$merge": { "into": "x" } }]));
+        assert!(!writes_output(&[doc! { "$match": { "$out": 1 } }]));
+    }
+}
@@ -0,0 +1,889 @@
+use std::{
+    cell::Cell,
+    fs::File,
+    io::{BufRead, BufReader, Read},
+    path::Path,
+    rc::Rc,
+    str::FromStr,
+};
+
+use mongodb::{
+    bson::{doc, Bson, DateTime, Decimal128, Document},
+    error::{BulkWriteFailure, ErrorKind},
+    options::InsertManyOptions,
+    sync::{Collection, Database},
+};
+use serde::{
+    de::{self, SeqAccess, Visitor},
+    Deserialize, Deserializer, Serialize,
+};
+use serde_json::Value;
+
+use crate::{engine::params::parse_date, error::Error};
+
+#[derive(Deserialize, Clone, Copy)]
+#[serde(rename_all = "lowercase")]
+pub enum ImportFormat {
+    /// A JSON array of documents.
+    Json,
+    /// One document per line, as written by `mongoexport`.
+    Ndjson,
+    /// Comma separated values with a header row.
+    Csv,
+}
+
+#[derive(Deserialize, Default, Clone, Copy, PartialEq)]
+#[serde(rename_all = "camelCase")]
+pub enum ImportMode {
+    #[default]
+    Insert,
+    /// Replaces the document with the same `upsertFields`, inserting it if
+    /// there is none. Documents missing one of the fields are inserted.
+    Upsert,
+}
+
+#[derive(Deserialize)]
+#[serde(rename_all = "camelCase", default)]
+pub struct ImportFileOptions {
+    /// Documents sent to the server at a time.
+    pub batch_size: usize,
+    pub mode: ImportMode,
+    /// Fields identifying a document in upsert mode, `_id` by default.
+    pub upsert_fields: Vec<String>,
+    /// Drops the collection before importing, once the first batch was read.
+    pub drop: bool,
+    /// Stops at the first document the server rejects instead of counting it
+    /// as failed and moving on.
+    pub stop_on_error: bool,
+    /// Leaves out empty CSV fields instead of importing empty strings.
+    pub ignore_blanks: bool,
+}
+
+impl Default for ImportFileOptions {
+    fn default() -> Self {
+        Self {
+            batch_size: 1000,
+            mode: ImportMode::Insert,
+            upsert_fields: vec!["_id".to_string()],
+            drop: false,
+            stop_on_error: false,
+            ignore_blanks: false,
+        }
+    }
+}
+
+/// Sent after every batch, and returned once the import is done.
+#[derive(Serialize, Default, Clone)]
+#[serde(rename_all = "camelCase")]
+pub struct ImportProgress {
+    /// Documents read from the file.
+    pub read: u64,
+    pub inserted: u64,
+    /// Documents that replaced an existing one in upsert mode.
+    pub updated: u64,
+    pub failed: u64,
+    pub bytes_read: u64,
+    pub total_bytes: u64,
+}
+
+/// Reads documents from `path` into the collection `name` of `database`,
+/// calling `on_progress` after every batch. The file is streamed, so it can be
+/// larger than memory.
+pub fn import_file(
+    database: Database,
+    name: &str,
+    path: &Path,
+    format: ImportFormat,
+    options: &ImportFileOptions,
+    on_progress: &mut dyn FnMut(&ImportProgress),
+) -> Result<ImportProgress, Error> {
+    if options.mode == ImportMode::Upsert && options.upsert_fields.is_empty() {
+        return Err(Error::InvalidArgument(
+            "upsert mode needs at least one field".to_string(),
+        ));
+    }
+    let file = File::open(path).map_err(|err| {
+        Error::InvalidArgument(format!("failed to open {}: {}", path.display(), err))
+    })?;
+    let total_bytes = file.metadata().map_or(0, |metadata| metadata.len());
+    let bytes_read = Rc::new(Cell::new(0));
+    let reader = BufReader::new(CountingReader {
+        inner: file,
+        count: bytes_read.clone(),
+    });
+
+    let mut target = Target {
+        collection: database.collection(name),
+        database,
+    };
+    let importer = Importer {
+        sink: &mut target,
+        options,
+        drop: options.drop,
+        batch: Vec::new(),
+        progress: ImportProgress {
+            total_bytes,
+            ..Default::default()
+        },
+        bytes_read,
+        on_progress,
+    };
+    importer.run(reader, format)
+}
+
+struct CountingReader<R> {
+    inner: R,
+    count: Rc<Cell<u64>>,
+}
+
+impl<R: Read> Read for CountingReader<R> {
+    fn read(&mut self, buf: &mut [u8]) -> std::io::Result<usize> {
+        let n = self.inner.read(buf)?;
+        self.count.set(self.count.get() + n as u64);
+        Ok(n)
+    }
+}
+
+/// What [`insert_documents`] made of a batch.
+pub struct Inserted {
+    pub inserted: u64,
+    /// Documents the server rejected.
+    pub rejected: u64,
+    /// Why an ordered insert stopped, at the first document it rejected.
+    pub stopped: Option<Error>,
+}
+
+/// Inserts a batch. Unordered, it carries on past the documents the server
+/// rejects. Ordered, it stops at the first and hands back its error along
+/// with the documents inserted before it.
+pub fn insert_documents<T: Serialize + Send + Sync>(
+    collection: &Collection<T>,
+    batch: Vec<T>,
+    ordered: bool,
+) -> Result<Inserted, Error> {
+    let count = batch.len() as u64;
+    let options = InsertManyOptions::builder().ordered(ordered).build();
+    let err = match collection.insert_many(batch, options) {
+        Ok(_) => {
+            return Ok(Inserted {
+                inserted: count,
+                rejected: 0,
+                stopped: None,
+            })
+        }
+        Err(err) => err,
+    };
+    let ErrorKind::BulkWrite(failure) = err.kind.as_ref() else {
+        return Err(err.into());
+    };
+    if failure.write_concern_error.is_some() {
+        return Err(err.into());
+    }
+    let errors = failure.write_errors.as_deref().unwrap_or_default();
+    if ordered {
+        return Ok(Inserted {
+            inserted: errors.first().map_or(0, |error| error.index as u64),
+            rejected: errors.len() as u64,
+            stopped: Some(err.into()),
+        });
+    }
+    Ok(Inserted {
+        inserted: count - errors.len() as u64,
+        rejected: errors.len() as u64,
+        stopped: None,
+    })
+}
+
+/// What [`Sink::upsert`] made of a batch, like [`Inserted`].
+struct Upserted {
+    inserted: u64,
+    /// Documents that replaced an existing one.
+    updated: u64,
+    rejected: u64,
+    stopped: Option<Error>,
+}
+
+/// Where the batches of an import go, the collection but for tests.
+trait Sink {
+    fn drop(&mut self) -> Result<(), Error>;
+    fn insert(&mut self, batch: Vec<Document>, ordered: bool) -> Result<Inserted, Error>;
+    /// Runs `update` statements, each replacing a document or inserting it.
+    fn upsert(&mut self, updates: Vec<Document>, ordered: bool) -> Result<Upserted, Error>;
+}
+
+struct Target {
+    database: Database,
+    collection: Collection<Document>,
+}
+
+/// The largest BSON document the server accepts.
+const MAX_BSON_SIZE: usize = 16 * 1024 * 1024;
+/// The most statements the server takes in one write command.
+const MAX_WRITE_BATCH_SIZE: usize = 100_000;
+
+/// The reply to an `update` command. Write errors don't fail the command.
+#[derive(Deserialize)]
+struct UpdateReply {
+    /// Documents matched or inserted.
+    n: u64,
+    #[serde(default)]
+    upserted: Vec<Document>,
+    #[serde(flatten)]
+    failure: BulkWriteFailure,
+}
+
+impl Sink for Target {
+    fn drop(&mut self) -> Result<(), Error> {
+        Ok(self.collection.drop(None)?)
+    }
+
+    fn insert(&mut self, batch: Vec<Document>, ordered: bool) -> Result<Inserted, Error> {
+        insert_documents(&self.collection, batch, ordered)
+    }
+
+    fn upsert(&mut self, updates: Vec<Document>, ordered: bool) -> Result<Upserted, Error> {
+        let mut result = Upserted {
+            inserted: 0,
+            updated: 0,
+            rejected: 0,
+            stopped: None,
+        };
+        for updates in split_commands(updates) {
+            let reply = self.database.run_command(
+                doc! {
+                    "update": self.collection.name(),
+                    "updates": updates,
+                    "ordered": ordered,
+                },
+                None,
+            )?;
+            let reply: UpdateReply =
+                mongodb::bson::from_document(reply).map_err(mongodb::error::Error::from)?;
+            let upserted = reply.upserted.len() as u64;
+            result.inserted += upserted;
+            result.updated += reply.n.saturating_sub(upserted);
+            if reply.failure.write_concern_error.is_some() {
+                return Err(
+                    mongodb::error::Error::from(ErrorKind::BulkWrite(reply.failure)).into(),
+                );
+            }
+            let rejected = reply.failure.write_errors.as_ref().map_or(0, Vec::len) as u64;
+            result.rejected += rejected;
+            if ordered && rejected > 0 {
+                result.stopped =
+                    Some(mongodb::error::Error::from(ErrorKind::BulkWrite(reply.failure)).into());
+                break;
+            }
+        }
+        Ok(result)
+    }
+}
+
+/// Splits `update` statements into commands that stay within the size the
+/// server accepts. A statement too large on its own gets a command of its
+/// own, for the server to reject.
+fn split_commands(updates: Vec<Document>) -> Vec<Vec<Document>> {
+    let mut commands = Vec::new();
+    let mut command = Vec::new();
+    let mut size = 0;
+    for update in updates {
+        let update_size = mongodb::bson::to_vec(&update).map_or(0, |bytes| bytes.len());
+        if !command.is_empty()
+            && (size + update_size > MAX_BSON_SIZE || command.len() >= MAX_WRITE_BATCH_SIZE)
+        {
+            commands.push(std::mem::take(&mut command));
+            size = 0;
+        }
+        size += update_size;
+        command.push(update);
+    }
+    if !command.is_empty() {
+        commands.push(command);
+    }
+    commands
+}
+
+struct Importer<'a> {
+    sink: &'a mut dyn Sink,
+    options: &'a ImportFileOptions,
+    /// Whether the collection still has to be dropped. That waits until a
+    /// batch was read, so a file that can't be read leaves it alone.
+    drop: bool,
+    batch: Vec<Document>,
+    progress: ImportProgress,
+    bytes_read: Rc<Cell<u64>>,
+    on_progress: &'a mut dyn FnMut(&ImportProgress),
+}
+
+impl Importer<'_> {
+    fn run(mut self, reader: impl BufRead, format: ImportFormat) -> Result<ImportProgress, Error> {
+        match format {
+            ImportFormat::Json => read_json_array(reader, &mut self)?,
+            ImportFormat::Ndjson => read_ndjson(reader, &mut self)?,
+            ImportFormat::Csv => read_csv(reader, &mut self)?,
+        }
+        self.flush()?;
+        Ok(self.progress)
+    }
+
+    fn push(&mut self, document: Document) -> Result<(), Error> {
+        self.batch.push(document);
+        self.progress.read += 1;
+        if self.batch.len() >= self.options.batch_size.max(1) {
+            self.flush()?;
+        }
+        Ok(())
+    }
+
+    fn flush(&mut self) -> Result<(), Error> {
+        if std::mem::take(&mut self.drop) {
+            self.sink.drop()?;
+        }
+        let batch = std::mem::take(&mut self.batch);
+        if batch.is_empty() {
+            return Ok(());
+        }
+        match self.options.mode {
+            ImportMode::Insert => self.insert(batch)?,
+            ImportMode::Upsert => self.upsert(batch)?,
+        }
+        self.progress.bytes_read = self.bytes_read.get();
+        (self.on_progress)(&self.progress);
+        Ok(())
+    }
+
+    fn insert(&mut self, batch: Vec<Document>) -> Result<(), Error> {
+        let inserted = self.sink.insert(batch, self.options.stop_on_error)?;
+        self.progress.inserted += inserted.inserted;
+        self.progress.failed += inserted.rejected;
+        match inserted.stopped {
+            Some(err) => Err(err),
+            None => Ok(()),
+        }
+    }
+
+    /// Replaces the documents matching the upsert fields of each document in
+    /// one `update` command. Documents without every upsert field match
+    /// nothing for sure, and are inserted.
+    fn upsert(&mut self, batch: Vec<Document>) -> Result<(), Error> {
+        let mut updates = Vec::new();
+        let mut inserts = Vec::new();
+        for document in batch {
+            match self.upsert_filter(&document) {
+                Some(filter) => {
+                    updates.push(doc! { "q": filter, "u": document, "upsert": true });
+                }
+                None => inserts.push(document),
+            }
+        }
+        if !updates.is_empty() {
+            let upserted = self.sink.upsert(updates, self.options.stop_on_error)?;
+            self.progress.inserted += upserted.inserted;
+            self.progress.updated += upserted.updated;
+            self.progress.failed += upserted.rejected;
+            if let Some(err) = upserted.stopped {
+                return Err(err);
+            }
+        }
+        if !inserts.is_empty() {
+            self.insert(inserts)?;
+        }
+        Ok(())
+    }
+
+    fn upsert_filter(&self, document: &Document) -> Option<Document> {
+        let mut filter = Document::new();
+        for field in &self.options.upsert_fields {
+            filter.insert(field, lookup(document, field)?.clone());
+        }
+        Some(filter)
+    }
+}
+
+/// Finds the value at a dotted path such as `address.city`.
+pub fn lookup<'a>(document: &'a Document, path: &str) -> Option<&'a Bson> {
+    let (head, rest) = match path.split_once('.') {
+        Some((head, rest)) => (head, Some(rest)),
+        None => (path, None),
+    };
+    let value = document.get(head)?;
+    match rest {
+        Some(rest) => lookup(value.as_document()?, rest),
+        None => Some(value),
+    }
+}
+
+/// Turns a JSON value, which can use Extended JSON, into a document.
+fn to_document(value: Value, position: u64) -> Result<Document, Error> {
+    let invalid = |reason: String| {
+        Error::InvalidArgument(format!("document {} is invalid: {}", position, reason))
+    };
+    match Bson::try_from(value).map_err(|err| invalid(err.to_string()))? {
+        Bson::Document(document) => Ok(document),
+        _ => Err(invalid("not an object".to_string())),
+    }
+}
+
+fn read_json_array(reader: impl Read, importer: &mut Importer) -> Result<(), Error> {
+    let mut deserializer = serde_json::Deserializer::from_reader(reader);
+    let mut failure = None;
+    let result = deserializer.deserialize_seq(ArrayVisitor {
+        importer,
+        failure: &mut failure,
+    });
+    if let Some(err) = failure {
+        return Err(err);
+    }
+    result
+        .and_then(|_| deserializer.end())
+        .map_err(|err| Error::InvalidArgument(format!("invalid JSON file: {}", err)))
+}
+
+/// Hands the elements of a JSON array to the importer one at a time, rather
+/// than reading the whole array first.
+struct ArrayVisitor<'a, 'b> {
+    importer: &'a mut Importer<'b>,
+    /// Why the import stopped, when it wasn't the JSON.
+    failure: &'a mut Option<Error>,
+}
+
+impl<'de> Visitor<'de> for ArrayVisitor<'_, '_> {
+    type Value = ();
+
+    fn expecting(&self, formatter: &mut std::fmt::Formatter) -> std::fmt::Result {
+        formatter.write_str("an array of documents")
+    }
+
+    fn visit_seq<A: SeqAccess<'de>>(self, mut seq: A) -> Result<(), A::Error> {
+        while let Some(value) = seq.next_element::<Value>()? {
+            let position = self.importer.progress.read + 1;
+            let pushed = to_document(value, position).and_then(|doc| self.importer.push(doc));
+            if let Err(err) = pushed {
+                *self.failure = Some(err);
+                return Err(de::Error::custom("import aborted"));
+            }
+        }
+        Ok(())
+    }
+}
+
+fn read_ndjson(reader: impl BufRead, importer: &mut Importer) -> Result<(), Error> {
+    for (idx, line) in reader.lines().enumerate() {
+        let line = line.map_err(|err| Error::InvalidArgument(err.to_string()))?;
+        let line = line.trim();
+        if line.is_empty() {
+            continue;
+        }
+        let value = serde_json::from_str(line).map_err(|err| {
+            Error::InvalidArgument(format!("line {} is not valid JSON: {}", idx + 1, err))
+        })?;
+        importer.push(to_document(value, importer.progress.read + 1)?)?;
+    }
+    Ok(())
+}
+
+/// The type of a CSV column, given in the header as `name.type()` like
+/// `mongoimport --columnsHaveTypes` does. Columns without one are `auto`.
+#[derive(Clone, Copy)]
+enum ColumnType {
+    /// Numbers become numbers, anything else a string.
+    Auto,
+    String,
+    Int32,
+    Int64,
+    Double,
+    Decimal,
+    Boolean,
+    /// An RFC 3339 timestamp, or a bare date for midnight UTC.
+    Date,
+    /// Milliseconds since the epoch.
+    DateMs,
+}
+
+struct Column {
+    /// Dotted paths create nested documents.
+    path: String,
+    kind: ColumnType,
+}
+
+impl Column {
+    fn parse(header: &str) -> Result<Self, Error> {
+        let header = header.trim();
+        let Some(typed) = header.strip_suffix(')') else {
+            return Ok(Self {
+                path: header.to_string(),
+                kind: ColumnType::Auto,
+            });
+        };
+        let invalid = || Error::InvalidArgument(format!("invalid CSV column '{}'", header));
+        let (typed, argument) = typed.rsplit_once('(').ok_or_else(invalid)?;
+        let (path, kind) = typed.rsplit_once('.').ok_or_else(invalid)?;
+        let kind = match kind {
+            "auto" => ColumnType::Auto,
+            "string" => ColumnType::String,
+            "int32" => ColumnType::Int32,
+            "int64" => ColumnType::Int64,
+            "double" => ColumnType::Double,
+            "decimal" => ColumnType::Decimal,
+            "boolean" => ColumnType::Boolean,
+            "date" => ColumnType::Date,
+            "date_ms" => ColumnType::DateMs,
+            _ => {
+                return Err(Error::InvalidArgument(format!(
+                    "unknown type '{}' of CSV column '{}'",
+                    kind, path
+                )))
+            }
+        };
+        if !argument.is_empty() {
+            return Err(Error::InvalidArgument(format!(
+                "CSV column '{}' has an argument, custom formats are not supported",
+                path
+            )));
+        }
+        Ok(Self {
+            path: path.to_string(),
+            kind,
+        })
+    }
+
+    fn value(&self, field: &str) -> Option<Bson> {
+        let value = match self.kind {
+            ColumnType::Auto => {
+                if let Ok(n) = field.parse::<i32>() {
+                    Bson::Int32(n)
+                } else if let Ok(n) = field.parse::<i64>() {
+                    Bson::Int64(n)
+                } else if let Some(n) = field.parse::<f64>().ok().filter(|n| n.is_finite()) {
+                    Bson::Double(n)
+                } else {
+                    Bson::String(field.to_string())
+                }
+            }
+            ColumnType::String => Bson::String(field.to_string()),
+            ColumnType::Int32 => Bson::Int32(field.trim().parse().ok()?),
+            ColumnType::Int64 => Bson::Int64(field.trim().parse().ok()?),
+            ColumnType::Double => Bson::Double(field.trim().parse().ok()?),
+            ColumnType::Decimal => Bson::Decimal128(Decimal128::from_str(field.trim()).ok()?),
+            ColumnType::Boolean => match field.trim().to_ascii_lowercase().as_str() {
+                "true" | "t" | "1" => Bson::Boolean(true),
+                "false" | "f" | "0" | "" => Bson::Boolean(false),
+                _ => return None,
+            },
+            ColumnType::Date => Bson::DateTime(parse_date(field)?),
+            ColumnType::DateMs => Bson::DateTime(DateTime::from_millis(field.trim().parse().ok()?)),
+        };
+        Some(value)
+    }
+}
+
+/// Sets the value at a dotted path, creating the documents on the way.
+fn insert_path(document: &mut Document, path: &str, value: Bson) -> Result<(), String> {
+    match path.split_once('.') {
+        None => {
+            document.insert(path, value);
+            Ok(())
+        }
+        Some((head, rest)) => {
+            let child = document
+                .entry(head.to_string())
+                .or_insert_with(|| Bson::Document(Document::new()));
+            match child {
+                Bson::Document(child) => insert_path(child, rest, value),
+                _ => Err(format!("'{}' is both a value and a document", head)),
+            }
+        }
+    }
+}
+
+fn read_csv(reader: impl Read, importer: &mut Importer) -> Result<(), Error> {
+    // Rows of the wrong length are checked below, to name the line.
+    let mut reader = csv::ReaderBuilder::new().flexible(true).from_reader(reader);
+    let columns = reader
+        .headers()
+        .map_err(|err| Error::InvalidArgument(format!("invalid CSV header: {}", err)))?
+        .iter()
+        .map(Column::parse)
+        .collect::<Result<Vec<_>, _>>()?;
+
+    for record in reader.records() {
+        let record =
+            record.map_err(|err| Error::InvalidArgument(format!("invalid CSV file: {}", err)))?;
+        let line = record.position().map_or(0, |position| position.line());
+        if record.len() != columns.len() {
+            return Err(Error::InvalidArgument(format!(
+                "line {} has {} fields, the header has {}",
+                line,
+                record.len(),
+                columns.len()
+            )));
+        }
+        let mut document = Document::new();
+        for (column, field) in columns.iter().zip(record.iter()) {
+            if field.is_empty() && importer.options.ignore_blanks {
+                continue;
+            }
+            let value = column.value(field).ok_or_else(|| {
+                Error::InvalidArgument(format!(
+                    "line {}: '{}' is not a valid value for column '{}'",
+                    line, field, column.path
+                ))
+            })?;
+            insert_path(&mut document, &column.path, value)
+                .map_err(|err| Error::InvalidArgument(format!("line {}: {}", line, err)))?;
+        }
+        importer.push(document)?;
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// Records what the importer sends, and rejects documents with a `bad`
+    /// field like the server would with a validator.
+    #[derive(Default)]
+    struct FakeSink {
+        calls: Vec<String>,
+    }
+
+    impl Sink for FakeSink {
+        fn drop(&mut self) -> Result<(), Error> {
+            self.calls.push("drop".to_string());
+            Ok(())
+        }
+
+        fn insert(&mut self, batch: Vec<Document>, ordered: bool) -> Result<Inserted, Error> {
+            self.calls.push(format!("insert {}", batch.len()));
+            let rejected = batch.iter().position(|doc| doc.contains_key("bad"));
+            Ok(match (rejected, ordered) {
+                (None, _) => Inserted {
+                    inserted: batch.len() as u64,
+                    rejected: 0,
+                    stopped: None,
+                },
+                (Some(index), true) => Inserted {
+                    inserted: index as u64,
+                    rejected: 1,
+                    stopped: Some(Error::InvalidArgument("rejected".to_string())),
+                },
+                (Some(_), false) => {
+                    let rejected = batch.iter().filter(|doc| doc.contains_key("bad")).count();
+                    Inserted {
+                        inserted: (batch.len() - rejected) as u64,
+                        rejected: rejected as u64,
+                        stopped: None,
+                    }
+                }
+            })
+        }
+
+        fn upsert(&mut self, updates: Vec<Document>, _: bool) -> Result<Upserted, Error> {
+            self.calls.push(format!("upsert {}", updates.len()));
+            Ok(Upserted {
+                inserted: 0,
+                updated: updates.len() as u64,
+                rejected: 0,
+                stopped: None,
+            })
+        }
+    }
+
+    fn import(
+        sink: &mut FakeSink,
+        options: &ImportFileOptions,
+        format: ImportFormat,
+        input: &str,
+    ) -> Result<ImportProgress, Error> {
+        let importer = Importer {
+            sink,
+            options,
+            drop: options.drop,
+            batch: Vec::new(),
+            progress: ImportProgress::default(),
+            bytes_read: Rc::new(Cell::new(0)),
+            on_progress: &mut |_| {},
+        };
+        importer.run(input.as_bytes(), format)
+    }
+
+    #[test]
+    fn drops_the_collection_once_a_batch_was_read() {
+        let options = ImportFileOptions {
+            batch_size: 2,
+            drop: true,
+            ..Default::default()
+        };
+        let mut sink = FakeSink::default();
+        let progress = import(
+            &mut sink,
+            &options,
+            ImportFormat::Ndjson,
+            "{\"a\": 1}\n{\"a\": 2}\n\n{\"a\": 3}\n",
+        )
+        .unwrap();
+        assert_eq!(sink.calls, ["drop", "insert 2", "insert 1"]);
+        assert_eq!((progress.read, progress.inserted), (3, 3));
+
+        let mut sink = FakeSink::default();
+        assert!(import(
+            &mut sink,
+            &options,
+            ImportFormat::Ndjson,
+            "{\"a\": 1}\nnope\n"
+        )
+        .is_err());
+        assert!(sink.calls.is_empty());
+    }
+
+    #[test]
+    fn stop_on_error_stops_at_the_first_rejected_document() {
+        let input = "[{\"a\": 1}, {\"bad\": 1}, {\"a\": 2}, {\"a\": 3}]";
+        let mut options = ImportFileOptions {
+            batch_size: 2,
+            ..Default::default()
+        };
+        let mut sink = FakeSink::default();
+        let progress = import(&mut sink, &options, ImportFormat::Json, input).unwrap();
+        assert_eq!(sink.calls, ["insert 2", "insert 2"]);
+        assert_eq!((progress.inserted, progress.failed), (3, 1));
+
+        options.stop_on_error = true;
+        let mut sink = FakeSink::default();
+        let err = import(&mut sink, &options, ImportFormat::Json, input)
+            .err()
+            .unwrap();
+        assert!(err.to_string().contains("rejected"), "{}", err);
+        assert_eq!(sink.calls, ["insert 2"]);
+    }
+
+    #[test]
+    fn documents_without_the_upsert_fields_are_inserted() {
+        let options = ImportFileOptions {
+            mode: ImportMode::Upsert,
+            ..Default::default()
+        };
+        let mut sink = FakeSink::default();
+        let progress = import(
+            &mut sink,
+            &options,
+            ImportFormat::Ndjson,
+            "{\"_id\": 1}\n{\"name\": \"a\"}\n{\"_id\": 2}\n{\"name\": \"b\"}\n",
+        )
+        .unwrap();
+        assert_eq!(sink.calls, ["upsert 2", "insert 2"]);
+        assert_eq!((progress.inserted, progress.updated), (2, 2));
+    }
+
+    #[test]
+    fn csv_rows_have_to_match_the_header() {
+        let options = ImportFileOptions::default();
+        let mut sink = FakeSink::default();
+        let err = import(&mut sink, &options, ImportFormat::Csv, "a,b\n1,2\n3\n")
+            .err()
+            .unwrap();
+        assert!(err.to_string().contains("line 3 has 1 fields"), "{}", err);
+
+        let err = import(&mut sink, &options, ImportFormat::Csv, "a,b\n1,2,3\n")
+            .err()
+            .unwrap();
+        assert!(err.to_string().contains("line 2 has 3 fields"), "{}", err);
+    }
+
+    #[test]
+    fn update_replies_are_read() {
+        let reply: UpdateReply = mongodb::bson::from_document(doc! {
+            "n": 3,
+            "nModified": 1,
+            "upserted": [{ "index": 2, "_id": 7 }],
+            "writeErrors": [{ "index": 1, "code": 121, "errmsg": "Document failed validation" }],
+            "ok": 1.0,
+        })
+        .unwrap();
+        assert_eq!((reply.n, reply.upserted.len()), (3, 1));
+        let errors = reply.failure.write_errors.unwrap();
+        assert_eq!((errors[0].index, errors[0].code), (1, 121));
+    }
+
+    #[test]
+    fn large_upserts_are_split_into_several_commands() {
+        let big = "x".repeat(MAX_BSON_SIZE / 3);
+        let updates = (0..4)
+            .map(|i| doc! { "q": { "_id": i }, "u": { "s": &big }, "upsert": true })
+            .collect::<Vec<_>>();
+        let sizes = split_commands(updates)
+            .iter()
+            .map(Vec::len)
+            .collect::<Vec<_>>();
+        assert_eq!(sizes, [2, 2]);
+    }
+
+    fn column(header: &str) -> Column {
+        Column::parse(header).unwrap()
+    }
+
+    #[test]
+    fn columns_carry_their_type() {
+        let plain = column(" name ");
+        assert_eq!(plain.path, "name");
+        assert!(matches!(plain.kind, ColumnType::Auto));
+
+        let typed = column("address.zip.int32()");
+        assert_eq!(typed.path, "address.zip");
+        assert!(matches!(typed.kind, ColumnType::Int32));
+        assert!(matches!(column("at.date_ms()").kind, ColumnType::DateMs));
+
+        assert!(Column::parse("at.date(2006-01-02)").is_err());
+        assert!(Column::parse("n.uint8()").is_err());
+        assert!(Column::parse("n()").is_err());
+    }
+
+    #[test]
+    fn fields_are_converted_to_the_column_type() {
+        let value = |header: &str, field: &str| column(header).value(field);
+
+        assert_eq!(value("n", "42"), Some(Bson::Int32(42)));
+        assert_eq!(value("n", "4294967296"), Some(Bson::Int64(4294967296)));
+        assert_eq!(value("n", "1.5"), Some(Bson::Double(1.5)));
+        assert_eq!(value("n", "NaN"), Some(Bson::String("NaN".to_string())));
+        assert_eq!(value("n", "007x"), Some(Bson::String("007x".to_string())));
+        assert_eq!(
+            value("n.string()", "42"),
+            Some(Bson::String("42".to_string()))
+        );
+        assert_eq!(value("n.int64()", " 42 "), Some(Bson::Int64(42)));
+        assert_eq!(value("n.int32()", "4294967296"), None);
+        assert_eq!(
+            value("n.decimal()", "0.1"),
+            Some(Bson::Decimal128("0.1".parse().unwrap()))
+        );
+        assert_eq!(value("b.boolean()", "T"), Some(Bson::Boolean(true)));
+        assert_eq!(value("b.boolean()", ""), Some(Bson::Boolean(false)));
+        assert_eq!(value("b.boolean()", "yes"), None);
+        assert_eq!(
+            value("d.date()", "2024-03-01"),
+            Some(Bson::DateTime(DateTime::from_millis(1709251200000)))
+        );
+        assert_eq!(
+            value("d.date_ms()", "1709251200000"),
+            Some(Bson::DateTime(DateTime::from_millis(1709251200000)))
+        );
+        assert_eq!(value("d.date()", "yesterday"), None);
+    }
+
+    #[test]
+    fn dotted_paths_build_nested_documents() {
+        let mut document = Document::new();
+        insert_path(&mut document, "name", Bson::from("a")).unwrap();
+        insert_path(&mut document, "address.city", Bson::from("Oslo")).unwrap();
+        insert_path(&mut document, "address.geo.lat", Bson::from(59.9)).unwrap();
+        assert_eq!(
+            document,
+            doc! { "name": "a", "address": { "city": "Oslo", "geo": { "lat": 59.9 } } }
+        );
+
+        let err = insert_path(&mut document, "name.first", Bson::from("b")).unwrap_err();
+        assert!(err.contains("'name'"), "{}", err);
+    }
+}
@@ -1,66 +1,50 @@
 // Prevents additional console window on Windows in release, DO NOT REMOVE!!
 #![cfg_attr(not(debug_assertions), windows_subsystem = "windows")]
-use boa_engine::{self, JsError};
-use lazy_static::lazy_static;
-use mongodb::bson::doc;
+use boa_engine::{self};
+use mongodb::bson::{doc, Bson};
 use mongodb::{self};
 use serde_json::Value;
-use std::sync::RwLock;
+use std::path::Path;
+use std::rc::Rc;
+use std::time::{Duration, Instant};
+use tauri::Manager;
 
-use engine::{bson::JsObjectId, js_to_bson, Collection, Db};
+use client::{ConnectionInfo, ConnectionProfile, SyncClientEntry, CLIENTS};
+use connections::{ExportOptions, ImportOptions, ImportSummary};
+use copy::{CopyDatabaseOptions, CopyOptions, CopyProgress, Endpoint};
+use diagnostics::{ConnectionDiagnostics, TestConnectionOptions};
+use diff::{DiffOptions, DiffSide, DiffSummary};
+use dump::{DumpOptions, DumpProgress, RestoreOptions};
+use engine::{
+    bson::JsObjectId,
+    js_to_bson,
+    loader::{self, ScriptLoader},
+    params, Collection, Db, ScriptError, UserScript,
+};
+use error::Error;
+use export::{ExportFormat, ExportProgress, ExportResultsOptions, ExportSource};
+use import::{ImportFileOptions, ImportFormat, ImportProgress};
+mod client;
+mod connections;
+mod copy;
+mod data_dir;
 mod db;
+mod diagnostics;
+mod diff;
+mod dump;
 mod engine;
-
-lazy_static! {
-    static ref CLIENTS: RwLock<Vec<SyncClientEntry>> = RwLock::new(Vec::new());
-}
-
-#[derive(thiserror::Error, Debug)]
-enum Error {
-    #[error("Invalid Argument: {}", .0)]
-    InvalidArgument(String),
-
-    #[error("Something Went Wrong")]
-    SomethingWentWrong,
-
-    #[error("{0}")]
-    JsExecution(#[from] JsError),
-
-    #[error("Mongo Error: {0}")]
-    Mongo(#[from] mongodb::error::Error),
-
-    #[error("Sqlite Error: {0}")]
-    Sqlite(#[from] rusqlite::Error),
-}
-
-impl serde::Serialize for Error {
-    fn serialize<S>(&self, serializer: S) -> Result<S::Ok, S::Error>
-    where
-        S: serde::ser::Serializer,
-    {
-        serializer.serialize_str(self.to_string().as_str())
-    }
-}
+mod error;
+mod export;
+mod import;
+mod proxy;
+mod tunnel;
+mod vault;
 
 #[tauri::command]
 async fn greet(name: String) -> String {
     format!("Hello, {}! You've been greeted from Rust!", name)
 }
 
-struct SyncClientEntry {
-    client: mongodb::sync::Client,
-    id: String,
-}
-impl SyncClientEntry {
-    fn new(uri: String) -> Result<Self, Error> {
-        let client = mongodb::sync::Client::with_uri_str(uri)?;
-        Ok(Self {
-            id: uuid::Uuid::new_v4().to_string(),
-            client,
-        })
-    }
-}
-
 #[derive(serde::Serialize)]
 struct ConnectDbResponse {
     id: String,
@@ -68,24 +52,44 @@ struct ConnectDbResponse {
 }
 
 #[tauri::command]
-async fn connect_db(uri: String, name: Option<String>) -> Result<ConnectDbResponse, Error> {
-    let entry = SyncClientEntry::new(uri.clone())?;
+async fn connect_db(
+    window: tauri::Window,
+    uri: String,
+    name: Option<String>,
+    profile: Option<ConnectionProfile>,
+) -> Result<ConnectDbResponse, Error> {
+    let profile = profile.unwrap_or_default();
+    let name = name.filter(|name| !name.is_empty());
+    if let Some(name) = &name {
+        // Fail before connecting rather than after.
+        if db::is_name_taken(name)? {
+            return Err(Error::Conflict(format!(
+                "a saved connection named '{}' already exists",
+                name
+            )));
+        }
+        if vault::split_credentials(&uri).1.is_some() || profile.has_secrets() {
+            vault::ensure_unlocked()?;
+        }
+        profile.validate(&uri)?;
+    }
+
+    let mut entry = SyncClientEntry::new(uri.clone(), profile.clone(), window.label())?;
     let id = entry.id.clone();
     let dbs = entry.client.list_database_names(None, None)?;
 
     if let Some(name) = name {
-        if !name.is_empty() {
-            db::create_saved_db(name, uri)?
-        }
+        entry.saved_db_id = Some(db::create_saved_db(name, uri, &profile)?.id);
     }
 
-    CLIENTS.write().unwrap().push(entry);
+    CLIENTS.write()?.push(entry);
     Ok(ConnectDbResponse { id, dbs })
 }
 
 #[tauri::command]
 async fn get_collection_names(client_id: String, db_name: String) -> Result<Vec<String>, Error> {
-    let entry_borrow = CLIENTS.read().map_err(|_| Error::SomethingWentWrong)?;
+    client::select_db(&client_id, &db_name)?;
+    let entry_borrow = CLIENTS.read()?;
     let entry = entry_borrow
         .iter()
         .find(|c| c.id == client_id)
@@ -100,51 +104,655 @@ async fn get_collection_names(client_id: String, db_name: String) -> Result<Vec<
 }
 
 #[tauri::command]
-async fn exec_script(client_id: String, db_name: String, script: String) -> Result<Value, Error> {
-    let client_borrow = CLIENTS.read().map_err(|_| Error::SomethingWentWrong)?;
+async fn exec_script(
+    window: tauri::Window,
+    client_id: String,
+    db_name: String,
+    script: Option<String>,
+    script_id: Option<i32>,
+    params: Option<serde_json::Map<String, Value>>,
+) -> Result<Value, Error> {
+    let info = client::connection_info(&client_id)?;
+    let script = match (script, script_id) {
+        (Some(script), None) => script,
+        (None, Some(id)) => {
+            let saved = db::get_saved_script(id)?;
+            if saved.saved_db_id.is_some() && saved.saved_db_id != info.saved_db_id {
+                return Err(Error::InvalidArgument(format!(
+                    "script '{}' is bound to another connection",
+                    saved.name
+                )));
+            }
+            if let Some(database) = saved.database.filter(|database| *database != db_name) {
+                return Err(Error::InvalidArgument(format!(
+                    "script '{}' is bound to database '{}', not '{}'",
+                    saved.name, database, db_name
+                )));
+            }
+            saved.body
+        }
+        _ => {
+            return Err(Error::InvalidArgument(
+                "pass either a script or the id of a saved script".to_string(),
+            ))
+        }
+    };
+    let params = params::bind_params(
+        &params::declared_params(&script)?,
+        params.unwrap_or_default(),
+    )?;
+    client::select_db(&client_id, &db_name)?;
+
+    let started = Instant::now();
+    let result = run_script(&client_id, &db_name, &script, params);
+    let entry = db::NewHistoryEntry {
+        saved_db_id: info.saved_db_id,
+        host: info.host,
+        database: db_name,
+        script,
+        duration_ms: started.elapsed().as_millis() as i64,
+        result_count: result.as_ref().ok().map(result_count),
+        error: result.as_ref().err().map(ToString::to_string),
+    };
+    // Losing a history entry shouldn't fail the script, only be reported.
+    if let Err(err) = db::record_history(entry) {
+        let _ = window.emit("history-error", &err);
+    }
+    Ok(result?.into())
+}
+
+/// Number of documents in a script's result, for the history.
+fn result_count(result: &Bson) -> i64 {
+    match result {
+        Bson::Array(values) => values.len() as i64,
+        Bson::Null | Bson::Undefined => 0,
+        _ => 1,
+    }
+}
+
+fn run_script(
+    client_id: &str,
+    db_name: &str,
+    script: &str,
+    params: Vec<(String, Bson)>,
+) -> Result<Bson, Error> {
+    let client_borrow = CLIENTS.read()?;
     let client = client_borrow
         .iter()
         .find(|c| c.id == client_id)
         .ok_or(Error::InvalidArgument("client not found".to_string()))?;
 
-    let mut context = boa_engine::Context::default();
+    let loader = Rc::new(ScriptLoader::new(
+        db::get_scripts_dir()?,
+        client.sources.clone(),
+    ));
+    let mut context = boa_engine::Context::builder()
+        .module_loader(loader.clone())
+        .build()?;
+    loader::register_load(loader, &mut context)?;
     context.register_global_class::<Db>()?;
     context.register_global_class::<Collection>()?;
     context.register_global_class::<JsObjectId>()?;
 
-    let db_initiation = format!("const db = new Db('{}', '{}');", db_name, client.id);
-    context.eval(boa_engine::Source::from_bytes(db_initiation.as_str()))?;
+    let script = UserScript::parse(script, &mut context)?;
+
+    Db::register(db_name, &client.id, script.promises, &mut context)?;
+    params::inject(params, &mut context)?;
+
+    let js_value = script.evaluate(&mut context)?;
+    let bson =
+        js_to_bson(js_value, &mut context).map_err(|err| ScriptError::new(err, 0, &mut context))?;
+    Ok(bson)
+}
+
+#[tauri::command]
+async fn get_saved_dbs() -> Result<Vec<db::SavedDb>, Error> {
+    db::get_dbs()
+}
+
+#[tauri::command]
+async fn connect_saved_db(window: tauri::Window, id: i32) -> Result<ConnectDbResponse, Error> {
+    let profile = db::get_db_profile(id)?;
+    let uri = db::get_db_uri(id)?;
+    let mut entry = SyncClientEntry::new(uri, profile, window.label())?;
+    entry.saved_db_id = Some(id);
+    let client_id = entry.id.clone();
+    let dbs = entry.client.list_database_names(None, None)?;
+    CLIENTS.write()?.push(entry);
+    db::touch_saved_db(id)?;
+    Ok(ConnectDbResponse { id: client_id, dbs })
+}
+
+#[tauri::command]
+async fn update_saved_db(
+    id: i32,
+    name: String,
+    uri: String,
+    profile: Option<ConnectionProfile>,
+    clear_credentials: Option<bool>,
+) -> Result<db::SavedDb, Error> {
+    db::update_saved_db(
+        id,
+        name,
+        uri,
+        profile.as_ref(),
+        clear_credentials.unwrap_or(false),
+    )
+}
+
+#[tauri::command]
+async fn rename_saved_db(id: i32, name: String) -> Result<db::SavedDb, Error> {
+    db::rename_saved_db(id, name)
+}
+
+#[tauri::command]
+async fn delete_saved_db(id: i32) -> Result<(), Error> {
+    db::delete_saved_db(id)
+}
+
+#[tauri::command]
+async fn duplicate_saved_db(id: i32, name: Option<String>) -> Result<db::SavedDb, Error> {
+    db::duplicate_saved_db(id, name)
+}
+
+#[tauri::command]
+async fn search_saved_dbs(filter: Option<db::SavedDbFilter>) -> Result<Vec<db::SavedDb>, Error> {
+    db::search_saved_dbs(&filter.unwrap_or_default())
+}
+
+#[tauri::command]
+async fn set_favorite(id: i32, favorite: bool) -> Result<db::SavedDb, Error> {
+    db::set_favorite(id, favorite)
+}
+
+#[tauri::command]
+async fn set_tags(id: i32, tags: Vec<String>) -> Result<db::SavedDb, Error> {
+    db::set_tags(id, tags)
+}
+
+#[tauri::command]
+async fn move_saved_db(
+    id: i32,
+    folder_id: Option<i32>,
+    position: Option<usize>,
+) -> Result<db::SavedDb, Error> {
+    db::move_saved_db(id, folder_id, position)
+}
+
+#[tauri::command]
+async fn reorder_saved_dbs(folder_id: Option<i32>, ids: Vec<i32>) -> Result<(), Error> {
+    db::reorder_saved_dbs(folder_id, &ids)
+}
+
+#[tauri::command]
+async fn get_folders() -> Result<Vec<db::Folder>, Error> {
+    db::get_folders()
+}
+
+#[tauri::command]
+async fn create_folder(name: String) -> Result<db::Folder, Error> {
+    db::create_folder(name)
+}
+
+#[tauri::command]
+async fn rename_folder(id: i32, name: String) -> Result<(), Error> {
+    db::rename_folder(id, name)
+}
+
+#[tauri::command]
+async fn delete_folder(id: i32) -> Result<(), Error> {
+    db::delete_folder(id)
+}
+
+#[tauri::command]
+async fn reorder_folders(ids: Vec<i32>) -> Result<(), Error> {
+    db::reorder_folders(&ids)
+}
+
+#[tauri::command]
+async fn export_connections(path: String, options: Option<ExportOptions>) -> Result<usize, Error> {
+    connections::export_connections(path.as_ref(), options.unwrap_or_default())
+}
+
+#[tauri::command]
+async fn import_connections(
+    path: String,
+    options: Option<ImportOptions>,
+) -> Result<ImportSummary, Error> {
+    connections::import_connections(path.as_ref(), options.unwrap_or_default())
+}
+
+#[tauri::command]
+async fn get_history(
+    filter: Option<db::HistoryFilter>,
+    paging: Option<db::Paging>,
+) -> Result<db::HistoryPage, Error> {
+    db::get_history(&filter.unwrap_or_default(), &paging.unwrap_or_default())
+}
+
+#[tauri::command]
+async fn delete_history(ids: Vec<i64>) -> Result<usize, Error> {
+    db::delete_history(&ids)
+}
+
+#[tauri::command]
+async fn clear_history() -> Result<usize, Error> {
+    db::clear_history()
+}
 
-    let js_value = context.eval(boa_engine::Source::from_bytes(script.as_str()))?;
-    let bson = js_to_bson(js_value, &mut context)?;
-    Ok(bson.into())
+#[tauri::command]
+async fn get_history_retention() -> Result<db::HistoryRetention, Error> {
+    db::get_history_retention()
+}
+
+#[tauri::command]
+async fn set_history_retention(retention: db::HistoryRetention) -> Result<(), Error> {
+    db::set_history_retention(&retention)
+}
+
+/// Lists the parameters `script` declares, for the UI to ask for.
+#[tauri::command]
+async fn get_script_params(script: String) -> Result<Vec<params::ScriptParam>, Error> {
+    params::declared_params(&script)
+}
+
+#[tauri::command]
+async fn save_workspace(saved_db_id: Option<i32>, workspace: db::Workspace) -> Result<(), Error> {
+    db::save_workspace(saved_db_id, &workspace)
+}
+
+#[tauri::command]
+async fn load_workspace(saved_db_id: Option<i32>) -> Result<db::Workspace, Error> {
+    db::load_workspace(saved_db_id)
+}
+
+#[tauri::command]
+async fn get_scripts_dir() -> Result<Option<String>, Error> {
+    Ok(db::get_scripts_dir()?.map(|dir| dir.to_string_lossy().into_owned()))
+}
+
+#[tauri::command]
+async fn set_scripts_dir(dir: Option<String>) -> Result<(), Error> {
+    db::set_scripts_dir(dir.as_deref().map(Path::new))
+}
+
+#[tauri::command]
+async fn get_saved_scripts() -> Result<Vec<db::SavedScript>, Error> {
+    db::get_saved_scripts()
 }
 
 #[tauri::command]
-async fn get_saved_dbs() -> Result<Vec<db::SavedDb>, String> {
-    let dbs = db::get_dbs();
-    let res: Result<Vec<db::SavedDb>, String> = match dbs {
-        Ok(dbs) => Ok(dbs),
-        Err(err) => {
-            println!("{}", err);
-            Err(err.to_string())
+async fn get_saved_script(id: i32) -> Result<db::SavedScript, Error> {
+    db::get_saved_script(id)
+}
+
+#[tauri::command]
+async fn create_saved_script(script: db::SavedScriptInput) -> Result<db::SavedScript, Error> {
+    db::create_saved_script(&script)
+}
+
+#[tauri::command]
+async fn update_saved_script(
+    id: i32,
+    script: db::SavedScriptInput,
+) -> Result<db::SavedScript, Error> {
+    db::update_saved_script(id, &script)
+}
+
+#[tauri::command]
+async fn delete_saved_script(id: i32) -> Result<(), Error> {
+    db::delete_saved_script(id)
+}
+
+#[tauri::command]
+async fn search_saved_scripts(
+    filter: Option<db::SavedScriptFilter>,
+) -> Result<Vec<db::SavedScript>, Error> {
+    db::search_saved_scripts(&filter.unwrap_or_default())
+}
+
+/// Imports a JSON, NDJSON or CSV file into a collection, emitting
+/// `import-progress` after every batch.
+#[tauri::command]
+async fn import_file(
+    window: tauri::Window,
+    client_id: String,
+    db: String,
+    coll: String,
+    path: String,
+    format: ImportFormat,
+    options: Option<ImportFileOptions>,
+) -> Result<ImportProgress, Error> {
+    let database = client::get_writable_client(&client_id)?.database(&db);
+    import::import_file(
+        database,
+        &coll,
+        path.as_ref(),
+        format,
+        &options.unwrap_or_default(),
+        &mut |progress| {
+            let _ = window.emit("import-progress", progress);
+        },
+    )
+}
+
+/// Writes the documents of a query, a pipeline or a script to a file,
+/// emitting `export-progress` as it goes.
+#[tauri::command]
+async fn export_results(
+    window: tauri::Window,
+    client_id: String,
+    db: String,
+    source: ExportSource,
+    path: String,
+    format: ExportFormat,
+    options: Option<ExportResultsOptions>,
+) -> Result<ExportProgress, Error> {
+    let database = client::get_client(&client_id)?.database(&db);
+    let documents: Box<dyn Iterator<Item = Result<mongodb::bson::Document, Error>>> = match source {
+        ExportSource::Find {
+            collection,
+            filter,
+            projection,
+            sort,
+            skip,
+            limit,
+        } => {
+            let options = mongodb::options::FindOptions::builder()
+                .projection(export::query_document(projection, "projection")?)
+                .sort(export::query_document(sort, "sort")?)
+                .skip(skip)
+                .limit(limit)
+                .build();
+            let cursor = database
+                .collection(&collection)
+                .find(export::query_document(filter, "filter")?, options)?;
+            Box::new(cursor.map(|document| document.map_err(Error::from)))
+        }
+        ExportSource::Aggregate {
+            collection,
+            pipeline,
+        } => {
+            let pipeline = pipeline
+                .into_iter()
+                .map(|stage| {
+                    export::query_document(Some(stage), "pipeline stage")
+                        .map(Option::unwrap_or_default)
+                })
+                .collect::<Result<Vec<_>, _>>()?;
+            if export::writes_output(&pipeline) {
+                client::get_writable_client(&client_id)?;
+            }
+            let cursor = database
+                .collection::<mongodb::bson::Document>(&collection)
+                .aggregate(pipeline, None)?;
+            Box::new(cursor.map(|document| document.map_err(Error::from)))
+        }
+        ExportSource::Script { script, params } => {
+            let declared = params::declared_params(&script)?;
+            let bound = params::bind_params(&declared, params.unwrap_or_default())?;
+            let documents = match run_script(&client_id, &db, &script, bound)? {
+                Bson::Array(values) => values,
+                Bson::Null | Bson::Undefined => Vec::new(),
+                value => vec![value],
+            };
+            Box::new(documents.into_iter().map(|value| match value {
+                Bson::Document(document) => Ok(document),
+                _ => Err(Error::InvalidArgument(
+                    "the script must return documents".to_string(),
+                )),
+            }))
         }
     };
-    res
+    export::export_results(
+        documents,
+        path.as_ref(),
+        format,
+        &options.unwrap_or_default(),
+        &mut |progress| {
+            let _ = window.emit("export-progress", progress);
+        },
+    )
 }
 
+/// Dumps databases in the layout of `mongodump`, emitting `dump-progress`
+/// as it goes.
 #[tauri::command]
-async fn connect_saved_db(id: i32) -> Result<ConnectDbResponse, Error> {
-    let res = db::get_db(id)?;
-    let entry = SyncClientEntry::new(res.uri)?;
-    let id = entry.id.clone();
-    let dbs = entry.client.list_database_names(None, None)?;
-    CLIENTS.write().unwrap().push(entry);
-    Ok(ConnectDbResponse { id, dbs })
+async fn dump_databases(
+    window: tauri::Window,
+    client_id: String,
+    path: String,
+    options: Option<DumpOptions>,
+) -> Result<DumpProgress, Error> {
+    let client = client::get_client(&client_id)?;
+    dump::dump(
+        &client,
+        path.as_ref(),
+        &options.unwrap_or_default(),
+        &mut |progress| {
+            let _ = window.emit("dump-progress", progress);
+        },
+    )
+}
+
+/// Restores a dump written by `dump_databases` or `mongodump`, emitting
+/// `restore-progress` as it goes.
+#[tauri::command]
+async fn restore_dump(
+    window: tauri::Window,
+    client_id: String,
+    path: String,
+    options: Option<RestoreOptions>,
+) -> Result<DumpProgress, Error> {
+    let client = client::get_writable_client(&client_id)?;
+    dump::restore(
+        &client,
+        path.as_ref(),
+        &options.unwrap_or_default(),
+        &mut |progress| {
+            let _ = window.emit("restore-progress", progress);
+        },
+    )
+}
+
+/// Copies a collection, possibly between two connections, emitting
+/// `copy-progress` as it goes. `copy_id` is what `cancel_copy` takes.
+#[tauri::command]
+#[allow(clippy::too_many_arguments)]
+async fn copy_collection(
+    window: tauri::Window,
+    copy_id: String,
+    src_client: String,
+    src_db: String,
+    src_coll: String,
+    dst_client: String,
+    dst_db: String,
+    dst_coll: String,
+    options: Option<CopyOptions>,
+) -> Result<CopyProgress, Error> {
+    let source = client::get_client(&src_client)?;
+    let target = client::get_writable_client(&dst_client)?;
+    copy::copy_collection(
+        &copy_id,
+        Endpoint {
+            client: &source,
+            client_id: &src_client,
+            database: &src_db,
+        },
+        &src_coll,
+        Endpoint {
+            client: &target,
+            client_id: &dst_client,
+            database: &dst_db,
+        },
+        &dst_coll,
+        &options.unwrap_or_default(),
+        &mut |progress| {
+            let _ = window.emit("copy-progress", progress);
+        },
+    )
+}
+
+/// Copies the collections of a database, possibly between two connections,
+/// emitting `copy-progress` as it goes. `copy_id` is what `cancel_copy`
+/// takes.
+#[tauri::command]
+async fn copy_database(
+    window: tauri::Window,
+    copy_id: String,
+    src_client: String,
+    src_db: String,
+    dst_client: String,
+    dst_db: String,
+    options: Option<CopyDatabaseOptions>,
+) -> Result<CopyProgress, Error> {
+    let source = client::get_client(&src_client)?;
+    let target = client::get_writable_client(&dst_client)?;
+    copy::copy_database(
+        &copy_id,
+        Endpoint {
+            client: &source,
+            client_id: &src_client,
+            database: &src_db,
+        },
+        Endpoint {
+            client: &target,
+            client_id: &dst_client,
+            database: &dst_db,
+        },
+        &options.unwrap_or_default(),
+        &mut |progress| {
+            let _ = window.emit("copy-progress", progress);
+        },
+    )
+}
+
+#[tauri::command]
+async fn cancel_copy(id: String) -> Result<(), Error> {
+    copy::cancel(&id)
+}
+
+/// Compares a collection across two connections by `_id`, emitting
+/// `diff-progress` with the differences as they are found.
+#[tauri::command]
+#[allow(clippy::too_many_arguments)]
+async fn diff_collections(
+    window: tauri::Window,
+    source_client: String,
+    source_db: String,
+    source_coll: String,
+    target_client: String,
+    target_db: String,
+    target_coll: String,
+    options: Option<DiffOptions>,
+) -> Result<DiffSummary, Error> {
+    let source = client::get_client(&source_client)?;
+    let target = client::get_client(&target_client)?;
+    diff::diff_collections(
+        DiffSide {
+            client: &source,
+            host: client::connection_info(&source_client)?.host,
+            database: &source_db,
+            collection: &source_coll,
+        },
+        DiffSide {
+            client: &target,
+            host: client::connection_info(&target_client)?.host,
+            database: &target_db,
+            collection: &target_coll,
+        },
+        options.unwrap_or_default(),
+        &mut |progress| {
+            let _ = window.emit("diff-progress", progress);
+        },
+    )
+}
+
+#[tauri::command]
+async fn store_status() -> Result<db::StoreStatus, Error> {
+    Ok(db::store_status())
+}
+
+#[tauri::command]
+async fn vault_status() -> Result<vault::VaultStatus, Error> {
+    vault::status()
+}
+
+#[tauri::command]
+async fn create_vault(
+    password: String,
+    confirmation: String,
+    idle_timeout_secs: Option<u64>,
+) -> Result<(), Error> {
+    vault::create(
+        &password,
+        &confirmation,
+        idle_timeout_secs.map(Duration::from_secs),
+    )
+}
+
+#[tauri::command]
+async fn unlock_vault(password: String, idle_timeout_secs: Option<u64>) -> Result<(), Error> {
+    vault::unlock(&password, idle_timeout_secs.map(Duration::from_secs))
+}
+
+#[tauri::command]
+async fn lock_vault() -> Result<(), Error> {
+    vault::lock()
+}
+
+#[tauri::command]
+async fn disconnect(client_id: String) -> Result<(), Error> {
+    client::disconnect(&client_id)
+}
+
+#[tauri::command]
+async fn list_connections() -> Result<Vec<ConnectionInfo>, Error> {
+    Ok(CLIENTS.read()?.iter().map(SyncClientEntry::info).collect())
+}
+
+#[tauri::command]
+async fn reconnect(client_id: String) -> Result<ConnectDbResponse, Error> {
+    // Connecting can take as long as server selection, so the registry is
+    // only locked to read the settings and to swap the new client in.
+    let (uri, profile) = {
+        let clients = CLIENTS.read()?;
+        let entry = clients
+            .iter()
+            .find(|c| c.id == client_id)
+            .ok_or(Error::InvalidArgument("client not found".to_string()))?;
+        (entry.uri().to_string(), entry.profile.clone())
+    };
+    let (client, tunnel) = client::connect(&uri, &profile)?;
+    let dbs = client.list_database_names(None, None)?;
+
+    let replaced = CLIENTS
+        .write()?
+        .iter_mut()
+        .find(|c| c.id == client_id)
+        .map(|entry| entry.replace_client(client.clone(), tunnel));
+    let Some((old_client, _old_tunnel)) = replaced else {
+        client.shutdown_immediate();
+        return Err(Error::InvalidArgument("client not found".to_string()));
+    };
+    old_client.shutdown_immediate();
+    Ok(ConnectDbResponse { id: client_id, dbs })
+}
+
+#[tauri::command]
+async fn test_connection(
+    uri: String,
+    profile: Option<ConnectionProfile>,
+    options: Option<TestConnectionOptions>,
+) -> Result<ConnectionDiagnostics, Error> {
+    Ok(diagnostics::test_connection(
+        &uri,
+        &profile.unwrap_or_default(),
+        options.unwrap_or_default(),
+    ))
 }
 
 fn main() {
-    db::ensure_tables();
     tauri::Builder::default()
         .invoke_handler(tauri::generate_handler![
             connect_db,
@@ -152,8 +760,76 @@ fn main() {
             exec_script,
             get_collection_names,
             get_saved_dbs,
-            connect_saved_db
+            connect_saved_db,
+            update_saved_db,
+            rename_saved_db,
+            delete_saved_db,
+            duplicate_saved_db,
+            search_saved_dbs,
+            set_favorite,
+            set_tags,
+            move_saved_db,
+            reorder_saved_dbs,
+            get_folders,
+            create_folder,
+            rename_folder,
+            delete_folder,
+            reorder_folders,
+            export_connections,
+            import_connections,
+            get_history,
+            delete_history,
+            clear_history,
+            get_history_retention,
+            set_history_retention,
+            get_script_params,
+            save_workspace,
+            load_workspace,
+            get_scripts_dir,
+            set_scripts_dir,
+            get_saved_scripts,
+            get_saved_script,
+            create_saved_script,
+            update_saved_script,
+            delete_saved_script,
+            search_saved_scripts,
+            import_file,
+            export_results,
+            dump_databases,
+            restore_dump,
+            copy_collection,
+            copy_database,
+            cancel_copy,
+            diff_collections,
+            store_status,
+            vault_status,
+            create_vault,
+            unlock_vault,
+            lock_vault,
+            disconnect,
+            list_connections,
+            reconnect,
+            test_connection
         ])
+        .setup(|app| {
+            let data_dir = data_dir::resolve(app.path_resolver().app_config_dir());
+            // Reported through store_status, and by every store command.
+            let _ = db::init(data_dir);
+
+            let handle = app.handle();
+            std::thread::spawn(move || loop {
+                std::thread::sleep(Duration::from_secs(30));
+                if vault::lock_if_idle() {
+                    let _ = handle.emit_all("vault-locked", ());
+                }
+            });
+            Ok(())
+        })
+        .on_window_event(|event| {
+            if let tauri::WindowEvent::Destroyed = event.event() {
+                client::reap_window(event.window().label());
+            }
+        })
         .run(tauri::generate_context!())
         .expect("error while running tauri application");
 }
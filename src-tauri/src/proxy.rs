@@ -0,0 +1,436 @@
+use std::net::IpAddr;
+
+use rusqlite::types::{FromSql, FromSqlError, FromSqlResult, ValueRef};
+use serde::{Deserialize, Serialize};
+use tokio::{
+    io::{AsyncReadExt, AsyncWriteExt},
+    net::TcpStream,
+};
+
+use crate::error::Error;
+
+#[derive(Debug, Clone, Copy, Default, PartialEq, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum ProxyKind {
+    #[default]
+    Socks5,
+    /// An HTTP proxy that supports `CONNECT`.
+    Http,
+}
+
+impl ProxyKind {
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            ProxyKind::Socks5 => "socks5",
+            ProxyKind::Http => "http",
+        }
+    }
+
+    pub fn parse(kind: &str) -> Result<Self, Error> {
+        match kind {
+            "socks5" => Ok(ProxyKind::Socks5),
+            "http" => Ok(ProxyKind::Http),
+            _ => Err(Error::InvalidArgument(format!(
+                "unknown proxy kind '{}'",
+                kind
+            ))),
+        }
+    }
+}
+
+impl FromSql for ProxyKind {
+    fn column_result(value: ValueRef) -> FromSqlResult<Self> {
+        Self::parse(value.as_str()?).map_err(|err| FromSqlError::Other(err.to_string().into()))
+    }
+}
+
+/// A proxy the connections to the server are made through.
+#[derive(Debug, Clone, Default, Serialize, Deserialize)]
+#[serde(rename_all = "camelCase", default)]
+pub struct ProxyOptions {
+    pub kind: ProxyKind,
+    pub host: String,
+    /// Defaults to 1080 for SOCKS5 and 8080 for HTTP.
+    pub port: Option<u16>,
+    pub username: Option<String>,
+    pub password: Option<String>,
+}
+
+impl ProxyOptions {
+    pub fn validate(&self) -> Result<(), Error> {
+        if self.host.is_empty() {
+            return Err(Error::InvalidArgument("a proxy needs a host".to_string()));
+        }
+        if self.password.is_some() && self.username.is_none() {
+            return Err(Error::InvalidArgument(
+                "a proxy password needs a username".to_string(),
+            ));
+        }
+        Ok(())
+    }
+
+    pub fn has_secrets(&self) -> bool {
+        self.password.is_some()
+    }
+
+    fn port(&self) -> u16 {
+        self.port.unwrap_or(match self.kind {
+            ProxyKind::Socks5 => 1080,
+            ProxyKind::Http => 8080,
+        })
+    }
+
+    /// Opens a connection to `host:port` through the proxy.
+    pub async fn connect(&self, host: &str, port: u16) -> Result<TcpStream, String> {
+        let mut stream = TcpStream::connect((self.host.as_str(), self.port()))
+            .await
+            .map_err(|err| {
+                format!(
+                    "failed to connect to proxy {}:{}: {}",
+                    self.host,
+                    self.port(),
+                    err
+                )
+            })?;
+        let result = match self.kind {
+            ProxyKind::Socks5 => self.socks5_handshake(&mut stream, host, port).await,
+            ProxyKind::Http => self.http_handshake(&mut stream, host, port).await,
+        };
+        result.map_err(|err| format!("proxy {}:{}: {}", self.host, self.port(), err))?;
+        Ok(stream)
+    }
+
+    /// See RFC 1928 and, for the username and password, RFC 1929.
+    async fn socks5_handshake(
+        &self,
+        stream: &mut TcpStream,
+        host: &str,
+        port: u16,
+    ) -> Result<(), String> {
+        let io = |err: std::io::Error| err.to_string();
+
+        let greeting: &[u8] = match self.username {
+            Some(_) => &[5, 2, 0, 2],
+            None => &[5, 1, 0],
+        };
+        stream.write_all(greeting).await.map_err(io)?;
+        let mut choice = [0u8; 2];
+        stream.read_exact(&mut choice).await.map_err(io)?;
+        match choice {
+            [5, 0] => {}
+            [5, 2] => {
+                let username = self.username.as_deref().unwrap_or_default().as_bytes();
+                let password = self.password.as_deref().unwrap_or_default().as_bytes();
+                if username.len() > 255 || password.len() > 255 {
+                    return Err("the username or password is too long".to_string());
+                }
+                let mut auth = vec![1, username.len() as u8];
+                auth.extend_from_slice(username);
+                auth.push(password.len() as u8);
+                auth.extend_from_slice(password);
+                stream.write_all(&auth).await.map_err(io)?;
+                let mut status = [0u8; 2];
+                stream.read_exact(&mut status).await.map_err(io)?;
+                if status[1] != 0 {
+                    return Err("the username or password was rejected".to_string());
+                }
+            }
+            [5, 0xff] => return Err("no offered authentication method is accepted".to_string()),
+            _ => return Err("not a SOCKS5 proxy".to_string()),
+        }
+
+        let mut request = vec![5, 1, 0];
+        match host.parse::<IpAddr>() {
+            Ok(IpAddr::V4(ip)) => {
+                request.push(1);
+                request.extend_from_slice(&ip.octets());
+            }
+            Ok(IpAddr::V6(ip)) => {
+                request.push(4);
+                request.extend_from_slice(&ip.octets());
+            }
+            Err(_) => {
+                if host.len() > 255 {
+                    return Err("the host name is too long".to_string());
+                }
+                request.push(3);
+                request.push(host.len() as u8);
+                request.extend_from_slice(host.as_bytes());
+            }
+        }
+        request.extend_from_slice(&port.to_be_bytes());
+        stream.write_all(&request).await.map_err(io)?;
+
+        let mut reply = [0u8; 4];
+        stream.read_exact(&mut reply).await.map_err(io)?;
+        if reply[1] != 0 {
+            let reason = match reply[1] {
+                1 => "general failure",
+                2 => "connection not allowed by ruleset",
+                3 => "network unreachable",
+                4 => "host unreachable",
+                5 => "connection refused",
+                6 => "TTL expired",
+                7 => "command not supported",
+                8 => "address type not supported",
+                _ => "unknown error",
+            };
+            return Err(format!("failed to reach {}:{}: {}", host, port, reason));
+        }
+        // Skip the bound address, which is of no use here.
+        let address_len = match reply[3] {
+            1 => 4,
+            4 => 16,
+            3 => {
+                let mut len = [0u8; 1];
+                stream.read_exact(&mut len).await.map_err(io)?;
+                len[0] as usize
+            }
+            _ => return Err("malformed reply".to_string()),
+        };
+        let mut bound = vec![0u8; address_len + 2];
+        stream.read_exact(&mut bound).await.map_err(io)?;
+        Ok(())
+    }
+
+    async fn http_handshake(
+        &self,
+        stream: &mut TcpStream,
+        host: &str,
+        port: u16,
+    ) -> Result<(), String> {
+        let io = |err: std::io::Error| err.to_string();
+
+        let authority = match host.parse::<IpAddr>() {
+            Ok(IpAddr::V6(_)) => format!("[{}]:{}", host, port),
+            _ => format!("{}:{}", host, port),
+        };
+        let mut request = format!("CONNECT {0} HTTP/1.1\r\nHost: {0}\r\n", authority);
+        if let Some(username) = &self.username {
+            let credentials = format!(
+                "{}:{}",
+                username,
+                self.password.as_deref().unwrap_or_default()
+            );
+            request.push_str(&format!(
+                "Proxy-Authorization: Basic {}\r\n",
+                base64(credentials.as_bytes())
+            ));
+        }
+        request.push_str("\r\n");
+        stream.write_all(request.as_bytes()).await.map_err(io)?;
+
+        // Read the response head byte by byte so nothing the server sends
+        // after it is consumed.
+        let mut head = Vec::new();
+        while !head.ends_with(b"\r\n\r\n") {
+            if head.len() > 8192 {
+                return Err("the response head is too long".to_string());
+            }
+            let mut byte = [0u8; 1];
+            stream.read_exact(&mut byte).await.map_err(io)?;
+            head.push(byte[0]);
+        }
+        let head = String::from_utf8_lossy(&head);
+        let status_line = head.lines().next().unwrap_or_default();
+        match status_line.split_whitespace().nth(1) {
+            Some("200") => Ok(()),
+            Some(_) => Err(format!("CONNECT to {} failed: {}", authority, status_line)),
+            None => Err("not an HTTP proxy".to_string()),
+        }
+    }
+}
+
+fn base64(input: &[u8]) -> String {
+    const ALPHABET: &[u8] = b"ABCDEFGHIJKLMNOPQRSTUVWXYZabcdefghijklmnopqrstuvwxyz0123456789+/";
+    let mut output = String::with_capacity(input.len().div_ceil(3) * 4);
+    for chunk in input.chunks(3) {
+        let bytes = [
+            chunk[0],
+            *chunk.get(1).unwrap_or(&0),
+            *chunk.get(2).unwrap_or(&0),
+        ];
+        let n = (bytes[0] as u32) << 16 | (bytes[1] as u32) << 8 | bytes[2] as u32;
+        for i in 0..4 {
+            if i <= chunk.len() {
+                output.push(ALPHABET[(n >> (18 - 6 * i) & 0x3f) as usize] as char);
+            } else {
+                output.push('=');
+            }
+        }
+    }
+    output
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{
+        io::{Read, Write},
+        net::{TcpListener, TcpStream as StdTcpStream},
+        thread::{self, JoinHandle},
+    };
+
+    use super::*;
+    use crate::client::ConnectionProfile;
+
+    /// Accepts a single connection and hands it to `server`, returning the
+    /// port and what `server` read.
+    fn serve(
+        server: impl FnOnce(&mut StdTcpStream) -> Vec<u8> + Send + 'static,
+    ) -> (u16, JoinHandle<Vec<u8>>) {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let port = listener.local_addr().unwrap().port();
+        let handle = thread::spawn(move || {
+            let (mut stream, _) = listener.accept().unwrap();
+            server(&mut stream)
+        });
+        (port, handle)
+    }
+
+    fn read(stream: &mut StdTcpStream, len: usize) -> Vec<u8> {
+        let mut buf = vec![0u8; len];
+        stream.read_exact(&mut buf).unwrap();
+        buf
+    }
+
+    fn read_head(stream: &mut StdTcpStream) -> Vec<u8> {
+        let mut head = Vec::new();
+        while !head.ends_with(b"\r\n\r\n") {
+            head.extend(read(stream, 1));
+        }
+        head
+    }
+
+    fn proxy(kind: ProxyKind, port: u16) -> ProxyOptions {
+        ProxyOptions {
+            kind,
+            host: "127.0.0.1".to_string(),
+            port: Some(port),
+            username: Some("user".to_string()),
+            password: Some("pass".to_string()),
+        }
+    }
+
+    /// Connects through the proxy and reads the `len` bytes the server sends
+    /// after the handshake, which have to be left in the stream.
+    fn connect(options: &ProxyOptions, len: usize) -> Result<Vec<u8>, String> {
+        tokio::runtime::Builder::new_current_thread()
+            .enable_all()
+            .build()
+            .unwrap()
+            .block_on(async {
+                let mut stream = options.connect("db.internal", 27017).await?;
+                let mut rest = vec![0u8; len];
+                stream.read_exact(&mut rest).await.unwrap();
+                Ok(rest)
+            })
+    }
+
+    #[test]
+    fn base64_matches_the_rfc_vectors() {
+        for (input, output) in [
+            ("", ""),
+            ("f", "Zg=="),
+            ("fo", "Zm8="),
+            ("foo", "Zm9v"),
+            ("foob", "Zm9vYg=="),
+            ("fooba", "Zm9vYmE="),
+            ("foobar", "Zm9vYmFy"),
+        ] {
+            assert_eq!(base64(input.as_bytes()), output);
+        }
+    }
+
+    #[test]
+    fn unknown_kinds_are_rejected() {
+        assert_eq!(ProxyKind::parse("http").unwrap(), ProxyKind::Http);
+        assert_eq!(ProxyKind::parse("socks5").unwrap(), ProxyKind::Socks5);
+        assert!(ProxyKind::parse("socks4").is_err());
+    }
+
+    #[test]
+    fn only_a_single_host_can_be_reached() {
+        let profile = ConnectionProfile {
+            proxy: Some(proxy(ProxyKind::Socks5, 1080)),
+            ..Default::default()
+        };
+        profile.validate("mongodb://db.internal:27017").unwrap();
+        assert!(profile.validate("mongodb://a:27017,b:27017").is_err());
+        assert!(profile
+            .validate("mongodb+srv://cluster.example.net")
+            .is_err());
+        ConnectionProfile::default()
+            .validate("mongodb+srv://cluster.example.net")
+            .unwrap();
+    }
+
+    #[test]
+    fn socks5_sends_the_credentials_and_the_host() {
+        let (port, server) = serve(|stream| {
+            let mut seen = read(stream, 4);
+            stream.write_all(&[5, 2]).unwrap();
+            seen.extend(read(stream, 11));
+            stream.write_all(&[1, 0]).unwrap();
+            seen.extend(read(stream, 18));
+            stream
+                .write_all(&[5, 0, 0, 1, 10, 0, 0, 1, 0x69, 0x89, b'h', b'i'])
+                .unwrap();
+            seen
+        });
+        assert_eq!(connect(&proxy(ProxyKind::Socks5, port), 2).unwrap(), b"hi");
+
+        let mut expected = vec![5, 2, 0, 2, 1, 4];
+        expected.extend(b"user");
+        expected.push(4);
+        expected.extend(b"pass");
+        expected.extend([5, 1, 0, 3, 11]);
+        expected.extend(b"db.internal");
+        expected.extend(27017u16.to_be_bytes());
+        assert_eq!(server.join().unwrap(), expected);
+    }
+
+    #[test]
+    fn socks5_reports_rejected_credentials() {
+        let (port, server) = serve(|stream| {
+            read(stream, 4);
+            stream.write_all(&[5, 2]).unwrap();
+            read(stream, 11);
+            stream.write_all(&[1, 1]).unwrap();
+            Vec::new()
+        });
+        let err = connect(&proxy(ProxyKind::Socks5, port), 0).unwrap_err();
+        assert!(err.contains("rejected"), "{}", err);
+        server.join().unwrap();
+    }
+
+    #[test]
+    fn http_connect_sends_basic_auth() {
+        let (port, server) = serve(|stream| {
+            let head = read_head(stream);
+            stream
+                .write_all(b"HTTP/1.1 200 Connection established\r\n\r\nhello")
+                .unwrap();
+            head
+        });
+        assert_eq!(connect(&proxy(ProxyKind::Http, port), 5).unwrap(), b"hello");
+        assert_eq!(
+            String::from_utf8(server.join().unwrap()).unwrap(),
+            "CONNECT db.internal:27017 HTTP/1.1\r\nHost: db.internal:27017\r\n\
+            Proxy-Authorization: Basic dXNlcjpwYXNz\r\n\r\n"
+        );
+    }
+
+    #[test]
+    fn http_connect_reports_the_status() {
+        let (port, server) = serve(|stream| {
+            read_head(stream);
+            stream
+                .write_all(b"HTTP/1.1 407 Proxy Authentication Required\r\n\r\n")
+                .unwrap();
+            Vec::new()
+        });
+        let err = connect(&proxy(ProxyKind::Http, port), 0).unwrap_err();
+        assert!(err.contains("407"), "{}", err);
+        server.join().unwrap();
+    }
+}
@@ -0,0 +1,527 @@
+use std::{
+    future::Future,
+    net::SocketAddr,
+    path::PathBuf,
+    sync::{Arc, Mutex},
+    time::Duration,
+};
+
+use russh::{
+    client::{self, Handle},
+    keys::{self, key::PublicKey},
+    Disconnect,
+};
+use serde::{Deserialize, Serialize};
+use tokio::{
+    net::{TcpListener, TcpStream},
+    sync::oneshot,
+};
+
+use crate::{error::Error, proxy::ProxyOptions};
+
+/// How to reach a server through an SSH bastion host.
+#[derive(Debug, Clone, Default, Serialize, Deserialize)]
+#[serde(rename_all = "camelCase", default)]
+pub struct SshTunnelOptions {
+    pub host: String,
+    /// Defaults to 22.
+    pub port: Option<u16>,
+    pub username: String,
+    pub password: Option<String>,
+    pub private_key_file: Option<String>,
+    /// Passphrase of `private_key_file`, if it is encrypted.
+    pub passphrase: Option<String>,
+    /// Defaults to `~/.ssh/known_hosts`. The host key has to be listed there.
+    pub known_hosts_file: Option<String>,
+}
+
+impl SshTunnelOptions {
+    /// Credentials are only checked when connecting, so that profiles
+    /// imported without their secrets can still be saved.
+    pub fn validate(&self) -> Result<(), Error> {
+        if self.host.is_empty() || self.username.is_empty() {
+            return Err(Error::InvalidArgument(
+                "an SSH tunnel needs a host and a username".to_string(),
+            ));
+        }
+        Ok(())
+    }
+
+    pub fn has_secrets(&self) -> bool {
+        self.password.is_some() || self.passphrase.is_some()
+    }
+}
+
+/// A local port forwarded to a remote host, through SSH or a proxy. The
+/// forward runs on its own thread and is torn down when this is dropped.
+pub struct Tunnel {
+    local_addr: SocketAddr,
+    shutdown: Option<oneshot::Sender<()>>,
+}
+
+/// How connections to the local port reach the target.
+enum Forwarder {
+    Ssh(Handle<KnownHostsCheck>),
+    Proxy(ProxyOptions),
+}
+
+impl Tunnel {
+    /// Connects to the SSH server and starts forwarding a local port to
+    /// `target_host:target_port`, as seen from the SSH server.
+    pub fn ssh(
+        options: &SshTunnelOptions,
+        target_host: &str,
+        target_port: u16,
+    ) -> Result<Self, Error> {
+        options.validate()?;
+        if options.password.is_none() && options.private_key_file.is_none() {
+            return Err(Error::InvalidArgument(
+                "an SSH tunnel needs a password or a private key".to_string(),
+            ));
+        }
+        let options = options.clone();
+        Self::spawn(
+            async move { Ok(Forwarder::Ssh(start(&options).await?)) },
+            target_host,
+            target_port,
+        )
+    }
+
+    /// Starts forwarding a local port to `target_host:target_port` through a
+    /// proxy. The proxy is tried once up front so that a bad setting fails
+    /// here rather than as a server selection timeout.
+    pub fn proxy(
+        options: &ProxyOptions,
+        target_host: &str,
+        target_port: u16,
+    ) -> Result<Self, Error> {
+        options.validate()?;
+        let options = options.clone();
+        let host = target_host.to_string();
+        Self::spawn(
+            async move {
+                options.connect(&host, target_port).await?;
+                Ok(Forwarder::Proxy(options))
+            },
+            target_host,
+            target_port,
+        )
+    }
+
+    fn spawn(
+        forwarder: impl Future<Output = Result<Forwarder, String>> + Send + 'static,
+        target_host: &str,
+        target_port: u16,
+    ) -> Result<Self, Error> {
+        let target = (target_host.to_string(), target_port);
+        let (ready_tx, ready_rx) = std::sync::mpsc::channel();
+        let (shutdown_tx, shutdown_rx) = oneshot::channel();
+
+        // Commands already run inside an async runtime, which can't be
+        // blocked on, so the tunnel gets a runtime on a thread of its own.
+        std::thread::Builder::new()
+            .name("tunnel".to_string())
+            .spawn(move || {
+                let runtime = match tokio::runtime::Builder::new_current_thread()
+                    .enable_all()
+                    .build()
+                {
+                    Ok(runtime) => runtime,
+                    Err(err) => {
+                        let _ = ready_tx.send(Err(err.to_string()));
+                        return;
+                    }
+                };
+                runtime.block_on(async move {
+                    let started = async {
+                        let forwarder = forwarder.await?;
+                        let listener = TcpListener::bind("127.0.0.1:0")
+                            .await
+                            .map_err(tunnel_error)?;
+                        Ok::<_, String>((forwarder, listener))
+                    };
+                    match started.await {
+                        Ok((forwarder, listener)) => {
+                            let _ = ready_tx.send(listener.local_addr().map_err(tunnel_error));
+                            serve(forwarder, listener, target, shutdown_rx).await;
+                        }
+                        Err(err) => {
+                            let _ = ready_tx.send(Err(err));
+                        }
+                    }
+                });
+            })
+            .map_err(|err| Error::Tunnel(err.to_string()))?;
+
+        let local_addr = ready_rx
+            .recv()
+            .unwrap_or_else(|_| Err("the tunnel thread stopped".to_string()))
+            .map_err(Error::Tunnel)?;
+        Ok(Self {
+            local_addr,
+            shutdown: Some(shutdown_tx),
+        })
+    }
+
+    pub fn local_port(&self) -> u16 {
+        self.local_addr.port()
+    }
+}
+
+impl Drop for Tunnel {
+    fn drop(&mut self) {
+        if let Some(shutdown) = self.shutdown.take() {
+            let _ = shutdown.send(());
+        }
+    }
+}
+
+fn tunnel_error(err: impl std::fmt::Display) -> String {
+    err.to_string()
+}
+
+struct KnownHostsCheck {
+    host: String,
+    port: u16,
+    known_hosts_file: Option<PathBuf>,
+    /// Why the host key was rejected, to report something better than the
+    /// generic error russh returns.
+    rejection: Arc<Mutex<Option<String>>>,
+}
+
+#[async_trait::async_trait]
+impl client::Handler for KnownHostsCheck {
+    type Error = russh::Error;
+
+    async fn check_server_key(
+        &mut self,
+        server_public_key: &PublicKey,
+    ) -> Result<bool, Self::Error> {
+        let known = match &self.known_hosts_file {
+            Some(path) => {
+                keys::check_known_hosts_path(&self.host, self.port, server_public_key, path)
+            }
+            None => keys::check_known_hosts(&self.host, self.port, server_public_key),
+        };
+        let rejection = match known {
+            Ok(true) => return Ok(true),
+            Ok(false) => format!(
+                "the host key of {} ({}) is not in known_hosts",
+                self.host,
+                server_public_key.fingerprint()
+            ),
+            Err(keys::Error::KeyChanged { line }) => format!(
+                "the host key of {} does not match line {} of known_hosts",
+                self.host, line
+            ),
+            Err(err) => format!("failed to check known_hosts: {}", err),
+        };
+        if let Ok(mut slot) = self.rejection.lock() {
+            *slot = Some(rejection);
+        }
+        Ok(false)
+    }
+}
+
+async fn start(options: &SshTunnelOptions) -> Result<Handle<KnownHostsCheck>, String> {
+    let port = options.port.unwrap_or(22);
+    let rejection = Arc::new(Mutex::new(None));
+    let handler = KnownHostsCheck {
+        host: options.host.clone(),
+        port,
+        known_hosts_file: options.known_hosts_file.as_ref().map(PathBuf::from),
+        rejection: rejection.clone(),
+    };
+
+    let config = Arc::new(client::Config::default());
+    let mut session = client::connect(config, (options.host.as_str(), port), handler)
+        .await
+        .map_err(
+            |err| match rejection.lock().ok().and_then(|mut r| r.take()) {
+                Some(rejection) => rejection,
+                None => format!("failed to connect to {}:{}: {}", options.host, port, err),
+            },
+        )?;
+
+    let mut authenticated = false;
+    if let Some(path) = &options.private_key_file {
+        let key = keys::load_secret_key(path, options.passphrase.as_deref())
+            .map_err(|err| format!("failed to load private key {}: {}", path, err))?;
+        authenticated = session
+            .authenticate_publickey(&options.username, Arc::new(key))
+            .await
+            .map_err(tunnel_error)?;
+    }
+    if let (false, Some(password)) = (authenticated, &options.password) {
+        authenticated = session
+            .authenticate_password(&options.username, password)
+            .await
+            .map_err(tunnel_error)?;
+    }
+    if !authenticated {
+        return Err(format!("authentication as {} failed", options.username));
+    }
+    Ok(session)
+}
+
+/// How often `serve` checks whether the SSH session is still up.
+const SESSION_CHECK_INTERVAL: Duration = Duration::from_secs(1);
+
+impl Forwarder {
+    fn is_closed(&self) -> bool {
+        match self {
+            Forwarder::Ssh(session) => session.is_closed(),
+            Forwarder::Proxy(_) => false,
+        }
+    }
+}
+
+/// Accepts local connections and forwards each of them until `shutdown` fires
+/// or the SSH session drops. Once the session is gone the local port is
+/// closed, so the driver sees refused connections rather than hanging ones.
+async fn serve(
+    forwarder: Forwarder,
+    listener: TcpListener,
+    (target_host, target_port): (String, u16),
+    mut shutdown: oneshot::Receiver<()>,
+) {
+    let forwarder = Arc::new(forwarder);
+    let mut session_check = tokio::time::interval(SESSION_CHECK_INTERVAL);
+    loop {
+        let (socket, peer) = tokio::select! {
+            _ = &mut shutdown => break,
+            _ = session_check.tick() => match forwarder.is_closed() {
+                true => break,
+                false => continue,
+            },
+            accepted = listener.accept() => match accepted {
+                Ok(accepted) => accepted,
+                Err(_) => break,
+            },
+        };
+        if forwarder.is_closed() {
+            break;
+        }
+        let forwarder = forwarder.clone();
+        let target_host = target_host.clone();
+        tokio::spawn(async move {
+            let _ = forward(&forwarder, socket, peer, &target_host, target_port).await;
+        });
+    }
+    if let Forwarder::Ssh(session) = forwarder.as_ref() {
+        let _ = session
+            .disconnect(Disconnect::ByApplication, "", "en")
+            .await;
+    }
+}
+
+async fn forward(
+    forwarder: &Forwarder,
+    mut socket: TcpStream,
+    peer: SocketAddr,
+    target_host: &str,
+    target_port: u16,
+) -> Result<(), String> {
+    match forwarder {
+        Forwarder::Ssh(session) => {
+            let channel = session
+                .channel_open_direct_tcpip(
+                    target_host,
+                    target_port as u32,
+                    peer.ip().to_string(),
+                    peer.port() as u32,
+                )
+                .await
+                .map_err(tunnel_error)?;
+            let mut stream = channel.into_stream();
+            tokio::io::copy_bidirectional(&mut socket, &mut stream)
+                .await
+                .map_err(tunnel_error)?;
+        }
+        Forwarder::Proxy(proxy) => {
+            let mut stream = proxy.connect(target_host, target_port).await?;
+            tokio::io::copy_bidirectional(&mut socket, &mut stream)
+                .await
+                .map_err(tunnel_error)?;
+        }
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{
+        io::{BufRead, BufReader, Read, Write},
+        net::{TcpListener as StdTcpListener, TcpStream as StdTcpStream},
+        thread,
+        time::Duration,
+    };
+
+    use super::*;
+    use crate::{
+        client::{self, ConnectionProfile},
+        proxy::ProxyKind,
+    };
+
+    /// An HTTP proxy that accepts every `CONNECT` and then echoes back what it
+    /// is sent, standing in for the server behind it.
+    fn echo_proxy() -> u16 {
+        let listener = StdTcpListener::bind("127.0.0.1:0").unwrap();
+        let port = listener.local_addr().unwrap().port();
+        thread::spawn(move || {
+            for stream in listener.incoming() {
+                let Ok(mut stream) = stream else { break };
+                thread::spawn(move || {
+                    let mut reader = BufReader::new(stream.try_clone().unwrap());
+                    let mut line = String::new();
+                    while reader.read_line(&mut line).unwrap_or(0) > 0 && line != "\r\n" {
+                        line.clear();
+                    }
+                    stream
+                        .write_all(b"HTTP/1.1 200 Connection established\r\n\r\n")
+                        .unwrap();
+                    let mut buf = [0u8; 1024];
+                    while let Ok(n @ 1..) = reader.read(&mut buf) {
+                        if stream.write_all(&buf[..n]).is_err() {
+                            break;
+                        }
+                    }
+                });
+            }
+        });
+        port
+    }
+
+    fn proxy(port: u16) -> ProxyOptions {
+        ProxyOptions {
+            kind: ProxyKind::Http,
+            host: "127.0.0.1".to_string(),
+            port: Some(port),
+            ..Default::default()
+        }
+    }
+
+    #[test]
+    fn forwards_the_local_port() {
+        let tunnel = Tunnel::proxy(&proxy(echo_proxy()), "db.internal", 27017).unwrap();
+        let mut stream = StdTcpStream::connect(("127.0.0.1", tunnel.local_port())).unwrap();
+        stream.write_all(b"ping").unwrap();
+        let mut reply = [0u8; 4];
+        stream.read_exact(&mut reply).unwrap();
+        assert_eq!(&reply, b"ping");
+    }
+
+    #[test]
+    fn stops_listening_once_dropped() {
+        let tunnel = Tunnel::proxy(&proxy(echo_proxy()), "db.internal", 27017).unwrap();
+        let port = tunnel.local_port();
+        drop(tunnel);
+        for _ in 0..50 {
+            if StdTcpStream::connect(("127.0.0.1", port)).is_err() {
+                return;
+            }
+            thread::sleep(Duration::from_millis(40));
+        }
+        panic!("the tunnel still accepts connections");
+    }
+
+    #[test]
+    fn ssh_credentials_are_checked_when_connecting() {
+        let options = SshTunnelOptions {
+            host: "bastion.internal".to_string(),
+            username: "deploy".to_string(),
+            ..Default::default()
+        };
+        options.validate().unwrap();
+        let err = Tunnel::ssh(&options, "db.internal", 27017).err().unwrap();
+        assert!(
+            err.to_string().contains("a password or a private key"),
+            "{}",
+            err
+        );
+    }
+
+    /// The SSH server named by `MONGOUI_TEST_SSHD` as `user@host:port`, which
+    /// has to allow TCP forwarding. `MONGOUI_TEST_SSHD_KEY` is the private key
+    /// to log in with and `MONGOUI_TEST_SSHD_KNOWN_HOSTS` has to list its host
+    /// key. The tests that need it pass without doing anything when unset.
+    fn test_sshd() -> Option<SshTunnelOptions> {
+        let target = std::env::var("MONGOUI_TEST_SSHD").ok()?;
+        let (username, address) = target.split_once('@')?;
+        let (host, port) = address.rsplit_once(':')?;
+        Some(SshTunnelOptions {
+            host: host.to_string(),
+            port: Some(port.parse().ok()?),
+            username: username.to_string(),
+            private_key_file: std::env::var("MONGOUI_TEST_SSHD_KEY").ok(),
+            known_hosts_file: std::env::var("MONGOUI_TEST_SSHD_KNOWN_HOSTS").ok(),
+            ..Default::default()
+        })
+    }
+
+    /// Echoes back what it is sent, on a port of its own.
+    fn echo_server() -> u16 {
+        let listener = StdTcpListener::bind("127.0.0.1:0").unwrap();
+        let port = listener.local_addr().unwrap().port();
+        thread::spawn(move || {
+            for stream in listener.incoming() {
+                let Ok(mut stream) = stream else { break };
+                thread::spawn(move || {
+                    let mut buf = [0u8; 1024];
+                    while let Ok(n @ 1..) = stream.read(&mut buf) {
+                        if stream.write_all(&buf[..n]).is_err() {
+                            break;
+                        }
+                    }
+                });
+            }
+        });
+        port
+    }
+
+    #[test]
+    fn forwards_through_ssh() {
+        let Some(options) = test_sshd() else { return };
+        let tunnel = Tunnel::ssh(&options, "127.0.0.1", echo_server()).unwrap();
+        let mut stream = StdTcpStream::connect(("127.0.0.1", tunnel.local_port())).unwrap();
+        stream.write_all(b"ping").unwrap();
+        let mut reply = [0u8; 4];
+        stream.read_exact(&mut reply).unwrap();
+        assert_eq!(&reply, b"ping");
+    }
+
+    #[test]
+    fn rejects_unknown_ssh_host_keys() {
+        let Some(mut options) = test_sshd() else {
+            return;
+        };
+        let known_hosts =
+            std::env::temp_dir().join(format!("mongoui-{}-known_hosts", uuid::Uuid::new_v4()));
+        std::fs::write(&known_hosts, "").unwrap();
+        options.known_hosts_file = Some(known_hosts.to_string_lossy().into_owned());
+
+        let err = Tunnel::ssh(&options, "127.0.0.1", echo_server())
+            .err()
+            .unwrap();
+        assert!(err.to_string().contains("is not in known_hosts"), "{}", err);
+        std::fs::remove_file(&known_hosts).unwrap();
+    }
+
+    #[test]
+    fn tls_through_a_tunnel_needs_unchecked_certificates() {
+        let mut profile = ConnectionProfile {
+            proxy: Some(proxy(echo_proxy())),
+            ..Default::default()
+        };
+        let err = client::connect("mongodb://db.internal/?tls=true", &profile)
+            .err()
+            .unwrap();
+        assert!(err.to_string().contains("TLS"), "{}", err);
+
+        profile.tls_allow_invalid_certificates = Some(true);
+        let (client, tunnel) =
+            client::connect("mongodb://db.internal/?tls=true", &profile).unwrap();
+        assert!(tunnel.is_some());
+        client.shutdown_immediate();
+    }
+}
@@ -0,0 +1,203 @@
+use std::{
+    sync::Mutex,
+    time::{Duration, Instant},
+};
+
+use argon2::Argon2;
+use chacha20poly1305::{
+    aead::{rand_core::RngCore, Aead, AeadCore, KeyInit, OsRng},
+    XChaCha20Poly1305, XNonce,
+};
+use lazy_static::lazy_static;
+use serde::Serialize;
+
+use crate::{db, error::Error};
+
+const NONCE_LEN: usize = 24;
+const SALT_LEN: usize = 16;
+/// Encrypted with the derived key to check the master password on unlock.
+const VERIFIER: &[u8] = b"mongoui-vault-v1";
+const DEFAULT_IDLE_TIMEOUT: Duration = Duration::from_secs(15 * 60);
+
+lazy_static! {
+    static ref VAULT: Mutex<Option<UnlockedVault>> = Mutex::new(None);
+}
+
+struct UnlockedVault {
+    key: [u8; 32],
+    last_used: Instant,
+    idle_timeout: Duration,
+}
+
+impl UnlockedVault {
+    fn is_idle(&self) -> bool {
+        self.last_used.elapsed() >= self.idle_timeout
+    }
+}
+
+impl Drop for UnlockedVault {
+    fn drop(&mut self) {
+        self.key.fill(0);
+    }
+}
+
+#[derive(Serialize)]
+#[serde(rename_all = "camelCase")]
+pub struct VaultStatus {
+    initialized: bool,
+    unlocked: bool,
+}
+
+fn derive_key(password: &str, salt: &[u8]) -> Result<[u8; 32], Error> {
+    let mut key = [0u8; 32];
+    Argon2::default()
+        .hash_password_into(password.as_bytes(), salt, &mut key)
+        .map_err(|err| Error::Crypto(err.to_string()))?;
+    Ok(key)
+}
+
+fn seal(key: &[u8; 32], plaintext: &[u8]) -> Result<Vec<u8>, Error> {
+    let cipher = XChaCha20Poly1305::new(key.into());
+    let nonce = XChaCha20Poly1305::generate_nonce(&mut OsRng);
+    let ciphertext = cipher
+        .encrypt(&nonce, plaintext)
+        .map_err(|err| Error::Crypto(err.to_string()))?;
+    Ok([nonce.as_slice(), &ciphertext].concat())
+}
+
+fn open(key: &[u8; 32], sealed: &[u8]) -> Result<Vec<u8>, Error> {
+    if sealed.len() < NONCE_LEN {
+        return Err(Error::Crypto("encrypted value is truncated".to_string()));
+    }
+    let (nonce, ciphertext) = sealed.split_at(NONCE_LEN);
+    XChaCha20Poly1305::new(key.into())
+        .decrypt(XNonce::from_slice(nonce), ciphertext)
+        .map_err(|_| Error::Crypto("failed to decrypt value".to_string()))
+}
+
+/// Runs `f` with the vault key, locking the vault first if it sat idle for
+/// longer than its timeout.
+fn with_key<T>(f: impl FnOnce(&[u8; 32]) -> Result<T, Error>) -> Result<T, Error> {
+    let mut vault = VAULT.lock()?;
+    if vault.as_ref().is_some_and(UnlockedVault::is_idle) {
+        *vault = None;
+    }
+    let unlocked = vault.as_mut().ok_or(Error::VaultLocked)?;
+    unlocked.last_used = Instant::now();
+    f(&unlocked.key)
+}
+
+pub fn ensure_unlocked() -> Result<(), Error> {
+    with_key(|_| Ok(()))
+}
+
+pub fn encrypt(plaintext: &str) -> Result<Vec<u8>, Error> {
+    with_key(|key| seal(key, plaintext.as_bytes()))
+}
+
+pub fn decrypt(sealed: &[u8]) -> Result<String, Error> {
+    with_key(|key| {
+        let plaintext = open(key, sealed)?;
+        String::from_utf8(plaintext).map_err(|err| Error::Crypto(err.to_string()))
+    })
+}
+
+pub fn status() -> Result<VaultStatus, Error> {
+    let unlocked = VAULT.lock()?.as_ref().is_some_and(|vault| !vault.is_idle());
+    Ok(VaultStatus {
+        initialized: db::get_vault()?.is_some(),
+        unlocked,
+    })
+}
+
+/// Sets the master password, unlocks the vault with it and moves any plain
+/// text credentials into it. `confirmation` must repeat the password, since a
+/// mistyped one would lock every saved credential away for good.
+pub fn create(
+    password: &str,
+    confirmation: &str,
+    idle_timeout: Option<Duration>,
+) -> Result<(), Error> {
+    if db::get_vault()?.is_some() {
+        return Err(Error::Conflict(
+            "a master password is already set".to_string(),
+        ));
+    }
+    if password.is_empty() {
+        return Err(Error::InvalidArgument(
+            "master password can't be empty".to_string(),
+        ));
+    }
+    if password != confirmation {
+        return Err(Error::InvalidArgument(
+            "the master passwords don't match".to_string(),
+        ));
+    }
+    let mut salt = [0u8; SALT_LEN];
+    OsRng.fill_bytes(&mut salt);
+    let key = derive_key(password, &salt)?;
+    db::create_vault(&salt, &seal(&key, VERIFIER)?)?;
+    open_vault(key, idle_timeout)
+}
+
+/// Unlocks the vault with the master password set by [`create`].
+pub fn unlock(password: &str, idle_timeout: Option<Duration>) -> Result<(), Error> {
+    let Some((salt, verifier)) = db::get_vault()? else {
+        return Err(Error::NotFound("no master password is set yet".to_string()));
+    };
+    let key = derive_key(password, &salt)?;
+    if open(&key, &verifier).ok().as_deref() != Some(VERIFIER) {
+        return Err(Error::InvalidArgument("wrong master password".to_string()));
+    }
+    open_vault(key, idle_timeout)
+}
+
+fn open_vault(key: [u8; 32], idle_timeout: Option<Duration>) -> Result<(), Error> {
+    *VAULT.lock()? = Some(UnlockedVault {
+        key,
+        last_used: Instant::now(),
+        idle_timeout: idle_timeout.unwrap_or(DEFAULT_IDLE_TIMEOUT),
+    });
+    db::encrypt_plaintext_credentials()
+}
+
+pub fn lock() -> Result<(), Error> {
+    *VAULT.lock()? = None;
+    Ok(())
+}
+
+/// Locks the vault once it has been idle for too long and reports whether
+/// that happened, so the frontend can be asked to prompt again.
+pub fn lock_if_idle() -> bool {
+    match VAULT.lock() {
+        Ok(mut vault) if vault.as_ref().is_some_and(UnlockedVault::is_idle) => {
+            *vault = None;
+            true
+        }
+        _ => false,
+    }
+}
+
+/// Splits the `user:password` part out of a connection string, returning the
+/// URI without it and the raw (still percent-encoded) credentials.
+pub fn split_credentials(uri: &str) -> (String, Option<String>) {
+    let Some((scheme, rest)) = uri.split_once("://") else {
+        return (uri.to_string(), None);
+    };
+    let authority_end = rest.find(['/', '?']).unwrap_or(rest.len());
+    match rest[..authority_end].rfind('@') {
+        Some(at) => (
+            format!("{}://{}", scheme, &rest[at + 1..]),
+            Some(rest[..at].to_string()),
+        ),
+        None => (uri.to_string(), None),
+    }
+}
+
+/// Inverse of [`split_credentials`].
+pub fn join_credentials(uri: &str, credentials: &str) -> String {
+    match uri.split_once("://") {
+        Some((scheme, rest)) => format!("{}://{}@{}", scheme, credentials, rest),
+        None => uri.to_string(),
+    }
+}
@@ -1,12 +1,153 @@
 import { invoke } from "@tauri-apps/api";
 import { EJSON } from "bson";
 
+export interface CommandError {
+  kind: string;
+  message: string;
+  code: number | null;
+  codeName: string | null;
+  labels: string[];
+  retryable: boolean;
+  details: any;
+}
+
+export interface ScriptErrorDetails {
+  name: string;
+  line: number | null;
+  column: number | null;
+  stack: string | null;
+}
+
+export type ReadPreference =
+  | "primary"
+  | "primaryPreferred"
+  | "secondary"
+  | "secondaryPreferred"
+  | "nearest";
+
+export interface SshTunnelOptions {
+  host: string;
+  port?: number | null;
+  username: string;
+  /** Left out when reading a saved connection; leaving it out keeps the stored one. */
+  password?: string | null;
+  privateKeyFile?: string | null;
+  passphrase?: string | null;
+  knownHostsFile?: string | null;
+}
+
+export interface ProxyOptions {
+  kind: "socks5" | "http";
+  host: string;
+  port?: number | null;
+  username?: string | null;
+  /** Left out when reading a saved connection; leaving it out keeps the stored one. */
+  password?: string | null;
+}
+
+export interface ConnectionProfile {
+  defaultDatabase?: string | null;
+  readPreference?: ReadPreference | null;
+  appName?: string | null;
+  connectTimeoutMs?: number | null;
+  serverSelectionTimeoutMs?: number | null;
+  tls?: boolean | null;
+  tlsCaFile?: string | null;
+  tlsCertFile?: string | null;
+  tlsKeyFile?: string | null;
+  tlsAllowInvalidHostnames?: boolean | null;
+  tlsAllowInvalidCertificates?: boolean | null;
+  authMechanism?: string | null;
+  directConnection?: boolean | null;
+  color?: string | null;
+  readOnly?: boolean;
+  sshTunnel?: SshTunnelOptions | null;
+  proxy?: ProxyOptions | null;
+}
+
+export interface SavedDb extends ConnectionProfile {
+  id: number;
+  name: string;
+  uri: string;
+  createdAt: number;
+  hasCredentials: boolean;
+  folderId: number | null;
+  tags: string[];
+  favorite: boolean;
+  sortOrder: number;
+  lastUsedAt: number | null;
+}
+
+export interface Folder {
+  id: number;
+  name: string;
+  sortOrder: number;
+}
+
+export interface SavedDbFilter {
+  query?: string;
+  folderId?: number;
+  tags?: string[];
+  favorite?: boolean;
+}
+
+/** Returned by the `store_status` command, worth checking at startup. */
+export interface StoreStatus {
+  dataDir: string | null;
+  /** Why the local store could not be opened. */
+  error: string | null;
+  warnings: string[];
+}
+
+export interface VaultStatus {
+  initialized: boolean;
+  unlocked: boolean;
+}
+
+/**
+ * Runs a command, asking for the master password and retrying once if it
+ * failed because the credential vault is locked. A new master password is
+ * asked for twice.
+ */
+export async function withVault<T>(command: () => Promise<T>): Promise<T> {
+  try {
+    return await command();
+  } catch (err) {
+    if ((err as CommandError).kind !== "vaultLocked") throw err;
+    const status: VaultStatus = await invoke("vault_status");
+    if (status.initialized) {
+      const password = window.prompt("Master password");
+      if (password === null) throw err;
+      await invoke("unlock_vault", { password });
+    } else {
+      const password = window.prompt("Choose a master password");
+      if (password === null) throw err;
+      const confirmation = window.prompt("Repeat the master password");
+      if (confirmation === null) throw err;
+      await invoke("create_vault", { password, confirmation });
+    }
+    return command();
+  }
+}
+
+/**
+ * Runs `script`, or the saved script `scriptId`. A saved script bound to a
+ * database fails to run against any other `dbName`.
+ *
+ * A failure to record the run in the history doesn't fail it, it is emitted
+ * as a `history-error` event carrying a `CommandError`.
+ */
 export async function executeScript({
   script,
+  scriptId,
+  params,
   clientId,
   dbName,
 }: {
-  script: string;
+  script?: string;
+  scriptId?: number;
+  /** Values of the parameters the script declares, plain or Extended JSON. */
+  params?: Record<string, unknown>;
   clientId: string;
   dbName: string;
 }) {
@@ -14,7 +155,264 @@ export async function executeScript({
     clientId,
     dbName,
     script,
+    scriptId,
+    params,
   });
   const parsed = EJSON.deserialize(res);
   return parsed;
 }
+
+export interface ImportSummary {
+  format: "mongoui" | "compass" | "uris";
+  created: number;
+  updated: number;
+  skipped: number;
+}
+
+export interface HistoryEntry {
+  id: number;
+  savedDbId: number | null;
+  host: string;
+  database: string;
+  script: string;
+  durationMs: number;
+  resultCount: number | null;
+  error: string | null;
+  executedAt: number;
+}
+
+export interface HistoryFilter {
+  savedDbId?: number;
+  host?: string;
+  database?: string;
+  query?: string;
+  failed?: boolean;
+  since?: number;
+  until?: number;
+}
+
+export interface Paging {
+  offset?: number;
+  limit?: number;
+}
+
+export interface HistoryPage {
+  entries: HistoryEntry[];
+  total: number;
+}
+
+export interface HistoryRetention {
+  maxAgeDays: number | null;
+  maxEntries: number | null;
+}
+
+export interface SavedScriptInput {
+  name: string;
+  description?: string | null;
+  folder?: string | null;
+  tags?: string[];
+  body: string;
+  savedDbId?: number | null;
+  database?: string | null;
+}
+
+export interface SavedScript extends SavedScriptInput {
+  id: number;
+  tags: string[];
+  createdAt: number;
+  updatedAt: number;
+}
+
+export interface SavedScriptFilter {
+  query?: string;
+  folder?: string;
+  tags?: string[];
+  savedDbId?: number;
+}
+
+/**
+ * Int64 parameters are numbers in the script, or BigInts when they are past
+ * 2^53 and a number would lose digits, the same as Int64 values in results.
+ */
+export type ScriptParamType =
+  | "String"
+  | "Double"
+  | "Int32"
+  | "Int64"
+  | "Boolean"
+  | "Date"
+  | "ObjectId"
+  | "Object"
+  | "Array";
+
+export interface ScriptParam {
+  name: string;
+  type: ScriptParamType;
+  optional: boolean;
+  description: string | null;
+}
+
+export interface ResultSummary {
+  resultCount: number | null;
+  durationMs: number | null;
+  error: string | null;
+  executedAt: number | null;
+}
+
+export interface WorkspaceTab {
+  savedDbId: number | null;
+  savedScriptId: number | null;
+  title: string | null;
+  script: string;
+  database: string | null;
+  cursor: { line: number; column: number } | null;
+  lastResult: ResultSummary | null;
+  active: boolean;
+}
+
+/**
+ * `save_workspace` takes the `savedDbId` whose tabs it replaces, null for
+ * connections that weren't saved, and keeps the tabs of the others.
+ * `load_workspace` takes the `savedDbId` whose tabs it returns, the same way.
+ */
+export interface Workspace {
+  tabs: WorkspaceTab[];
+}
+
+export type ImportFormat = "json" | "ndjson" | "csv";
+
+export interface ImportFileOptions {
+  batchSize?: number;
+  mode?: "insert" | "upsert";
+  upsertFields?: string[];
+  drop?: boolean;
+  stopOnError?: boolean;
+  ignoreBlanks?: boolean;
+}
+
+/** Payload of the `import-progress` event, and the result of `import_file`. */
+export interface ImportProgress {
+  read: number;
+  inserted: number;
+  updated: number;
+  failed: number;
+  bytesRead: number;
+  totalBytes: number;
+}
+
+export type ExportSource =
+  | {
+      kind: "find";
+      collection: string;
+      filter?: object;
+      projection?: object;
+      sort?: object;
+      skip?: number;
+      limit?: number;
+    }
+  | { kind: "aggregate"; collection: string; pipeline: object[] }
+  | { kind: "script"; script: string; params?: Record<string, unknown> };
+
+export type ExportFormat = "json" | "ndjson" | "csv" | "bson";
+
+export interface ExportResultsOptions {
+  /** CSV columns, the fields of the first document by default. */
+  fields?: string[];
+  canonical?: boolean;
+}
+
+/** Payload of the `export-progress` event, and the result of `export_results`. */
+export interface ExportProgress {
+  written: number;
+  bytesWritten: number;
+  /** Fields left out of a CSV export because they have no column. */
+  unseenFields: string[];
+}
+
+export interface DumpOptions {
+  databases?: string[];
+  /** Namespaces as `db.collection`, where `*` matches any run of characters. */
+  include?: string[];
+  exclude?: string[];
+  archive?: boolean;
+  gzip?: boolean;
+}
+
+export interface RestoreOptions {
+  include?: string[];
+  exclude?: string[];
+  archive?: boolean;
+  drop?: boolean;
+  restoreIndexes?: boolean;
+  batchSize?: number;
+}
+
+/** Payload of the `dump-progress` and `restore-progress` events. */
+export interface DumpProgress {
+  namespace: string | null;
+  collections: number;
+  documents: number;
+  failed: number;
+}
+
+export interface CopyOptions {
+  filter?: object;
+  dropTarget?: boolean;
+  copyIndexes?: boolean;
+  batchSize?: number;
+}
+
+export interface CopyDatabaseOptions {
+  collections?: string[];
+  dropTarget?: boolean;
+  copyIndexes?: boolean;
+  batchSize?: number;
+}
+
+/**
+ * Payload of the `copy-progress` event. `id` is the `copyId` the copy was
+ * started with, which `cancel_copy` takes and which can be cancelled before
+ * the first event.
+ */
+export interface CopyProgress {
+  id: string;
+  namespace: string | null;
+  collections: number;
+  copied: number;
+  failed: number;
+  total: number;
+  cancelled: boolean;
+}
+
+export interface DiffOptions {
+  filter?: object;
+  /** Has to keep `_id`. */
+  projection?: object;
+  /** Also writes the differences to this file as a JSON report. */
+  reportPath?: string;
+}
+
+/** A field that differs; a side without the field has no value. */
+export interface FieldDiff {
+  path: string;
+  source?: unknown;
+  target?: unknown;
+}
+
+export type DiffEntry =
+  | { kind: "missing"; id: unknown; document: object }
+  | { kind: "extra"; id: unknown; document: object }
+  | { kind: "changed"; id: unknown; fields: FieldDiff[] };
+
+export interface DiffSummary {
+  identical: number;
+  missing: number;
+  extra: number;
+  changed: number;
+}
+
+/** Payload of the `diff-progress` event, with the differences found since the last one. */
+export interface DiffProgress {
+  summary: DiffSummary;
+  differences: DiffEntry[];
+}
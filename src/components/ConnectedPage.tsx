@@ -5,7 +5,7 @@ import { SideBar } from "./SideBar";
 import { Panel, PanelGroup, PanelResizeHandle } from "react-resizable-panels";
 import { Button } from "./ui/button";
 import { customStringify } from "@/lib/utils";
-import { executeScript } from "@/api";
+import { CommandError, executeScript, ScriptErrorDetails } from "@/api";
 import { Tabs } from "./Tabs";
 import EditorTheme from "./EditorTheme";
 
@@ -124,12 +124,32 @@ export function ConnectedPage({ dbs, clientId }: Props) {
     const { dbName, model: selectedModel } = editorModels.find((m) => {
       return m.model.id === selectedModelId;
     })!;
-    const bsonData = await executeScript({
-      script: selectedModel.getValue(),
-      clientId,
-      dbName,
-    });
-    const value = customStringify(bsonData);
+    monacoRef.current?.editor.setModelMarkers(selectedModel, "script", []);
+    let value: string;
+    try {
+      const bsonData = await executeScript({
+        script: selectedModel.getValue(),
+        clientId,
+        dbName,
+      });
+      value = customStringify(bsonData);
+    } catch (err) {
+      const commandError = err as CommandError;
+      const position = commandError.details as ScriptErrorDetails | null;
+      if (commandError.kind === "script" && position?.line) {
+        monacoRef.current?.editor.setModelMarkers(selectedModel, "script", [
+          {
+            severity: monacoRef.current.MarkerSeverity.Error,
+            message: `${position.name}: ${commandError.message}`,
+            startLineNumber: position.line,
+            startColumn: position.column ?? 1,
+            endLineNumber: position.line,
+            endColumn: selectedModel.getLineMaxColumn(position.line),
+          },
+        ]);
+      }
+      value = JSON.stringify(commandError, null, 2);
+    }
     console.log(value);
 
     let outputModelEntry = outputEditorModels.find((m) => {
@@ -1,4 +1,5 @@
 import { invoke } from "@tauri-apps/api";
+import { CommandError, withVault } from "@/api";
 import { Database, Minus, Plus } from "lucide-react";
 import { Suspense, useEffect, useState, useContext } from "react";
 import { Button } from "./ui/button";
@@ -28,19 +29,18 @@ export function HomePage({ onConnect }: Props) {
     try {
       console.log("Tring to connect and save", { uri, connectionName });
       setLoading(true);
-      const { id, dbs } = await invoke<{ id: string; dbs: string[] }>(
-        "connect_db",
-        {
+      const { id, dbs } = await withVault(() =>
+        invoke<{ id: string; dbs: string[] }>("connect_db", {
           uri,
           name: connectionName,
-        },
+        }),
       );
       console.log("Connected and got id", id, dbs);
       onConnect({ clientId: id, dbs });
       setLoading(false);
     } catch (err) {
       toast({
-        description: err as any,
+        description: (err as CommandError).message,
         variant: "destructive",
         duration: 1000000000,
       });
@@ -115,24 +115,55 @@ const SavedDatabases = ({
 }) => {
   const [dbs, setDbs] = useState<{ name: String; id: number }[]>([]);
 
-  useEffect(() => {
+  const { toast } = useToast();
+
+  function loadDbs() {
     invoke("get_saved_dbs").then((d) => setDbs(d as []));
+  }
+
+  useEffect(() => {
+    loadDbs();
   }, []);
 
   async function connectDb(id: number) {
     console.log("Connecting a saved db");
-    const { id: clientId, dbs } = await invoke<{
-      id: string;
-      dbs: string[];
-    }>("connect_saved_db", {
-      id,
-    });
+    const { id: clientId, dbs } = await withVault(() =>
+      invoke<{
+        id: string;
+        dbs: string[];
+      }>("connect_saved_db", {
+        id,
+      }),
+    );
     console.log(clientId, dbs);
     onConnect({ clientId, dbs });
   }
 
-  async function renameDb(_id: number) {}
-  async function deleteDb(_id: number) {}
+  async function renameDb(id: number) {
+    const name = window.prompt("New name");
+    if (!name) return;
+    try {
+      await invoke("rename_saved_db", { id, name });
+      loadDbs();
+    } catch (err) {
+      toast({
+        description: (err as CommandError).message,
+        variant: "destructive",
+      });
+    }
+  }
+
+  async function deleteDb(id: number) {
+    try {
+      await invoke("delete_saved_db", { id });
+      loadDbs();
+    } catch (err) {
+      toast({
+        description: (err as CommandError).message,
+        variant: "destructive",
+      });
+    }
+  }
 
   return (
     <>